@@ -0,0 +1,70 @@
+//! Measures how fast stop_times.txt is loaded, on a generated feed about the size of the one published by PID.
+//! Run it with `cargo bench --bench stop_times`.
+
+use prahadlo::gtfs::*;
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const STOPS: usize = 15_000;
+const TRIPS: usize = 100_000;
+const STOPS_PER_TRIP: usize = 20;
+const RUNS: usize = 5;
+
+fn write_feed(path: &Path) {
+    fs::create_dir_all(path).unwrap();
+    let mut stops = String::from("stop_id,stop_name,stop_lat,stop_lon,zone_id,stop_url,location_type,parent_station,wheelchair_boarding,level_id,platform_code\n");
+    for stop in 0..STOPS {
+        writeln!(stops, "U{}Z1,Stop {},50.{:04},14.{:04},P,,0,,1,,", stop, stop, stop % 10_000, stop / 10).unwrap();
+    }
+    fs::write(path.join("stops.txt"), stops).unwrap();
+    fs::write(path.join("routes.txt"), "\
+route_id,agency_id,route_short_name,route_long_name,route_type,route_url,route_color,route_text_color,is_night
+L1,99,1,Test,3,,,,0
+").unwrap();
+    fs::write(path.join("calendar.txt"), "\
+service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date
+daily,1,1,1,1,1,1,1,20200101,20201231
+").unwrap();
+    let mut trips = String::from("route_id,service_id,trip_id,trip_headsign,trip_short_name,direction_id,block_id,shape_id,wheelchair_accessible,bikes_allowed,exceptional,trip_operation_type\n");
+    let mut stop_times = String::from("trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign,pickup_type,drop_off_type,shape_dist_travelled\n");
+    for trip in 0..TRIPS {
+        writeln!(trips, "L1,daily,{}_{}_200101,Stop {},,0,,,1,1,0,1", trip % 500, trip, trip % STOPS).unwrap();
+        for sequence in 0..STOPS_PER_TRIP {
+            let time = 4 * 3600 + (trip % 1000) * 60 + sequence * 90;
+            let time = format!("{:02}:{:02}:{:02}", time / 3600, time % 3600 / 60, time % 60);
+            writeln!(stop_times, "{}_{}_200101,{},{},U{}Z1,{},,0,0,{}.{}",
+                trip % 500, trip, time, time, (trip * 7 + sequence * 13) % STOPS, sequence + 1, sequence * 2, trip % 10).unwrap();
+        }
+    }
+    fs::write(path.join("trips.txt"), trips).unwrap();
+    fs::write(path.join("stop_times.txt"), stop_times).unwrap();
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("prahadlo_bench_stop_times");
+    if !path.join("stop_times.txt").exists() {
+        write_feed(&path);
+    }
+    let mut feed = FeedReader::new(&path);
+    let stops = load_stops(&mut feed).unwrap();
+    let routes = load_routes(&mut feed).unwrap();
+    let services = load_services(&mut feed).unwrap();
+    let trips = load_trips(&mut feed, &routes, &services).unwrap();
+    let size = fs::metadata(path.join("stop_times.txt")).unwrap().len();
+
+    let mut best = Duration::MAX;
+    let mut records = 0;
+    for _ in 0..RUNS {
+        let mut loaded = trips.clone();
+        let start = Instant::now();
+        load_stop_times(&mut feed, &mut loaded, &stops).unwrap();
+        best = best.min(start.elapsed());
+        records = loaded.values().map(|trip| trip.stop_times.len()).sum::<usize>();
+    }
+    let seconds = best.as_secs_f64();
+    println!("stop_times.txt: {} records, {:.1} MB", records, size as f64 / 1e6);
+    println!("best of {} runs: {:.3} s, {:.0} records/s, {:.1} MB/s", RUNS, seconds, records as f64 / seconds, size as f64 / 1e6 / seconds);
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+/// Everything that can go wrong when loading a feed or answering a query
+#[derive(Debug)]
+pub enum Error {
+    /// The feed could not be loaded
+    Gtfs(GtfsError),
+    /// No stop matches the name given in a query
+    StopNotFound(String),
+    /// The query combines options that cannot be searched for together
+    UnsupportedQuery(&'static str),
+    /// The snapshot of the network could not be written
+    Snapshot(std::io::Error),
+}
+
+/// A problem with a GTFS feed. Apart from missing files, it points to the line and if possible the field it is on.
+#[derive(Debug)]
+pub enum GtfsError {
+    /// A required file is missing or cannot be read
+    Io { file: String, source: std::io::Error },
+    /// A line of a file is not a valid record, e.g. a value cannot be parsed
+    InvalidRecord { file: String, line: u64, field: Option<String>, message: String },
+    /// A record refers to an entity missing from the feed, e.g. a stop time to an unknown trip
+    UnknownReference { file: String, line: u64, field: String, value: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl GtfsError {
+    pub fn file(&self) -> &str {
+        match self {
+            GtfsError::Io { file, .. } => file,
+            GtfsError::InvalidRecord { file, .. } => file,
+            GtfsError::UnknownReference { file, .. } => file,
+        }
+    }
+
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            GtfsError::Io { .. } => None,
+            GtfsError::InvalidRecord { line, .. } => Some(*line),
+            GtfsError::UnknownReference { line, .. } => Some(*line),
+        }
+    }
+}
+
+impl fmt::Display for GtfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GtfsError::Io { file, source } => write!(f, "{}: {}", file, source),
+            GtfsError::InvalidRecord { file, line, field: Some(field), message } => write!(f, "{}:{}: {}: {}", file, line, field, message),
+            GtfsError::InvalidRecord { file, line, field: None, message } => write!(f, "{}:{}: {}", file, line, message),
+            GtfsError::UnknownReference { file, line, field, value } => write!(f, "{}:{}: {}: {} does not exist", file, line, field, value),
+        }
+    }
+}
+
+impl std::error::Error for GtfsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GtfsError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Gtfs(err) => write!(f, "invalid feed: {}", err),
+            Error::StopNotFound(name) => write!(f, "no stop called {}", name),
+            Error::UnsupportedQuery(reason) => write!(f, "unsupported query: {}", reason),
+            Error::Snapshot(err) => write!(f, "could not write the snapshot: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Gtfs(err) => Some(err),
+            Error::Snapshot(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<GtfsError> for Error {
+    fn from(err: GtfsError) -> Error {
+        Error::Gtfs(err)
+    }
+}
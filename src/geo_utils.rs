@@ -1,26 +1,45 @@
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
 
 use crate::gtfs::*;
 
-use geo_types::Point;
+use geo_types::{LineString, MultiPolygon, Point, Polygon};
 use proj::Proj;
 
+/// The corners of an outline traced around grid cells, the last corner is not repeated
+type Ring = Vec<(i32, i32)>;
     
 /// Converts stop coordinates in WGS84 to UTM coordinates in zone 33U
-pub fn get_stop_coords_in_utm(stops: &HashMap<String, Rc<Stop>>) -> HashMap<String, Point<f32>> {
+pub fn get_stop_coords_in_utm(stops: &Vec<Stop>) -> HashMap<String, Point<f32>> {
     let mut stop_coords: HashMap<String, Point<f32>> = HashMap::new();
-    for (stop_id, stop) in stops {
+    for stop in stops {
         let from = "EPSG:4326";
         let to = "EPSG:32633";
-        let wsg_to_utm = Proj::new_known_crs(&from, &to, None).unwrap();
+        let wsg_to_utm = Proj::new_known_crs(from, to, None).unwrap();
         let wsg_coords = Point::new(stop.stop_lon, stop.stop_lat);
         let coords = wsg_to_utm.convert(wsg_coords).unwrap();
-        stop_coords.insert(stop_id.clone(), coords);
+        stop_coords.insert(stop.stop_id.clone(), coords);
     }
     return stop_coords;
 }
 
+/// Converts polygons in UTM coordinates in zone 33U back to WGS84
+pub fn get_area_in_wgs84(area: &MultiPolygon<f64>) -> MultiPolygon<f64> {
+    let utm_to_wgs = Proj::new_known_crs("EPSG:32633", "EPSG:4326", None).unwrap();
+    let convert_ring = |ring: &LineString<f64>| -> LineString<f64> {
+        ring.points_iter()
+            .map(|point| utm_to_wgs.convert(point).unwrap())
+            .collect::<Vec<Point<f64>>>()
+            .into()
+    };
+    let polygons = area.0.iter()
+        .map(|polygon| Polygon::new(
+            convert_ring(polygon.exterior()),
+            polygon.interiors().iter().map(convert_ring).collect(),
+        ))
+        .collect();
+    return MultiPolygon(polygons);
+}
+
 /// Takes stop coords in utm and a maximum connections distance. Divides the stops into squares of
 /// size max_connection_dist * max_connection_dist.
 pub fn calculate_proximity_squares(
@@ -33,14 +52,7 @@ pub fn calculate_proximity_squares(
             (utm.x() / max_connection_dist) as i32,
             (utm.y() / max_connection_dist) as i32,
         );
-        if squares.contains_key(&square_coords) {
-            squares
-                .get_mut(&square_coords)
-                .unwrap()
-                .push(String::from(stop_id));
-        } else {
-            squares.insert(square_coords, vec![String::from(stop_id)]);
-        }
+        squares.entry(square_coords).or_insert_with(Vec::new).push(String::from(stop_id));
     }
     return squares;
 }
@@ -49,7 +61,6 @@ pub fn calculate_proximity_squares(
 /// and it efficiently computes connections between stops closer than max_conn_dist. (efficiently means faster than
 /// O(N^2) N being the number of all stops.
 pub fn get_pedestrian_connections(
-    stops: &HashMap<String, Rc<Stop>>,
     utm_coords: &HashMap<String, Point<f32>>,
     squares: &HashMap<(i32, i32), Vec<String>>,
     max_conn_dist: f32,
@@ -84,3 +95,198 @@ pub fn get_pedestrian_connections(
     return connections;
 }
 
+
+/// Takes utm coords of places and the distance that can still be walked from each of them. Returns the area
+/// within that distance, measured the same way as the pedestrian connections, as polygons in utm coordinates.
+/// The area is rasterised onto a grid of squares of size cell_size * cell_size and the outline of the grid is traced.
+pub fn get_reachable_area(reached: &Vec<(Point<f32>, f32)>, cell_size: f32) -> MultiPolygon<f64> {
+    let cell_of = |coord: f32| (coord / cell_size).floor() as i32;
+    let cell_center = |cell: i32| (cell as f32 + 0.5) * cell_size;
+
+    let mut cells: HashSet<(i32, i32)> = HashSet::new();
+    for (coord, distance) in reached {
+        cells.insert((cell_of(coord.x()), cell_of(coord.y())));
+        for x in cell_of(coord.x() - distance)..=cell_of(coord.x() + distance) {
+            let remaining = distance - (cell_center(x) - coord.x()).abs();
+            if remaining < 0.0 {
+                continue;
+            }
+            for y in cell_of(coord.y() - remaining)..=cell_of(coord.y() + remaining) {
+                if (cell_center(y) - coord.y()).abs() <= remaining {
+                    cells.insert((x, y));
+                }
+            }
+        }
+    }
+
+    let rings = trace_cell_outlines(&cells);
+    let (outer, holes): (Vec<Ring>, Vec<Ring>) = rings.into_iter().partition(|ring| ring_area(ring) > 0);
+    let polygon_holes = assign_holes(&outer, holes);
+
+    let to_line_string = |ring: &Ring| -> LineString<f64> {
+        let mut coords: Vec<(f64, f64)> = ring.iter()
+            .map(|(x, y)| (*x as f64 * cell_size as f64, *y as f64 * cell_size as f64))
+            .collect();
+        coords.push(coords[0]);
+        coords.into()
+    };
+    let polygons = outer.iter().zip(polygon_holes.iter())
+        .map(|(ring, holes)| Polygon::new(to_line_string(ring), holes.iter().map(to_line_string).collect()))
+        .collect();
+    return MultiPolygon(polygons);
+}
+
+/// Finds the outer ring each hole belongs to, which is the smallest one containing it.
+/// Returns the holes of every outer ring in the same order as the rings.
+fn assign_holes(outer: &Vec<Ring>, holes: Vec<Ring>) -> Vec<Vec<Ring>> {
+    let mut polygon_holes: Vec<Vec<Ring>> = vec![Vec::new(); outer.len()];
+    for hole in holes {
+        // the filled cells are on the left of every edge, so the center of the cell next to the start
+        // of the first side is inside the polygon the hole belongs to, however long the side is
+        let (a, b) = (hole[0], hole[1]);
+        let (dx, dy) = ((b.0 - a.0).signum() as f64, (b.1 - a.1).signum() as f64);
+        let point = (
+            a.0 as f64 + dx * 0.5 - dy * 0.5,
+            a.1 as f64 + dy * 0.5 + dx * 0.5,
+        );
+        let containing = outer.iter().enumerate()
+            .filter(|(_, ring)| is_in_ring(ring, point))
+            .min_by_key(|(_, ring)| ring_area(ring));
+        if let Some((index, _)) = containing {
+            polygon_holes[index].push(hole);
+        }
+    }
+    return polygon_holes;
+}
+
+#[test]
+fn test_assign_holes() {
+    // a square with a wide hole and thin walls, standing in the hole of a bigger square
+    let mut cells: HashSet<(i32, i32)> = HashSet::new();
+    for x in -3..13 {
+        for y in -3..13 {
+            let outer_wall = x == -3 || x == 12 || y == -3 || y == 12;
+            let inner_wall = (0..10).contains(&x) && (0..10).contains(&y)
+                && (x == 0 || x == 9 || y == 0 || y == 9);
+            if outer_wall || inner_wall {
+                cells.insert((x, y));
+            }
+        }
+    }
+    let (outer, holes): (Vec<Ring>, Vec<Ring>) = trace_cell_outlines(&cells)
+        .into_iter().partition(|ring| ring_area(ring) > 0);
+    assert_eq!(outer.len(), 2);
+    assert_eq!(holes.len(), 2);
+    let polygon_holes = assign_holes(&outer, holes);
+    for (ring, holes) in outer.iter().zip(polygon_holes.iter()) {
+        assert_eq!(holes.len(), 1);
+        // the hole of a ring is the inside of its walls
+        let wall_area = if ring_area(ring) == 2 * 16 * 16 { 2 * (16 * 16 - 14 * 14) } else { 2 * (10 * 10 - 8 * 8) };
+        assert_eq!(ring_area(ring) + ring_area(&holes[0]), wall_area);
+    }
+}
+
+/// Traces the outlines of a set of grid cells. Outer rings go counterclockwise and holes clockwise,
+/// so the cells are always on the left. Only the corners of the outlines are returned, the rings are not closed.
+fn trace_cell_outlines(cells: &HashSet<(i32, i32)>) -> Vec<Ring> {
+    // edges of the outline, from a grid vertex to the following ones
+    let mut edges: HashMap<(i32, i32), Vec<(i32, i32)>> = HashMap::new();
+    for &(x, y) in cells {
+        let sides = [
+            ((x, y - 1), (x, y), (x + 1, y)),
+            ((x + 1, y), (x + 1, y), (x + 1, y + 1)),
+            ((x, y + 1), (x + 1, y + 1), (x, y + 1)),
+            ((x - 1, y), (x, y + 1), (x, y)),
+        ];
+        for (neighbour, from, to) in sides.iter() {
+            if !cells.contains(neighbour) {
+                edges.entry(*from).or_insert_with(Vec::new).push(*to);
+            }
+        }
+    }
+
+    let mut rings: Vec<Ring> = Vec::new();
+    while let Some(&start) = edges.keys().next() {
+        let mut ring: Ring = vec![start];
+        let mut direction = (0, 0);
+        let mut vertex = start;
+        loop {
+            let next_vertices = edges.get_mut(&vertex).unwrap();
+            // where two cells touch only by a corner, turn left so that they are kept apart
+            let index = (0..next_vertices.len())
+                .max_by_key(|&i| {
+                    let next = (next_vertices[i].0 - vertex.0, next_vertices[i].1 - vertex.1);
+                    direction.0 * next.1 - direction.1 * next.0
+                })
+                .unwrap();
+            let next_vertex = next_vertices.swap_remove(index);
+            if next_vertices.is_empty() {
+                edges.remove(&vertex);
+            }
+            let next_direction = (next_vertex.0 - vertex.0, next_vertex.1 - vertex.1);
+            if next_direction == direction {
+                ring.pop();
+            }
+            direction = next_direction;
+            vertex = next_vertex;
+            if vertex == start {
+                break;
+            }
+            ring.push(vertex);
+        }
+        // the start may lie in the middle of a side
+        if ring.len() > 2 {
+            let (first, second, last) = (ring[0], ring[1], ring[ring.len() - 1]);
+            if (first.0 - last.0) * (second.1 - first.1) == (first.1 - last.1) * (second.0 - first.0) {
+                ring.remove(0);
+            }
+        }
+        rings.push(ring);
+    }
+    return rings;
+}
+
+#[test]
+fn test_trace_cell_outlines() {
+    // a 3x3 square with the middle missing and a cell touching it only by a corner
+    let mut cells: HashSet<(i32, i32)> = HashSet::new();
+    for x in 0..3 {
+        for y in 0..3 {
+            if (x, y) != (1, 1) {
+                cells.insert((x, y));
+            }
+        }
+    }
+    cells.insert((3, 3));
+    let mut rings = trace_cell_outlines(&cells);
+    rings.sort_by_key(ring_area);
+    assert_eq!(rings.len(), 3);
+    assert_eq!(ring_area(&rings[0]), -2);
+    assert_eq!(rings[0].len(), 4);
+    assert_eq!(ring_area(&rings[1]), 2);
+    assert_eq!(ring_area(&rings[2]), 18);
+    assert_eq!(rings[2].len(), 4);
+}
+
+/// Twice the signed area of a ring, positive for counterclockwise rings
+fn ring_area(ring: &Ring) -> i64 {
+    let mut area: i64 = 0;
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+        area += a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64;
+    }
+    return area;
+}
+
+/// Checks whether a point is inside a ring by counting the crossings of a ray going from it in the x direction
+fn is_in_ring(ring: &Ring, point: (f64, f64)) -> bool {
+    let mut inside = false;
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[(i + 1) % ring.len()]);
+        let (ax, ay, bx, by) = (a.0 as f64, a.1 as f64, b.0 as f64, b.1 as f64);
+        if (ay > point.1) != (by > point.1) && point.0 < ax + (point.1 - ay) / (by - ay) * (bx - ax) {
+            inside = !inside;
+        }
+    }
+    return inside;
+}
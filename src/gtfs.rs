@@ -1,20 +1,40 @@
+use crate::error::GtfsError;
+
 use chrono::NaiveDate;
 use std::collections::HashMap;
-use std::path::Path;
+use std::fmt;
 use std::fs::File;
-use serde::{de, de::Unexpected, Deserialize, Deserializer};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use rayon::prelude::*;
+use serde::{de, de::DeserializeOwned, de::Unexpected, Deserialize, Deserializer, Serialize, Serializer};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+const STOP_TIMES_FILE: &str = "stop_times.txt";
+const FREQUENCIES_FILE: &str = "frequencies.txt";
+/// Number of records of stop_times.txt that are parsed in parallel at once
+const STOP_TIMES_BATCH: usize = 1 << 16;
+/// The records of stop_times.txt are short, reading them in large blocks is faster
+const STOP_TIMES_BUFFER: usize = 1 << 20;
+/// pickup_type or drop_off_type of a stop time at which the passengers cannot board or get off
+const NO_STOPPING: u8 = 1;
+/// pickup_type or drop_off_type of a stop time at which the passengers have to tell the driver
+const COORDINATE_WITH_DRIVER: u8 = 3;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Agency {
     pub agency_id: String,
     pub agency_name: String,
     pub agency_url: String,
     pub agency_timezone: String,
-    pub agency_lang: String,
-    pub agency_phone: String,
+    pub agency_lang: Option<String>,
+    pub agency_phone: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Route {
     pub route_id: String,
     pub agency_id: String,
@@ -24,11 +44,11 @@ pub struct Route {
     pub route_url: Option<String>,
     pub route_color: Option<String>,
     pub route_text_color: Option<String>,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub is_night: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Trip {
     pub route_id: String,
     pub service_id: String,
@@ -42,20 +62,51 @@ pub struct Trip {
     pub bikes_allowed: Option<u8>,
     pub exceptional: Option<u8>,
     pub trip_operation_type: Option<u8>,
-    #[serde(default = "Vec::new", skip_deserializing)]
+    #[serde(default = "Vec::new", skip)]
     pub stop_times: Vec<StopTime>,
+    // set for the trips expanded from frequencies.txt
+    #[serde(default, skip)]
+    pub headway: Option<Headway>,
+}
+
+/// How often a trip expanded from frequencies.txt runs
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Headway {
+    pub headway_secs: u32,
+    // with exact times the departures are scheduled, otherwise the passengers wait half of the headway on average
+    pub exact_times: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Trip {
+    /// The time the passenger waits at the stop on average before the vehicle arrives, the times of the trips
+    /// without exact times are moved by it, so the passenger has to be at the stop this much before the departure
+    pub fn get_expected_wait(&self) -> u32 {
+        match self.headway {
+            Some(Headway { headway_secs, exact_times: false }) => headway_secs / 2,
+            _ => 0,
+        }
+    }
+
+    /// Returns the id the trip has in trips.txt, the trips expanded from frequencies.txt share the id of their template
+    pub fn get_template_id(&self) -> &str {
+        match self.headway {
+            Some(_) => self.trip_id.rsplit_once('@').map_or(&self.trip_id, |(template_id, _)| template_id),
+            None => &self.trip_id,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StopTime {
-    pub trip_id: String,
-    #[serde(deserialize_with = "deserialize_time")]
+    // the ids are shared by all the stop times of a trip or a stop
+    pub trip_id: Arc<str>,
+    #[serde(deserialize_with = "deserialize_time", serialize_with = "serialize_time")]
     // time of the day in seconds
     pub arrival_time: u32,
-    #[serde(deserialize_with = "deserialize_time")]
+    #[serde(deserialize_with = "deserialize_time", serialize_with = "serialize_time")]
     // time of the day in seconds
     pub departure_time: u32,
-    pub stop_id: String,
+    pub stop_id: Arc<str>,
     pub stop_sequence: u32,
     pub stop_headsign: Option<String>,
     pub pickup_type: u8,
@@ -63,12 +114,39 @@ pub struct StopTime {
     pub shape_dist_travelled: Option<f32>,
 }
 
+impl StopTime {
+    pub fn can_board(&self) -> bool {
+        self.pickup_type != NO_STOPPING
+    }
+
+    pub fn can_get_off(&self) -> bool {
+        self.drop_off_type != NO_STOPPING
+    }
+
+    /// The vehicle only stops for boarding when the passenger signals it to the driver
+    pub fn is_boarding_on_request(&self) -> bool {
+        self.pickup_type == COORDINATE_WITH_DRIVER
+    }
+
+    /// The vehicle only stops for getting off when the passenger tells the driver
+    pub fn is_getting_off_on_request(&self) -> bool {
+        self.drop_off_type == COORDINATE_WITH_DRIVER
+    }
+}
+
 fn deserialize_ymd<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s: String = Deserialize::deserialize(deserializer)?;
-    Ok(NaiveDate::parse_from_str(&s, "%Y%m%d").unwrap())
+    NaiveDate::parse_from_str(&s, "%Y%m%d").map_err(|_| de::Error::invalid_value(Unexpected::Str(&s), &"a date as YYYYMMDD"))
+}
+
+fn serialize_ymd<S>(date: &NaiveDate, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&date.format("%Y%m%d").to_string())
 }
 
 fn deserialize_time<'de, D>(deserializer: D) -> Result<u32, D::Error>
@@ -76,8 +154,23 @@ where
     D: Deserializer<'de>,
 {
     let s: String = Deserialize::deserialize(deserializer)?;
-    let hms: Vec<u32> = s.split(":").map(|x| x.parse::<u32>().unwrap()).collect();
-    return Ok(3600 * hms[0] + 60 * hms[1] + hms[2]);
+    parse_time(&s).ok_or_else(|| de::Error::invalid_value(Unexpected::Str(&s), &"a time as HH:MM:SS"))
+}
+
+/// Parses a time of the day in the format HH:MM:SS into seconds, the hours can exceed 24
+fn parse_time(s: &str) -> Option<u32> {
+    let hms: Option<Vec<u32>> = s.split(":").map(|x| x.parse::<u32>().ok()).collect();
+    match hms.as_deref() {
+        Some([hours, minutes, seconds]) => Some(3600 * hours + 60 * minutes + seconds),
+        _ => None,
+    }
+}
+
+fn serialize_time<S>(time: &u32, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{:0>2}:{:0>2}:{:0>2}", time / 3600, time % 3600 / 60, time % 60))
 }
 
 fn bool_from_int<'de, D>(deserializer: D) -> Result<bool, D::Error>
@@ -94,33 +187,59 @@ where
     }
 }
 
-#[derive(Debug, Deserialize)]
+fn bool_to_int<S>(value: &bool, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u8(*value as u8)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Service {
     pub service_id: String,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub monday: bool,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub tuesday: bool,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub wednesday: bool,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub thursday: bool,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub friday: bool,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub saturday: bool,
-    #[serde(deserialize_with = "bool_from_int")]
+    #[serde(deserialize_with = "bool_from_int", serialize_with = "bool_to_int")]
     pub sunday: bool,
-    #[serde(deserialize_with = "deserialize_ymd")]
+    #[serde(deserialize_with = "deserialize_ymd", serialize_with = "serialize_ymd")]
     pub start_date: NaiveDate,
-    #[serde(deserialize_with = "deserialize_ymd")]
+    #[serde(deserialize_with = "deserialize_ymd", serialize_with = "serialize_ymd")]
     pub end_date: NaiveDate,
-    #[serde(default = "Vec::new", skip_deserializing)]
+    #[serde(default = "Vec::new", skip)]
     pub exceptions: Vec<ServiceException>,
 }
 
+impl Service {
+    /// Creates a service with no regular weekdays, used for services that appear only in calendar_dates.txt
+    fn without_calendar(service_id: &str, date: NaiveDate) -> Service {
+        Service {
+            service_id: String::from(service_id),
+            monday: false,
+            tuesday: false,
+            wednesday: false,
+            thursday: false,
+            friday: false,
+            saturday: false,
+            sunday: false,
+            start_date: date,
+            end_date: date,
+            exceptions: Vec::new(),
+        }
+    }
+}
+
 /// A structure describing a stop.
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Stop {
     pub stop_id: String,
     pub stop_name: String,
@@ -135,33 +254,364 @@ pub struct Stop {
     pub platform_code: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ServiceException {
     pub service_id: String,
-    #[serde(deserialize_with = "deserialize_ymd")]
+    #[serde(deserialize_with = "deserialize_ymd", serialize_with = "serialize_ymd")]
     pub date: NaiveDate,
     pub exception_type: u8,
 }
 
+/// A rule from transfers.txt. Transfers can be specified between stops, routes or trips,
+/// with the more specific rules taking precedence.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Transfer {
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub from_route_id: Option<String>,
+    pub to_route_id: Option<String>,
+    pub from_trip_id: Option<String>,
+    pub to_trip_id: Option<String>,
+    // 0 - recommended, 1 - timed (the departing vehicle waits), 2 - minimum time, 3 - not possible
+    pub transfer_type: u8,
+    // in seconds, only used with transfer_type 2
+    pub min_transfer_time: Option<u32>,
+}
+
+/// A trip of frequencies.txt running every @headway_secs from @start_time until @end_time,
+/// the stop times of the trip only give the travel times between its stops
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Frequency {
+    pub trip_id: String,
+    #[serde(deserialize_with = "deserialize_time", serialize_with = "serialize_time")]
+    pub start_time: u32,
+    #[serde(deserialize_with = "deserialize_time", serialize_with = "serialize_time")]
+    pub end_time: u32,
+    pub headway_secs: u32,
+    // 1 - the departures are scheduled exactly every headway_secs, 0 or missing - only the headway is known
+    pub exact_times: Option<u8>,
+}
+
+/// Where the files of a feed are read from
+#[derive(Debug, Clone)]
+pub enum FeedSource {
+    /// A directory with the unpacked files
+    Directory(PathBuf),
+    /// A zip archive as published, its members are decompressed while they are being read
+    Zip(PathBuf),
+}
+
+impl FeedSource {
+    /// Paths ending with .zip are archives, anything else is a directory
+    pub fn from_path(path: &Path) -> FeedSource {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("zip") => FeedSource::Zip(path.to_path_buf()),
+            _ => FeedSource::Directory(path.to_path_buf()),
+        }
+    }
+
+    fn has_file(&self, file_name: &str) -> bool {
+        match self {
+            FeedSource::Directory(path) => path.join(file_name).exists(),
+            FeedSource::Zip(path) => File::open(path).ok()
+                .and_then(|file| ZipArchive::new(file).ok())
+                .is_some_and(|mut archive| archive.by_name(file_name).is_ok()),
+        }
+    }
+
+    /// Returns the CRC-32 of the contents of a file, None if the feed does not contain it.
+    /// Archives already store the checksums of their members, so they do not need to be decompressed.
+    fn file_hash(&self, file_name: &str) -> Result<Option<u32>, GtfsError> {
+        let io_error = |source| GtfsError::Io { file: String::from(file_name), source: source };
+        match self {
+            FeedSource::Directory(path) => {
+                let mut file = match File::open(path.join(file_name)) {
+                    Ok(file) => file,
+                    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(io_error(err)),
+                };
+                let mut hasher = crc32fast::Hasher::new();
+                let mut buffer = vec![0; 1 << 16];
+                loop {
+                    let read = file.read(&mut buffer).map_err(io_error)?;
+                    if read == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..read]);
+                }
+                Ok(Some(hasher.finalize()))
+            },
+            FeedSource::Zip(path) => {
+                let file = File::open(path).map_err(|source| GtfsError::Io { file: path.display().to_string(), source: source })?;
+                let mut archive = ZipArchive::new(file).map_err(|err| io_error(err.into()))?;
+                let hash = match archive.by_name(file_name) {
+                    Ok(member) => Ok(Some(member.crc32())),
+                    Err(ZipError::FileNotFound) => Ok(None),
+                    Err(err) => Err(io_error(err.into())),
+                };
+                hash
+            },
+        }
+    }
+}
+
+/// Reads the files of a GTFS feed. A strict reader fails on the first invalid record,
+/// a lenient one skips the invalid records and keeps them as warnings.
+pub struct FeedReader {
+    source: FeedSource,
+    lenient: bool,
+    warnings: Vec<GtfsError>,
+}
+
+impl FeedReader {
+    /// Reads the feed from a directory or a zip archive at @path
+    pub fn new(path: &Path) -> FeedReader {
+        FeedReader::from_source(FeedSource::from_path(path))
+    }
+
+    pub fn from_source(source: FeedSource) -> FeedReader {
+        FeedReader { source: source, lenient: false, warnings: Vec::new() }
+    }
+
+    pub fn lenient(path: &Path) -> FeedReader {
+        FeedReader { lenient: true, ..FeedReader::new(path) }
+    }
+
+    /// The records skipped so far by a lenient reader
+    pub fn warnings(&self) -> &Vec<GtfsError> {
+        &self.warnings
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    fn has_file(&self, file_name: &str) -> bool {
+        self.source.has_file(file_name)
+    }
+
+    /// Returns the CRC-32 of every file in @file_names, None for the files missing from the feed
+    pub fn file_hashes(&self, file_names: &[&str]) -> Result<Vec<(String, Option<u32>)>, GtfsError> {
+        file_names.iter()
+            .map(|file_name| Ok((String::from(*file_name), self.source.file_hash(file_name)?)))
+            .collect()
+    }
+
+    /// Fails with the error in strict mode, otherwise keeps it as a warning so that the record can be skipped
+    fn report(&mut self, err: GtfsError) -> Result<(), GtfsError> {
+        if self.lenient {
+            self.warnings.push(err);
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    /// Opens a file of the feed and passes its contents to @parse
+    fn read_file<T, F>(&mut self, file_name: &str, parse: F) -> Result<T, GtfsError>
+    where
+        F: FnOnce(&mut FeedReader, &mut dyn Read) -> Result<T, GtfsError>,
+    {
+        let io_error = |source| GtfsError::Io { file: String::from(file_name), source: source };
+        match self.source.clone() {
+            FeedSource::Directory(path) => {
+                let mut file = File::open(path.join(file_name)).map_err(io_error)?;
+                parse(self, &mut file)
+            },
+            FeedSource::Zip(path) => {
+                let file = File::open(&path).map_err(|source| GtfsError::Io { file: path.display().to_string(), source: source })?;
+                let mut archive = ZipArchive::new(file).map_err(|err| io_error(err.into()))?;
+                let mut member = archive.by_name(file_name).map_err(|err| io_error(err.into()))?;
+                parse(self, &mut member)
+            },
+        }
+    }
+
+    /// Reads all valid records of a file together with the lines they are on
+    fn read_records<T: DeserializeOwned>(&mut self, file_name: &str) -> Result<Vec<(u64, T)>, GtfsError> {
+        self.read_file(file_name, |feed, reader| feed.parse_records(file_name, reader))
+    }
+
+    fn parse_records<T: DeserializeOwned, R: Read>(&mut self, file_name: &str, reader: R) -> Result<Vec<(u64, T)>, GtfsError> {
+        let mut rdr = csv::Reader::from_reader(reader);
+        let headers = match rdr.headers() {
+            Ok(headers) => headers.clone(),
+            Err(err) => return Err(record_error(file_name, None, err)),
+        };
+        let mut records = Vec::new();
+        let mut record = csv::StringRecord::new();
+        loop {
+            match rdr.read_record(&mut record) {
+                Ok(false) => break,
+                Ok(true) => {
+                    let line = record.position().map_or(0, |position| position.line());
+                    match record.deserialize::<T>(Some(&headers)) {
+                        Ok(value) => records.push((line, value)),
+                        Err(err) => {
+                            let field = find_invalid_field::<T>(&headers, &record, &err);
+                            self.report(record_error(file_name, field, err))?
+                        },
+                    }
+                },
+                Err(err) => match err.kind() {
+                    csv::ErrorKind::Io(_) => return Err(record_error(file_name, None, err)),
+                    _ => self.report(record_error(file_name, None, err))?,
+                },
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Returns the name of the field a record failed to deserialize at. The csv crate knows the position of the values
+/// it fails to parse, but not of the values our own deserializers reject. As the fields are deserialized in order,
+/// that is the last field of the shortest cut of the record failing with the same error.
+fn find_invalid_field<T: DeserializeOwned>(headers: &csv::StringRecord, record: &csv::StringRecord, err: &csv::Error) -> Option<String> {
+    let err = match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err,
+        _ => return None,
+    };
+    let index = match (err.field(), err.kind()) {
+        (Some(index), _) => Some(index as usize),
+        // a column missing in the file is not the fault of any field
+        (None, csv::DeserializeErrorKind::Message(message)) if message.starts_with("missing field") => None,
+        (None, _) => (1..=record.len()).find(|len| {
+            let cut_headers: csv::StringRecord = headers.iter().take(*len).collect();
+            let cut_record: csv::StringRecord = record.iter().take(*len).collect();
+            match cut_record.deserialize::<T>(Some(&cut_headers)) {
+                Err(cut_err) => matches!(cut_err.kind(), csv::ErrorKind::Deserialize { err: cut_err, .. } if cut_err.kind() == err.kind()),
+                Ok(_) => false,
+            }
+        }).map(|len| len - 1),
+    };
+    index.and_then(|index| headers.get(index)).map(String::from)
+}
+
+/// Converts an error of the csv crate into one pointing to the line and the @field
+fn record_error(file_name: &str, field: Option<String>, err: csv::Error) -> GtfsError {
+    let line = err.position().map_or(0, |position| position.line());
+    let message = match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.kind().to_string(),
+        _ => err.to_string(),
+    };
+    match err.into_kind() {
+        csv::ErrorKind::Io(source) => GtfsError::Io { file: String::from(file_name), source: source },
+        _ => GtfsError::InvalidRecord { file: String::from(file_name), line: line, field: field, message: message },
+    }
+}
+
+/// Checks that a record refers to an entity in the feed
+fn check_reference<T>(feed: &mut FeedReader, map: &HashMap<String, T>, file: &str, line: u64, field: &str, value: &str) -> Result<bool, GtfsError> {
+    if map.contains_key(value) {
+        return Ok(true);
+    }
+    feed.report(GtfsError::UnknownReference {
+        file: String::from(file),
+        line: line,
+        field: String::from(field),
+        value: String::from(value),
+    })?;
+    Ok(false)
+}
+
+#[test]
+fn test_invalid_records() {
+    let path = std::env::temp_dir().join("prahadlo_test_invalid_records");
+    std::fs::create_dir_all(&path).unwrap();
+    std::fs::write(path.join("stops.txt"), "\
+stop_id,stop_name,stop_lat,stop_lon,zone_id,stop_url,location_type,parent_station,wheelchair_boarding,level_id,platform_code
+U1Z1,Alfa,50.08,14.40,P,,0,,1,,
+U2Z1,Beta,north,14.43,P,,0,,1,,
+U3Z1,Gama,50.08,14.46,P,,0,,1,,
+").unwrap();
+
+    match load_stops(&mut FeedReader::new(&path)) {
+        Err(GtfsError::InvalidRecord { file, line, field, .. }) => {
+            assert_eq!(file, "stops.txt");
+            assert_eq!(line, 3);
+            assert_eq!(field, Some(String::from("stop_lat")));
+        },
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut feed = FeedReader::lenient(&path);
+    let stops = load_stops(&mut feed).unwrap();
+    assert_eq!(stops.len(), 2);
+    assert_eq!(feed.warnings().len(), 1);
+    assert_eq!(feed.warnings()[0].line(), Some(3));
+
+    match load_routes(&mut feed) {
+        Err(GtfsError::Io { file, .. }) => assert_eq!(file, "routes.txt"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_invalid_fields_with_repeated_values() {
+    let path = std::env::temp_dir().join("prahadlo_test_invalid_fields");
+    std::fs::create_dir_all(&path).unwrap();
+    // the invalid values also appear in other fields of their rows
+    std::fs::write(path.join("stop_times.txt"), "\
+trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign,pickup_type,drop_off_type,shape_dist_travelled
+T1,08:00:00,08:00:00,U1Z1,1,,0,0,
+T1,08:10:00,8h10,U2Z1,2,8h10,0,0,
+T1,08:20:00,08:20:00,U3Z1,3,,0,x,
+T1,08:30:00,08:30:00,U4Z1,x,x,0,0,
+T1,08:40:00,08:40:00,U5Z1,5,,0,0,0
+").unwrap();
+
+    let mut feed = FeedReader::lenient(&path);
+    let stop_times = feed.read_records::<StopTime>("stop_times.txt").unwrap();
+    assert_eq!(stop_times.len(), 2);
+    let fields: Vec<(u64, &str)> = feed.warnings().iter().map(|warning| match warning {
+        GtfsError::InvalidRecord { line, field: Some(field), .. } => (*line, field.as_str()),
+        other => panic!("unexpected warning {:?}", other),
+    }).collect();
+    assert_eq!(fields, vec![(3, "departure_time"), (4, "drop_off_type"), (5, "stop_sequence")]);
+}
+
+#[test]
+fn test_zip_feed() {
+    let path = std::env::temp_dir().join("prahadlo_test_feed.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+    zip.start_file("stops.txt", zip::write::FileOptions::default()).unwrap();
+    std::io::Write::write_all(&mut zip, b"\
+stop_id,stop_name,stop_lat,stop_lon,zone_id,stop_url,location_type,parent_station,wheelchair_boarding,level_id,platform_code
+U1Z1,Alfa,50.08,14.40,P,,0,,1,,
+").unwrap();
+    zip.finish().unwrap();
+
+    let mut feed = FeedReader::new(&path);
+    let stops = load_stops(&mut feed).unwrap();
+    assert_eq!(stops["U1Z1"].stop_name, "Alfa");
+    assert!(load_transfers(&mut feed).unwrap().is_empty());
+    match load_routes(&mut feed) {
+        Err(GtfsError::Io { file, .. }) => assert_eq!(file, "routes.txt"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+/// Loads the contents of agency.txt
+pub fn load_agencies(feed: &mut FeedReader) -> Result<HashMap<String, Agency>, GtfsError> {
+    let mut agencies = HashMap::new();
+    for (_, record) in feed.read_records::<Agency>("agency.txt")? {
+        agencies.insert(record.agency_id.clone(), record);
+    }
+    Ok(agencies)
+}
+
 /// Loads the contents of stops.txt
-/// # Arguments
-/// * path - the path to the directory stops.txt is located in
-pub fn load_stops(path: &Path) -> HashMap<String, Stop> {
+pub fn load_stops(feed: &mut FeedReader) -> Result<HashMap<String, Stop>, GtfsError> {
     let mut stops = HashMap::new();
-    let mut file_path_buf = path.to_path_buf();
-    file_path_buf.push(Path::new("stops.txt"));
-    let file = File::open(file_path_buf.as_path()).unwrap(); // No need for error handling, if this fails, we want to panic
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let record: Stop = result.unwrap();
+    for (_, record) in feed.read_records::<Stop>("stops.txt")? {
         stops.insert(record.stop_id.clone(), record);
     }
-    return stops;
+    Ok(stops)
 }
 
 #[test]
 fn test_stop_loading() {
-    let stops = load_stops(Path::new("test_data/"));
+    let stops = load_stops(&mut FeedReader::new(Path::new("test_data/"))).unwrap();
     assert_eq!(1, stops.len());
     let stop = stops.get("U50S1").unwrap();
     assert_eq!(stop.stop_id, "U50S1");
@@ -178,24 +628,17 @@ fn test_stop_loading() {
 }
 
 /// Loads the contents of routes.txt
-/// # Arguments
-/// * path - the path to the directory routes.txt is located in
-pub fn load_routes(path: &Path) -> HashMap<String, Route> {
+pub fn load_routes(feed: &mut FeedReader) -> Result<HashMap<String, Route>, GtfsError> {
     let mut routes = HashMap::new();
-    let mut file_path_buf = path.to_path_buf();
-    file_path_buf.push(Path::new("routes.txt"));
-    let file = File::open(file_path_buf.as_path()).unwrap(); // No need for error handling, if this fails, we want to panic
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let record: Route = result.unwrap();
+    for (_, record) in feed.read_records::<Route>("routes.txt")? {
         routes.insert(record.route_id.clone(), record);
     }
-    return routes;
+    Ok(routes)
 }
 
 #[test]
 fn test_route_loading() {
-    let routes = load_routes(Path::new("test_data/"));
+    let routes = load_routes(&mut FeedReader::new(Path::new("test_data/"))).unwrap();
     assert_eq!(1, routes.len());
     let route = routes.get("L991").unwrap();
     assert_eq!(route.route_id, "L991");
@@ -215,25 +658,24 @@ fn test_route_loading() {
     assert_eq!(route.is_night, false);
 }
 
-/// Loads the contents of trips.txt
-/// # Arguments
-/// * path - the path to the directory trips.txt is located in
-pub fn load_trips(path: &Path) -> HashMap<String, Trip> {
+/// Loads the contents of trips.txt, skipping the trips of unknown routes and services in lenient mode
+pub fn load_trips(feed: &mut FeedReader, routes: &HashMap<String, Route>, services: &HashMap<String, Service>) -> Result<HashMap<String, Trip>, GtfsError> {
     let mut trips = HashMap::new();
-    let mut file_path_buf = path.to_path_buf();
-    file_path_buf.push(Path::new("trips.txt"));
-    let file = File::open(file_path_buf.as_path()).unwrap(); // No need for error handling, if this fails, we want to panic
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let record: Trip = result.unwrap();
-        trips.insert(record.trip_id.clone(), record);
+    for (line, record) in feed.read_records::<Trip>("trips.txt")? {
+        if check_reference(feed, routes, "trips.txt", line, "route_id", &record.route_id)?
+            && check_reference(feed, services, "trips.txt", line, "service_id", &record.service_id)? {
+            trips.insert(record.trip_id.clone(), record);
+        }
     }
-    return trips;
+    Ok(trips)
 }
 
 #[test]
 fn test_trip_loading() {
-    let trips = load_trips(Path::new("test_data/"));
+    let mut feed = FeedReader::new(Path::new("test_data/"));
+    let routes = load_routes(&mut feed).unwrap();
+    let services = load_services(&mut feed).unwrap();
+    let trips = load_trips(&mut feed, &routes, &services).unwrap();
     assert_eq!(trips.len(), 1);
     let trip = trips.get("991_1411_191224").unwrap();
     assert_eq!(trip.route_id, "L991");
@@ -250,25 +692,22 @@ fn test_trip_loading() {
     assert_eq!(trip.trip_operation_type, Some(1));
 }
 
-/// Loads the contents of services.txt and service_dates.txt
-/// # Arguments
-/// * path - the path to the directory the files are located in
-pub fn load_services(path: &Path) -> HashMap<String, Service> {
+/// Loads the contents of calendar.txt. It may be missing if calendar_dates.txt is there.
+pub fn load_services(feed: &mut FeedReader) -> Result<HashMap<String, Service>, GtfsError> {
     let mut services = HashMap::new();
-    let mut file_path_buf = path.to_path_buf();
-    file_path_buf.push(Path::new("calendar.txt"));
-    let file = File::open(file_path_buf.as_path()).unwrap(); // No need for error handling, if this fails, we want to panic
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let record: Service = result.unwrap();
+    // a feed can define its services by the dates of calendar_dates.txt alone, but it needs one of the files
+    if !feed.has_file("calendar.txt") && feed.has_file("calendar_dates.txt") {
+        return Ok(services);
+    }
+    for (_, record) in feed.read_records::<Service>("calendar.txt")? {
         services.insert(record.service_id.clone(), record);
     }
-    return services;
+    Ok(services)
 }
 
 #[test]
 fn test_service_loading() {
-    let services = load_services(Path::new("test_data/"));
+    let services = load_services(&mut FeedReader::new(Path::new("test_data/"))).unwrap();
     assert_eq!(services.len(), 1);
     let service = services.get("0000010-1").unwrap();
     assert_eq!(service.monday, false);
@@ -278,46 +717,393 @@ fn test_service_loading() {
     assert_eq!(service.friday, false);
     assert_eq!(service.saturday, true);
     assert_eq!(service.sunday, false);
-    assert_eq!(service.start_date, NaiveDate::from_ymd(2020, 1, 25));
-    assert_eq!(service.end_date, NaiveDate::from_ymd(2020, 2, 7))
+    assert_eq!(service.start_date, NaiveDate::from_ymd_opt(2020, 1, 25).unwrap());
+    assert_eq!(service.end_date, NaiveDate::from_ymd_opt(2020, 2, 7).unwrap())
 }
 
-/// Loads service exceptions from calendar_dates.txt and adds them to the HashMap
+/// Loads service exceptions from calendar_dates.txt and adds them to the HashMap. The file is optional.
 /// # Arguments
-/// * path - the path to the gtfs directory
+/// * feed - the reader of the gtfs feed
 /// * services - loaded contents of calendar.txt
-pub fn load_service_exceptions(path: &Path, services: &mut HashMap<String, Service>) {
-    let mut file_path_buf = path.to_path_buf();
-    file_path_buf.push(Path::new("calendar_dates.txt"));
-    let file = File::open(file_path_buf.as_path()).unwrap(); // No need for error handling, if this fails, we want to panic
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let record: ServiceException = result.unwrap();
-        services
-            .get_mut(&record.service_id)
-            .unwrap()
-            .exceptions
-            .push(record);
-    }
-}
-
-// FIXME StopTime loading is slow as hell. Probably it is due to the amout of StopTimes.
-// Perhaps some kind of buffering could help?
-pub fn load_stop_times(path: &Path, trips: &mut HashMap<String, Trip>) {
-    let mut file_path_buf = path.to_path_buf();
-    file_path_buf.push(Path::new("stop_times.txt"));
-    let file = File::open(file_path_buf.as_path()).unwrap();
-    let mut rdr = csv::Reader::from_reader(file);
-    for result in rdr.deserialize() {
-        let stop_time: StopTime = result.unwrap();
-        trips
-            .get_mut(&stop_time.trip_id)
-            .unwrap()
-            .stop_times
-            .push(stop_time);
+pub fn load_service_exceptions(feed: &mut FeedReader, services: &mut HashMap<String, Service>) -> Result<(), GtfsError> {
+    if !feed.has_file("calendar_dates.txt") {
+        return Ok(());
+    }
+    for (_, record) in feed.read_records::<ServiceException>("calendar_dates.txt")? {
+        // services defined only in calendar_dates.txt run exclusively on their added dates
+        let service = services
+            .entry(record.service_id.clone())
+            .or_insert_with(|| Service::without_calendar(&record.service_id, record.date));
+        service.exceptions.push(record);
+    }
+    Ok(())
+}
+
+/// Loads the contents of transfers.txt. The file is optional, no transfers are returned if it is missing.
+pub fn load_transfers(feed: &mut FeedReader) -> Result<Vec<Transfer>, GtfsError> {
+    if !feed.has_file("transfers.txt") {
+        return Ok(Vec::new());
+    }
+    Ok(feed.read_records("transfers.txt")?.into_iter().map(|(_, transfer)| transfer).collect())
+}
+
+/// Loads frequencies.txt and replaces every trip it refers to by trips departing every headway, named by the trip id
+/// and their start, e.g. "T1@08:05:00". The file is optional and has to be loaded after the stop times.
+pub fn load_frequencies(feed: &mut FeedReader, trips: &mut HashMap<String, Trip>) -> Result<(), GtfsError> {
+    if !feed.has_file(FREQUENCIES_FILE) {
+        return Ok(());
+    }
+    let mut frequencies: HashMap<String, Vec<Frequency>> = HashMap::new();
+    for (line, record) in feed.read_records::<Frequency>(FREQUENCIES_FILE)? {
+        let invalid = if record.headway_secs == 0 {
+            Some(("headway_secs", "the headway has to be positive"))
+        } else if record.end_time <= record.start_time {
+            Some(("end_time", "the end time has to be after the start time"))
+        } else {
+            None
+        };
+        if let Some((field, message)) = invalid {
+            feed.report(GtfsError::InvalidRecord {
+                file: String::from(FREQUENCIES_FILE),
+                line: line,
+                field: Some(String::from(field)),
+                message: String::from(message),
+            })?;
+        } else if check_reference(feed, trips, FREQUENCIES_FILE, line, "trip_id", &record.trip_id)? {
+            frequencies.entry(record.trip_id.clone()).or_insert_with(Vec::new).push(record);
+        }
+    }
+    for (trip_id, trip_frequencies) in frequencies {
+        let template = trips.remove(&trip_id).unwrap();
+        let first_departure = match template.stop_times.first() {
+            Some(stop_time) => stop_time.departure_time,
+            None => continue,
+        };
+        for frequency in trip_frequencies {
+            let headway = Headway {
+                headway_secs: frequency.headway_secs,
+                exact_times: frequency.exact_times == Some(1),
+            };
+            for start in (frequency.start_time..frequency.end_time).step_by(frequency.headway_secs as usize) {
+                let mut trip = template.clone();
+                trip.trip_id = format!("{}@{:0>2}:{:0>2}:{:0>2}", trip_id, start / 3600, start % 3600 / 60, start % 60);
+                trip.headway = Some(headway);
+                // the instances of two trips of a block cannot be paired, so they do not continue as one another
+                trip.block_id = None;
+                let shift = start + trip.get_expected_wait();
+                // the stop times of an instance share its id like those loaded from stop_times.txt
+                let instance_id: Arc<str> = Arc::from(trip.trip_id.as_str());
+                for stop_time in trip.stop_times.iter_mut() {
+                    stop_time.trip_id = instance_id.clone();
+                    // the first stop may be reached before its departure, which is where the instances start
+                    stop_time.arrival_time = (stop_time.arrival_time + shift).saturating_sub(first_departure);
+                    stop_time.departure_time = stop_time.departure_time + shift - first_departure;
+                }
+                trips.insert(trip.trip_id.clone(), trip);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_frequencies_loading() {
+    use crate::test_feed::FeedChange;
+
+    let path = crate::test_feed::write_test_feed_with("prahadlo_test_frequencies", &[
+        // the metro waits at its first stop for a minute
+        FeedChange::Replace(STOP_TIMES_FILE, "T3,08:05:00,08:05:00,U1Z1,1,,0,0,", "T3,08:04:00,08:05:00,U1Z1,1,,0,0,"),
+        FeedChange::Replace("trips.txt", "L3,daily,T3,Epsilon,,0,,,1,1,0,1", "L3,daily,T3,Epsilon,,0,B3,,1,1,0,1"),
+        FeedChange::Add(FREQUENCIES_FILE, "trip_id,start_time,end_time,headway_secs,exact_times"),
+        FeedChange::Add(FREQUENCIES_FILE, "T3,08:00:00,09:00:00,600,1"),
+        FeedChange::Add(FREQUENCIES_FILE, "T2,08:00:00,08:30:00,900,0"),
+        FeedChange::Add(FREQUENCIES_FILE, "T1,08:00:00,09:00:00,0,"),
+    ]);
+    let mut feed = FeedReader::lenient(&path);
+    let stops = load_stops(&mut feed).unwrap();
+    let routes = load_routes(&mut feed).unwrap();
+    let services = load_services(&mut feed).unwrap();
+    let mut trips = load_trips(&mut feed, &routes, &services).unwrap();
+    load_stop_times(&mut feed, &mut trips, &stops).unwrap();
+    load_frequencies(&mut feed, &mut trips).unwrap();
+
+    assert_eq!(feed.warnings().len(), 1);
+    assert_eq!(feed.warnings()[0].line(), Some(4));
+    assert!(trips.contains_key("T1") && !trips.contains_key("T2") && !trips.contains_key("T3"));
+    assert_eq!(trips.keys().filter(|trip_id| trip_id.starts_with("T3@")).count(), 6);
+    let metro = &trips["T3@08:50:00"];
+    assert_eq!(metro.headway, Some(Headway { headway_secs: 600, exact_times: true }));
+    assert_eq!((metro.stop_times[0].departure_time, metro.stop_times[1].arrival_time), (31800, 33300));
+    assert_eq!(metro.stop_times[0].arrival_time, 31740);
+    assert_eq!(&*metro.stop_times[1].trip_id, "T3@08:50:00");
+    assert_eq!(metro.get_template_id(), "T3");
+    assert_eq!(metro.block_id, None);
+    assert_eq!(trips["T1"].get_template_id(), "T1");
+    // the passengers wait for the buses without exact times half of the headway
+    let bus = &trips["T2@08:15:00"];
+    assert_eq!(bus.get_expected_wait(), 450);
+    assert_eq!((bus.stop_times[0].departure_time, bus.stop_times[1].arrival_time), (30150, 30750));
+    assert!(!trips.contains_key("T2@08:30:00"));
+}
+
+/// Positions of the columns of stop_times.txt, the optional ones may be missing
+struct StopTimeColumns {
+    trip_id: usize,
+    arrival_time: usize,
+    departure_time: usize,
+    stop_id: usize,
+    stop_sequence: usize,
+    stop_headsign: Option<usize>,
+    pickup_type: usize,
+    drop_off_type: usize,
+    shape_dist_travelled: Option<usize>,
+}
+
+impl StopTimeColumns {
+    /// Finds the columns in the header, a missing required column is an error in both modes
+    fn new(headers: &csv::ByteRecord) -> Result<StopTimeColumns, GtfsError> {
+        let find = |name: &str| headers.iter().position(|header| header == name.as_bytes());
+        let require = |name: &str| find(name).ok_or_else(|| GtfsError::InvalidRecord {
+            file: String::from(STOP_TIMES_FILE),
+            line: 1,
+            field: Some(String::from(name)),
+            message: String::from("the column is missing"),
+        });
+        Ok(StopTimeColumns {
+            trip_id: require("trip_id")?,
+            arrival_time: require("arrival_time")?,
+            departure_time: require("departure_time")?,
+            stop_id: require("stop_id")?,
+            stop_sequence: require("stop_sequence")?,
+            stop_headsign: find("stop_headsign"),
+            pickup_type: require("pickup_type")?,
+            drop_off_type: require("drop_off_type")?,
+            shape_dist_travelled: find("shape_dist_travelled"),
+        })
+    }
+
+    /// Parses a record into the same StopTime as deserializing it would and finds the slot of its trip.
+    /// The ids are borrowed from the record and replaced by their interned copies, nothing is allocated for them.
+    fn parse(&self, record: &csv::ByteRecord, trips: &InternedIds, stops: &InternedIds) -> Result<(usize, StopTime), GtfsError> {
+        let fields = StopTimeRecord { record: record, line: record.position().map_or(0, |position| position.line()) };
+        let trip_id = fields.text(self.trip_id, "trip_id")?;
+        let arrival_time = fields.time(self.arrival_time, "arrival_time")?;
+        let departure_time = fields.time(self.departure_time, "departure_time")?;
+        let stop_id = fields.text(self.stop_id, "stop_id")?;
+        let stop_sequence = fields.number(self.stop_sequence, "stop_sequence")?;
+        let stop_headsign = fields.optional_text(self.stop_headsign, "stop_headsign")?.map(String::from);
+        let pickup_type = fields.number(self.pickup_type, "pickup_type")?;
+        let drop_off_type = fields.number(self.drop_off_type, "drop_off_type")?;
+        let shape_dist_travelled = match self.shape_dist_travelled {
+            Some(index) if !record[index].is_empty() => Some(fields.number(index, "shape_dist_travelled")?),
+            _ => None,
+        };
+        let (slot, trip_id) = trips.get(trip_id).ok_or_else(|| fields.unknown_reference("trip_id", trip_id))?;
+        let (_, stop_id) = stops.get(stop_id).ok_or_else(|| fields.unknown_reference("stop_id", stop_id))?;
+        Ok((slot, StopTime {
+            trip_id: trip_id,
+            arrival_time: arrival_time,
+            departure_time: departure_time,
+            stop_id: stop_id,
+            stop_sequence: stop_sequence,
+            stop_headsign: stop_headsign,
+            pickup_type: pickup_type,
+            drop_off_type: drop_off_type,
+            shape_dist_travelled: shape_dist_travelled,
+        }))
+    }
+}
+
+/// The ids of the trips or the stops, each allocated once and numbered by its slot
+struct InternedIds {
+    ids: Vec<Arc<str>>,
+    slots: HashMap<Arc<str>, usize>,
+}
+
+impl InternedIds {
+    fn new<T>(map: &HashMap<String, T>) -> InternedIds {
+        let ids: Vec<Arc<str>> = map.keys().map(|id| Arc::from(id.as_str())).collect();
+        let slots = ids.iter().enumerate().map(|(slot, id)| (id.clone(), slot)).collect();
+        InternedIds { ids: ids, slots: slots }
+    }
+
+    /// Finds the slot of @id together with its shared copy
+    fn get(&self, id: &str) -> Option<(usize, Arc<str>)> {
+        self.slots.get(id).map(|slot| (*slot, self.ids[*slot].clone()))
+    }
+}
+
+/// The fields of a record of stop_times.txt, errors point to the line and the field they are in
+struct StopTimeRecord<'r> {
+    record: &'r csv::ByteRecord,
+    line: u64,
+}
+
+impl<'r> StopTimeRecord<'r> {
+    fn invalid(&self, field: &str, message: String) -> GtfsError {
+        GtfsError::InvalidRecord { file: String::from(STOP_TIMES_FILE), line: self.line, field: Some(String::from(field)), message: message }
+    }
+
+    fn unknown_reference(&self, field: &str, value: &str) -> GtfsError {
+        GtfsError::UnknownReference { file: String::from(STOP_TIMES_FILE), line: self.line, field: String::from(field), value: String::from(value) }
+    }
+
+    fn text(&self, index: usize, field: &str) -> Result<&'r str, GtfsError> {
+        std::str::from_utf8(&self.record[index]).map_err(|err| self.invalid(field, err.to_string()))
+    }
+
+    /// Returns None for empty values and missing columns
+    fn optional_text(&self, index: Option<usize>, field: &str) -> Result<Option<&'r str>, GtfsError> {
+        match index {
+            Some(index) if !self.record[index].is_empty() => Ok(Some(self.text(index, field)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn number<T: FromStr>(&self, index: usize, field: &str) -> Result<T, GtfsError>
+    where
+        T::Err: fmt::Display,
+    {
+        let value = self.text(index, field)?;
+        value.parse::<T>().map_err(|err| self.invalid(field, format!("invalid value: string {:?}, {}", value, err)))
+    }
+
+    fn time(&self, index: usize, field: &str) -> Result<u32, GtfsError> {
+        let value = self.text(index, field)?;
+        parse_time(value).ok_or_else(|| self.invalid(field, format!("invalid value: string {:?}, expected a time as HH:MM:SS", value)))
+    }
+}
+
+/// Loads the contents of stop_times.txt into the trips, ordered by their stop sequence. The file is by far
+/// the largest one of the feed, so it is not deserialized like the others. The records are read in batches
+/// without converting them to strings, then the batch is parsed in parallel. The ids of the trips and the stops
+/// are interned, so the stop times share them and are filed under the slot of their trip.
+pub fn load_stop_times(feed: &mut FeedReader, trips: &mut HashMap<String, Trip>, stops: &HashMap<String, Stop>) -> Result<(), GtfsError> {
+    let trip_ids = InternedIds::new(trips);
+    let stop_ids = InternedIds::new(stops);
+    let mut stop_times: Vec<Vec<StopTime>> = vec![Vec::new(); trip_ids.ids.len()];
+    feed.read_file(STOP_TIMES_FILE, |feed, reader| {
+        let mut rdr = csv::ReaderBuilder::new().buffer_capacity(STOP_TIMES_BUFFER).from_reader(reader);
+        let columns = match rdr.byte_headers() {
+            Ok(headers) => StopTimeColumns::new(headers)?,
+            Err(err) => return Err(record_error(STOP_TIMES_FILE, None, err)),
+        };
+        let mut batch = vec![csv::ByteRecord::new(); STOP_TIMES_BATCH];
+        let mut finished = false;
+        while !finished {
+            let mut count = 0;
+            // an invalid line ends the batch, so that the errors are reported in the order of the lines
+            let mut read_error = None;
+            while count < batch.len() {
+                match rdr.read_byte_record(&mut batch[count]) {
+                    Ok(true) => count += 1,
+                    Ok(false) => {
+                        finished = true;
+                        break;
+                    },
+                    Err(err) => match err.kind() {
+                        csv::ErrorKind::Io(_) => return Err(record_error(STOP_TIMES_FILE, None, err)),
+                        _ => {
+                            read_error = Some(record_error(STOP_TIMES_FILE, None, err));
+                            break;
+                        },
+                    },
+                }
+            }
+            let parsed: Vec<Result<(usize, StopTime), GtfsError>> = batch[..count].par_iter()
+                .map(|record| columns.parse(record, &trip_ids, &stop_ids))
+                .collect();
+            for result in parsed {
+                match result {
+                    Ok((slot, stop_time)) => stop_times[slot].push(stop_time),
+                    Err(err) => feed.report(err)?,
+                }
+            }
+            if let Some(err) = read_error {
+                feed.report(err)?;
+            }
+        }
+        Ok(())
+    })?;
+    for (trip_id, mut trip_stop_times) in trip_ids.ids.iter().zip(stop_times) {
+        trips.get_mut(&**trip_id).unwrap().stop_times.append(&mut trip_stop_times);
+    }
+    trips.par_iter_mut().for_each(|(_, trip)| {
+        trip.stop_times
+            .sort_by_key(|a| a.stop_sequence);
+    });
+    Ok(())
+}
+
+#[test]
+fn test_stop_times_loading() {
+    let path = crate::test_feed::write_test_feed("prahadlo_test_stop_times");
+    std::fs::write(path.join(STOP_TIMES_FILE), "\
+trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign,pickup_type,drop_off_type,shape_dist_travelled
+T1,08:10:00,08:11:00,U2Z1,2,\"Gama, nádraží\",0,0,1.5
+T1,08:00:00,08:00:00,U1Z1,1,,0,1,
+T1,24:20:00,24:20:00,U3Z1,3,,1,0,3.25
+T2,8:15:00,08:15:00,U2Z1,1,,3,0,
+T2,08:25:00,08:25:00,U5Z1,2,,0,3,
+").unwrap();
+    let mut feed = FeedReader::new(&path);
+    let stops = load_stops(&mut feed).unwrap();
+    let routes = load_routes(&mut feed).unwrap();
+    let services = load_services(&mut feed).unwrap();
+    let mut trips = load_trips(&mut feed, &routes, &services).unwrap();
+    load_stop_times(&mut feed, &mut trips, &stops).unwrap();
+
+    let mut expected = load_trips(&mut feed, &routes, &services).unwrap();
+    load_stop_times_by_records(&mut feed, &mut expected, &stops).unwrap();
+    assert_eq!(format!("{:?}", sorted_stop_times(&trips)), format!("{:?}", sorted_stop_times(&expected)));
+    assert_eq!(trips["T1"].stop_times[1].stop_headsign, Some(String::from("Gama, nádraží")));
+    assert_eq!(trips["T1"].stop_times[2].arrival_time, 24 * 3600 + 20 * 60);
+
+    let mut file = std::fs::OpenOptions::new().append(true).open(path.join(STOP_TIMES_FILE)).unwrap();
+    std::io::Write::write_all(&mut file, b"T3,08:05:00,8:5,U1Z1,1,,0,0,\nT9,08:30:00,08:30:00,U5Z1,2,,0,0,\n").unwrap();
+    let mut trips = load_trips(&mut feed, &routes, &services).unwrap();
+    match load_stop_times(&mut feed, &mut trips, &stops) {
+        Err(GtfsError::InvalidRecord { line, field, .. }) => {
+            assert_eq!(line, 7);
+            assert_eq!(field, Some(String::from("departure_time")));
+        },
+        other => panic!("unexpected result {:?}", other),
+    }
+    let mut feed = FeedReader::lenient(&path);
+    let mut trips = load_trips(&mut feed, &routes, &services).unwrap();
+    load_stop_times(&mut feed, &mut trips, &stops).unwrap();
+    assert_eq!(feed.warnings().len(), 2);
+    assert_eq!(feed.warnings()[1].line(), Some(8));
+    assert!(trips["T3"].stop_times.is_empty());
+
+    // the records left out and the warnings are the same as well
+    let mut old_feed = FeedReader::lenient(&path);
+    let mut expected = load_trips(&mut old_feed, &routes, &services).unwrap();
+    load_stop_times_by_records(&mut old_feed, &mut expected, &stops).unwrap();
+    assert_eq!(format!("{:?}", sorted_stop_times(&trips)), format!("{:?}", sorted_stop_times(&expected)));
+    let warnings = |feed: &FeedReader| feed.warnings().iter().map(|warning| warning.to_string()).collect::<Vec<String>>();
+    assert_eq!(warnings(&feed), warnings(&old_feed));
+}
+
+/// The loader of stop_times.txt deserializing the records one by one, which load_stop_times replaced
+#[cfg(test)]
+fn load_stop_times_by_records(feed: &mut FeedReader, trips: &mut HashMap<String, Trip>, stops: &HashMap<String, Stop>) -> Result<(), GtfsError> {
+    for (line, stop_time) in feed.read_records::<StopTime>(STOP_TIMES_FILE)? {
+        if check_reference(feed, trips, STOP_TIMES_FILE, line, "trip_id", &stop_time.trip_id)?
+            && check_reference(feed, stops, STOP_TIMES_FILE, line, "stop_id", &stop_time.stop_id)? {
+            trips.get_mut(&*stop_time.trip_id).unwrap().stop_times.push(stop_time);
+        }
     }
     for trip in trips.values_mut() {
         trip.stop_times
-            .sort_by(|a, b| a.stop_sequence.cmp(&b.stop_sequence));
+            .sort_by_key(|a| a.stop_sequence);
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(test)]
+fn sorted_stop_times(trips: &HashMap<String, Trip>) -> Vec<(&String, &Vec<StopTime>)> {
+    let mut stop_times: Vec<(&String, &Vec<StopTime>)> = trips.iter().map(|(trip_id, trip)| (trip_id, &trip.stop_times)).collect();
+    stop_times.sort_by_key(|(trip_id, _)| *trip_id);
+    stop_times
+}
@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+
+use prahadlo::gtfs::{Stop, Trip};
+use prahadlo::network::*;
+use prahadlo::{ConnectionQuery, Engine, Error, SearchProfile};
+
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[cfg(test)]
+use crate::test_feed::*;
+
+const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+/// Number of departures returned when the request does not say
+const DEFAULT_DEPARTURE_COUNT: usize = 10;
+/// The most departures a single request can ask for
+const MAX_DEPARTURE_COUNT: usize = 100;
+
+/// An error answered with a status code and a message in JSON
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_request(message: &str) -> ApiError {
+        ApiError { status: 400, message: String::from(message) }
+    }
+
+    fn not_found(message: &str) -> ApiError {
+        ApiError { status: 404, message: String::from(message) }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> ApiError {
+        let status = match err {
+            Error::StopNotFound(_) => 404,
+            Error::UnsupportedQuery(_) => 400,
+            _ => 500,
+        };
+        ApiError { status: status, message: err.to_string() }
+    }
+}
+
+/// Serves the network over HTTP. Every endpoint takes its arguments in the query string and answers with JSON.
+pub struct HttpInterface {
+    server: Server,
+}
+
+/// Decodes a part of an URL query string, that is the escaped bytes and pluses standing for spaces
+fn decode_url_component(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let hex_value = |index: usize| bytes.get(index).and_then(|byte| (*byte as char).to_digit(16));
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match (bytes[index], hex_value(index + 1), hex_value(index + 2)) {
+            (b'+', _, _) => decoded.push(b' '),
+            (b'%', Some(high), Some(low)) => {
+                decoded.push((high * 16 + low) as u8);
+                index += 2;
+            },
+            (byte, _, _) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[test]
+fn test_decode_url_component() {
+    assert_eq!(decode_url_component("Hlavn%C3%AD+n%C3%A1dra%C5%BE%C3%AD"), "Hlavní nádraží");
+    assert_eq!(decode_url_component("2020-04-14T07%3A50%3A00"), "2020-04-14T07:50:00");
+    assert_eq!(decode_url_component("100%"), "100%");
+}
+
+fn parse_query(url: &str) -> (String, HashMap<String, String>) {
+    let mut parts = url.splitn(2, "?");
+    let path = String::from(parts.next().unwrap_or(""));
+    let params = parts.next().unwrap_or("")
+        .split("&")
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let mut key_value = param.splitn(2, "=");
+            let key = decode_url_component(key_value.next().unwrap_or(""));
+            let value = decode_url_component(key_value.next().unwrap_or(""));
+            (key, value)
+        })
+        .collect();
+    (path, params)
+}
+
+fn get_param<'a>(params: &'a HashMap<String, String>, name: &str) -> Result<&'a String, ApiError> {
+    params.get(name).ok_or(ApiError::bad_request(&format!("Missing parameter {}", name)))
+}
+
+fn get_time_param(params: &HashMap<String, String>, name: &str) -> Result<NaiveDateTime, ApiError> {
+    NaiveDateTime::parse_from_str(get_param(params, name)?, DATETIME_FORMAT)
+        .map_err(|_| ApiError::bad_request(&format!("Parameter {} must be in the format YYYY-MM-DDTHH:MM:SS", name)))
+}
+
+fn format_time(time: &NaiveDateTime) -> String {
+    time.format(DATETIME_FORMAT).to_string()
+}
+
+/// Formats a time of a service day, which can be past midnight
+fn format_service_time(seconds: u32) -> String {
+    let days = seconds / 86400;
+    let time = NaiveTime::from_num_seconds_from_midnight_opt(seconds % 86400, 0).unwrap().format("%H:%M:%S").to_string();
+    if days == 0 { time } else { format!("{}+{}", time, days) }
+}
+
+fn stop_to_json(stop: &Stop) -> Value {
+    json!({
+        "stop_id": stop.stop_id,
+        "stop_name": stop.stop_name,
+        "lat": stop.stop_lat,
+        "lon": stop.stop_lon,
+        "platform_code": stop.platform_code,
+    })
+}
+
+/// Splits a connection into legs, each of them either riding a line or walking between stops
+fn connection_to_json(nw: &Network, conn: &Connection, mobility: Mobility) -> Value {
+    let mut legs: Vec<Value> = Vec::new();
+    // the boarding stop and time of the trip the connection is on
+    let mut boarding: Option<(&Stop, usize)> = None;
+    for (location_index, location) in conn.locations.iter().enumerate().skip(1) {
+        // the segments after the first one start at a via stop
+        if conn.segments.iter().skip(1).any(|segment| segment.start == location_index) {
+            legs.push(json!({
+                "type": "via",
+                "stop": stop_to_json(nw.get_stop_by_index(location.get_stop())),
+                "arrival": format_time(&conn.get_arrival_before(location_index)),
+                "departure": format_time(&conn.times[location_index]),
+            }));
+        }
+        let mut ride = |trip: &Trip, to_stop: &Stop, arrival_index: usize, boarding: Option<(&Stop, usize)>| {
+            if let Some((from_stop, departure_index)) = boarding {
+                // the trip stops at the boarding and getting off locations, which tell the visit of the stop
+                let stop_time = |index: usize| match conn.locations[index] {
+                    Location::Trip(trip, _, _, position) => Some(nw.get_stop_time(trip, position)),
+                    Location::Stop(_) => None,
+                };
+                legs.push(json!({
+                    "type": "ride",
+                    "line": nw.get_trip_short_name(trip),
+                    "trip_id": trip.trip_id,
+                    "from": stop_to_json(from_stop),
+                    "to": stop_to_json(to_stop),
+                    "departure": format_time(&conn.times[departure_index]),
+                    "arrival": format_time(&conn.times[arrival_index]),
+                    "boarding_on_request": stop_time(departure_index).is_some_and( |stop_time| stop_time.is_boarding_on_request()),
+                    "getting_off_on_request": stop_time(arrival_index).is_some_and( |stop_time| stop_time.is_getting_off_on_request()),
+                    // the trips of frequencies.txt run every headway, without exact times the times are expected ones
+                    "headway_secs": trip.headway.map(|headway| headway.headway_secs),
+                    "exact_times": trip.headway.is_none_or(|headway| headway.exact_times),
+                }));
+            }
+        };
+        match (conn.locations[location_index - 1], *location) {
+            (Location::Stop(from_stop), Location::Stop(to_stop)) => {
+                if from_stop != to_stop {
+                    legs.push(json!({
+                        "type": match mobility {
+                            Mobility::Bicycle { .. } => "cycle",
+                            _ => "walk",
+                        },
+                        "from": stop_to_json(nw.get_stop_by_index(from_stop)),
+                        "to": stop_to_json(nw.get_stop_by_index(to_stop)),
+                        "departure": format_time(&conn.times[location_index - 1]),
+                        "arrival": format_time(&conn.times[location_index]),
+                    }));
+                }
+            },
+            (Location::Stop(stop), Location::Trip(_, _, _, _)) => boarding = Some((nw.get_stop_by_index(stop), location_index)),
+            (Location::Trip(trip, _, _, _), Location::Stop(stop)) => {
+                ride(nw.get_trip_by_index(trip), nw.get_stop_by_index(stop), location_index - 1, boarding);
+                boarding = None;
+            },
+            (Location::Trip(past_trip, _, _, _), Location::Trip(trip, _, stop, _)) => {
+                if past_trip != trip {
+                    let stop = nw.get_stop_by_index(stop);
+                    ride(nw.get_trip_by_index(past_trip), stop, location_index - 1, boarding);
+                    boarding = Some((stop, location_index));
+                    // the passenger stays seated when the vehicle continues as the next trip
+                    if nw.get_block_successor(past_trip) == Some(trip) {
+                        if let Some(leg) = legs.last_mut() {
+                            leg["continues_as"] = json!(nw.get_trip_short_name(nw.get_trip_by_index(trip)));
+                        }
+                    }
+                }
+            },
+        }
+    }
+    // the connection may begin and end with waiting at the stops, which the legs leave out
+    let departure = legs.first().map_or(Value::Null, |leg| leg["departure"].clone());
+    let arrival = legs.last().map_or(Value::Null, |leg| leg["arrival"].clone());
+    json!({
+        "departure": departure,
+        "arrival": arrival,
+        "transfers": conn.transfers,
+        "walking_distance": conn.walking_distance,
+        "legs": legs,
+    })
+}
+
+fn find_connection(nw: &Network, params: &HashMap<String, String>) -> Result<Value, ApiError> {
+    let (from, to) = (get_param(params, "from")?, get_param(params, "to")?);
+    let time = get_time_param(params, "time")?;
+    let arrive_by = params.get("arrive_by").is_some_and(|value| value == "true" || value == "1");
+    let engine = match params.get("engine") {
+        Some(engine) => engine.parse().map_err(|_| ApiError::bad_request("Parameter engine must be graph, csa or raptor"))?,
+        None => Engine::Graph,
+    };
+    let max_transfers = match params.get("max_transfers") {
+        Some(count) => Some(count.parse().map_err(|_| ApiError::bad_request("Parameter max_transfers must be a number"))?),
+        None => None,
+    };
+    let query = if arrive_by {
+        ConnectionQuery::arriving_by(from, to, time)
+    } else {
+        ConnectionQuery::departing_at(from, to, time)
+    };
+    let profile = match params.get("profile").map(|profile| profile.as_str()) {
+        Some("wheelchair") => SearchProfile::wheelchair(),
+        Some("bicycle") => {
+            let parse = |name: &str, default: f32| match params.get(name) {
+                Some(value) => value.parse::<f32>().ok().filter(|value| *value > 0.0)
+                    .ok_or_else(|| ApiError::bad_request(&format!("Parameter {} must be a positive number", name))),
+                None => Ok(default),
+            };
+            SearchProfile::bicycle(parse("cycling_speed", DEFAULT_CYCLING_SPEED)?, parse("cycling_radius", MAX_CYCLING_DIST)?)
+        },
+        Some("walking") | None => SearchProfile::default(),
+        Some(_) => return Err(ApiError::bad_request("Parameter profile must be walking, wheelchair or bicycle")),
+    };
+    let mobility = profile.mobility;
+    // the route filters are comma separated lists
+    let list = |name: &str| -> Vec<String> {
+        params.get(name).map_or(Vec::new(), |values| values.split(",").map(|value| String::from(value.trim())).collect())
+    };
+    let route_types = |name: &str| -> Result<Vec<u32>, ApiError> {
+        list(name).iter()
+            .map(|route_type| route_type.parse().map_err(|_| ApiError::bad_request(&format!("Parameter {} must be a list of numbers", name))))
+            .collect()
+    };
+    let routes = RouteFilter {
+        include_route_types: route_types("route_types")?,
+        exclude_route_types: route_types("exclude_route_types")?,
+        include_lines: list("lines"),
+        exclude_lines: list("exclude_lines"),
+        include_agencies: list("agencies"),
+        exclude_agencies: list("exclude_agencies"),
+    };
+    let query = query.profile(profile.routes(routes));
+    let query = match params.get("via") {
+        Some(via) => {
+            let min_dwell = match params.get("min_dwell") {
+                Some(minutes) => minutes.parse::<u32>().ok()
+                    .filter(|minutes| *minutes <= MAX_VIA_DWELL_MINUTES)
+                    .ok_or_else(|| ApiError::bad_request("Parameter min_dwell must be a number of minutes up to a day"))?,
+                None => 0,
+            };
+            query.via(via, Duration::minutes(min_dwell as i64))
+        },
+        None => query,
+    };
+    let query = match max_transfers {
+        Some(max_transfers) => query.engine(engine).max_transfers(max_transfers),
+        None => query.engine(engine),
+    };
+    match query.run(nw)?.first() {
+        Some(conn) => Ok(connection_to_json(nw, conn, mobility)),
+        None => Err(ApiError::not_found("No connection found")),
+    }
+}
+
+fn find_stops(nw: &Network, params: &HashMap<String, String>) -> Result<Value, ApiError> {
+    let name = get_param(params, "name")?;
+    let stops: Vec<Value> = nw.find_stops(name).into_iter().map(stop_to_json).collect();
+    Ok(Value::Array(stops))
+}
+
+fn find_departures(nw: &Network, params: &HashMap<String, String>) -> Result<Value, ApiError> {
+    let stop_name = get_param(params, "stop")?;
+    let time = get_time_param(params, "time")?;
+    let count = match params.get("count") {
+        Some(count) => count.parse().ok()
+            .filter(|count| *count <= MAX_DEPARTURE_COUNT)
+            .ok_or(ApiError::bad_request(&format!("Parameter count must be a number up to {}", MAX_DEPARTURE_COUNT)))?,
+        None => DEFAULT_DEPARTURE_COUNT,
+    };
+    let departures = nw.find_departures(stop_name, time, count)?;
+    Ok(Value::Array(departures.iter()
+        .map(|departure| json!({
+            "stop": stop_to_json(departure.stop),
+            "line": nw.get_trip_short_name(departure.trip),
+            "trip_id": departure.trip.trip_id,
+            "headsign": departure.trip.trip_headsign,
+            "departure": format_time(&departure.departure),
+        }))
+        .collect()))
+}
+
+fn get_trip(nw: &Network, params: &HashMap<String, String>) -> Result<Value, ApiError> {
+    let trip = nw.get_trip(get_param(params, "id")?).ok_or(ApiError::not_found("No trip with such id"))?;
+    let stop_times: Vec<Value> = trip.stop_times.iter()
+        .map(|stop_time| json!({
+            "stop": nw.get_stop(&stop_time.stop_id).map(stop_to_json),
+            "arrival": format_service_time(stop_time.arrival_time),
+            "departure": format_service_time(stop_time.departure_time),
+        }))
+        .collect();
+    Ok(json!({
+        "trip_id": trip.trip_id,
+        "route_id": trip.route_id,
+        "service_id": trip.service_id,
+        "line": nw.get_trip_short_name(trip),
+        "headsign": trip.trip_headsign,
+        "stop_times": stop_times,
+    }))
+}
+
+fn respond(request: Request, status: u16, body: Value) {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json; charset=utf-8"[..]).unwrap();
+    let response = Response::from_string(body.to_string()).with_status_code(status).with_header(header);
+    if let Err(err) = request.respond(response) {
+        println!("ERROR: could not send a response: {}", err);
+    }
+}
+
+impl HttpInterface {
+    pub fn new(address: &str) -> HttpInterface {
+        let server = Server::http(address).expect("Could not start the HTTP server");
+        println!("Listening on http://{}", address);
+        HttpInterface { server: server }
+    }
+
+    /// Answers the next request. Can be called from several threads at once to answer requests in parallel.
+    pub fn process_request(&self, nw: &Network) {
+        let request = match self.server.recv() {
+            Ok(request) => request,
+            Err(err) => {
+                println!("Error: {:?}", err);
+                return;
+            }
+        };
+        let (path, params) = parse_query(request.url());
+        let result = match (request.method(), path.as_str()) {
+            (Method::Get, "/connection") => find_connection(nw, &params),
+            (Method::Get, "/stops") => find_stops(nw, &params),
+            (Method::Get, "/departures") => find_departures(nw, &params),
+            (Method::Get, "/trip") => get_trip(nw, &params),
+            _ => Err(ApiError::not_found("No such endpoint")),
+        };
+        match result {
+            Ok(body) => respond(request, 200, body),
+            Err(err) => respond(request, err.status, json!({ "error": err.message })),
+        }
+    }
+}
+
+#[cfg(test)]
+fn get_params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs.iter().map(|(key, value)| (String::from(*key), String::from(*value))).collect()
+}
+
+#[test]
+fn test_connection_response() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_http_connection")).unwrap();
+    let params = get_params(&[("from", "Alfa"), ("to", "Epsilon"), ("time", "2020-06-10T07:50:00")]);
+    let body = find_connection(&nw, &params).unwrap();
+    assert_eq!(body["departure"], "2020-06-10T08:00:00");
+    assert_eq!(body["arrival"], "2020-06-10T08:25:00");
+    assert_eq!(body["transfers"], 1);
+    let legs: Vec<(&str, &str, &str, &str, &str)> = body["legs"].as_array().unwrap().iter()
+        .map(|leg| (
+            leg["type"].as_str().unwrap(),
+            leg["line"].as_str().unwrap(),
+            leg["from"]["stop_name"].as_str().unwrap(),
+            leg["to"]["stop_name"].as_str().unwrap(),
+            leg["arrival"].as_str().unwrap(),
+        ))
+        .collect();
+    assert_eq!(legs, vec![
+        ("ride", "1", "Alfa", "Beta", "2020-06-10T08:10:00"),
+        ("ride", "2", "Beta", "Epsilon", "2020-06-10T08:25:00"),
+    ]);
+
+    let params = get_params(&[("from", "Alfa"), ("to", "Epsilon"), ("time", "2020-06-10T08:28:00"), ("arrive_by", "true")]);
+    let body = find_connection(&nw, &params).unwrap();
+    assert_eq!(body["departure"], "2020-06-10T08:00:00");
+}
+
+#[test]
+fn test_error_responses() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_http_errors")).unwrap();
+    let status = |result: Result<Value, ApiError>| result.unwrap_err().status;
+    assert_eq!(status(find_connection(&nw, &get_params(&[("from", "Alfa"), ("to", "Epsilon")]))), 400);
+    assert_eq!(status(find_connection(&nw, &get_params(&[("from", "Alfa"), ("to", "Epsilon"), ("time", "10:00")]))), 400);
+    // nothing leaves Gama
+    assert_eq!(status(find_connection(&nw, &get_params(&[("from", "Gama"), ("to", "Alfa"), ("time", "2020-06-10T07:50:00")]))), 404);
+    assert_eq!(status(get_trip(&nw, &get_params(&[("id", "T9")]))), 404);
+    assert_eq!(status(find_departures(&nw, &get_params(&[("stop", "Alfa"), ("time", "2020-06-10T07:50:00"), ("count", "few")]))), 400);
+    assert_eq!(status(find_departures(&nw, &get_params(&[("stop", "Alfa"), ("time", "2020-06-10T07:50:00"), ("count", "18446744073709551615")]))), 400);
+}
+
+#[test]
+fn test_profile_param() {
+    // the bus cannot take a wheelchair
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_http_profile", &[
+        FeedChange::Replace("trips.txt", "L2,daily,T2,Epsilon,,0,,,1,1,0,1", "L2,daily,T2,Epsilon,,0,,,2,1,0,1"),
+    ])).unwrap();
+    let query = [("from", "Alfa"), ("to", "Epsilon"), ("time", "2020-06-10T07:50:00")];
+    let with = |extra: &[(&'static str, &'static str)]| get_params(&[&query[..], extra].concat());
+    assert_eq!(find_connection(&nw, &with(&[])).unwrap()["arrival"], "2020-06-10T08:25:00");
+    assert_eq!(find_connection(&nw, &with(&[("profile", "walking")])).unwrap()["arrival"], "2020-06-10T08:25:00");
+    let body = find_connection(&nw, &with(&[("profile", "wheelchair")])).unwrap();
+    assert_eq!(body["arrival"], "2020-06-10T08:30:00");
+    assert_eq!(body["legs"][0]["line"], "A");
+    assert_eq!(find_connection(&nw, &with(&[("profile", "stroller")])).unwrap_err().status, 400);
+    let body = find_connection(&nw, &with(&[("profile", "bicycle")])).unwrap();
+    assert_eq!(body["arrival"], "2020-06-10T08:25:00");
+    assert_eq!((body["legs"][0]["type"].as_str(), body["legs"][1]["type"].as_str()), (Some("cycle"), Some("cycle")));
+    let body = find_connection(&nw, &with(&[("profile", "bicycle"), ("cycling_speed", "5"), ("cycling_radius", "1000")])).unwrap();
+    assert_eq!((body["legs"][0]["type"].as_str(), body["legs"][0]["line"].as_str()), (Some("ride"), Some("1")));
+    assert_eq!(find_connection(&nw, &with(&[("profile", "bicycle"), ("cycling_speed", "fast")])).unwrap_err().status, 400);
+    assert_eq!(find_connection(&nw, &with(&[("profile", "bicycle"), ("cycling_radius", "-100")])).unwrap_err().status, 400);
+    // only the graph search knows the profiles
+    assert_eq!(find_connection(&nw, &with(&[("profile", "wheelchair"), ("engine", "csa")])).unwrap_err().status, 400);
+}
+
+#[test]
+fn test_via_params() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_http_via")).unwrap();
+    let query = [("from", "Alfa"), ("to", "Epsilon"), ("time", "2020-06-10T07:50:00"), ("via", "Beta")];
+    let with = |extra: &[(&'static str, &'static str)]| get_params(&[&query[..], extra].concat());
+    // the stay starts when the tram arrives at 08:10
+    let body = find_connection(&nw, &with(&[("min_dwell", "5")])).unwrap();
+    assert_eq!(body["arrival"], "2020-06-10T08:25:00");
+    let via = &body["legs"][1];
+    assert_eq!((via["type"].as_str(), via["stop"]["stop_name"].as_str()), (Some("via"), Some("Beta")));
+    assert_eq!((via["arrival"].as_str(), via["departure"].as_str()), (Some("2020-06-10T08:10:00"), Some("2020-06-10T08:15:00")));
+    assert_eq!(find_connection(&nw, &with(&[("min_dwell", "6")])).unwrap()["arrival"], "2020-06-11T08:25:00");
+    assert_eq!(find_connection(&nw, &with(&[])).unwrap()["legs"][1]["departure"], "2020-06-10T08:11:00");
+    for min_dwell in ["-5", "1441", "long"] {
+        assert_eq!(find_connection(&nw, &with(&[("min_dwell", min_dwell)])).unwrap_err().status, 400);
+    }
+}
+
+#[test]
+fn test_departures_response() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_http_departures")).unwrap();
+    let params = get_params(&[("stop", "Alfa"), ("time", "2020-06-10T07:50:00"), ("count", "3")]);
+    let body = find_departures(&nw, &params).unwrap();
+    let departures: Vec<(&str, &str)> = body.as_array().unwrap().iter()
+        .map(|departure| (departure["line"].as_str().unwrap(), departure["departure"].as_str().unwrap()))
+        .collect();
+    assert_eq!(departures, vec![
+        ("1", "2020-06-10T08:00:00"),
+        ("A", "2020-06-10T08:05:00"),
+        ("1", "2020-06-11T08:00:00"),
+    ]);
+}
@@ -0,0 +1,28 @@
+//! Prahadlo finds public transport connections in a GTFS feed.
+//!
+//! The feed is loaded into a [`Network`](network::Network) once, then it can answer any number of queries,
+//! most easily built with a [`ConnectionQuery`](query::ConnectionQuery).
+
+// the crate initializes the fields by name, writes out the returns and the constructors of default values,
+// passes the vectors by reference and compares with booleans in the tests
+#![allow(
+    clippy::redundant_field_names,
+    clippy::needless_return,
+    clippy::unwrap_or_default,
+    clippy::ptr_arg,
+    clippy::bool_assert_comparison,
+)]
+
+pub mod error;
+pub mod gtfs;
+pub mod network;
+pub mod query;
+mod snapshot;
+mod geo_utils;
+mod str_utils;
+#[cfg(test)]
+mod test_feed;
+
+pub use error::{Error, Result};
+pub use network::{Connection, Departure, Network, RouteFilter, SearchProfile, StopArrival};
+pub use query::{ConnectionQuery, Engine};
@@ -1,20 +1,145 @@
+// the crate initializes the fields by name, writes out the returns and the constructors of default values,
+// passes the vectors by reference and compares with booleans in the tests
+#![allow(
+    clippy::redundant_field_names,
+    clippy::needless_return,
+    clippy::unwrap_or_default,
+    clippy::ptr_arg,
+    clippy::bool_assert_comparison,
+)]
+
+use std::env;
 use std::path::Path;
+use std::process::exit;
+use std::thread;
 
-mod gtfs;
-mod network;
-mod str_utils;
 mod text_interface;
-mod geo_utils;
+mod http_interface;
+// the tests of the interfaces only use some of the changes of the feed
+#[cfg(test)]
+#[allow(dead_code)]
+mod test_feed;
 
-use network::*;
+use prahadlo::gtfs::FeedReader;
+use prahadlo::{Engine, Network};
 use text_interface::*;
+use http_interface::*;
 
-fn main() {
-    println!("Hello, world! Prahadlo here!");
-    let nw = Network::new(Path::new("data/"));
+/// The address the server listens on unless another one is given, only reachable from this machine
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+/// The feed is loaded from here unless another directory or zip archive is given
+const DEFAULT_FEED: &str = "data/";
+/// The built network is saved here, so that it does not have to be built again while the feed stays the same
+const DEFAULT_SNAPSHOT: &str = "network.snapshot";
+/// Only this many of the records skipped in lenient mode are printed
+const PRINTED_WARNINGS: usize = 20;
+const USAGE: &str = "Usage: prahadlo [--lenient] [--feed directory_or_zip] [--snapshot file | --no-snapshot] [serve [address]]";
+
+/// The options given on the command line before the command
+struct Options {
+    feed: String,
+    lenient: bool,
+    snapshot: Option<String>,
+}
+
+/// Splits the arguments into the options and the command with its arguments
+fn parse_args(args: Vec<String>) -> Option<(Options, Vec<String>)> {
+    let mut options = Options {
+        feed: String::from(DEFAULT_FEED),
+        lenient: false,
+        snapshot: Some(String::from(DEFAULT_SNAPSHOT)),
+    };
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with("--")) {
+        match arg.as_str() {
+            "--lenient" => options.lenient = true,
+            "--feed" => options.feed = args.next()?,
+            "--snapshot" => options.snapshot = Some(args.next()?),
+            "--no-snapshot" => options.snapshot = None,
+            _ => return None,
+        }
+    }
+    Some((options, args.collect()))
+}
+
+fn load_network(options: &Options) -> Network {
+    let path = Path::new(&options.feed);
+    let mut feed = if options.lenient { FeedReader::lenient(path) } else { FeedReader::new(path) };
+    let snapshot = options.snapshot.as_ref().map(Path::new);
+    if let Some(snapshot) = snapshot {
+        match Network::load_snapshot(snapshot, &feed) {
+            Ok(Some(nw)) => {
+                println!("Network loaded from {}", snapshot.display());
+                nw.print_debug_info();
+                return nw;
+            },
+            Ok(None) => (),
+            Err(err) => {
+                println!("ERROR: {}", err);
+                exit(1);
+            },
+        }
+    }
+    match Network::from_feed(&mut feed) {
+        Ok(nw) => {
+            if let Some(snapshot) = snapshot {
+                // the interfaces search the graph unless told otherwise, so the snapshot should hold it
+                nw.prepare(Engine::Graph);
+                if let Err(err) = nw.save_snapshot(snapshot, &feed) {
+                    println!("WARNING: {}", err);
+                }
+            }
+            for warning in feed.warnings().iter().take(PRINTED_WARNINGS) {
+                println!("WARNING: skipped {}", warning);
+            }
+            if feed.warnings().len() > PRINTED_WARNINGS {
+                println!("WARNING: skipped {} more invalid records", feed.warnings().len() - PRINTED_WARNINGS);
+            }
+            nw.print_debug_info();
+            nw
+        },
+        Err(err) => {
+            println!("ERROR: {}", err);
+            exit(1);
+        }
+    }
+}
+
+fn run_text_interface(nw: &Network) {
     let mut interface = TextInterface::new("history.txt");
-    nw.print_debug_info();
     loop {
-        interface.process_command(&nw);
+        interface.process_command(nw);
+    }
+}
+
+/// Answers the requests in a thread for every core, the network is shared by all of them
+fn run_http_interface(nw: &Network, address: &str) {
+    let interface = HttpInterface::new(address);
+    let workers = thread::available_parallelism().map_or(1, |count| count.get());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                interface.process_request(nw);
+            });
+        }
+    });
+}
+
+fn main() {
+    println!("Hello, world! Prahadlo here!");
+    let (options, args) = match parse_args(env::args().skip(1).collect()) {
+        Some(parsed) => parsed,
+        None => {
+            println!("{}", USAGE);
+            exit(1);
+        }
+    };
+    match args.first().map(|arg| arg.as_str()) {
+        None => run_text_interface(&load_network(&options)),
+        Some("serve") => {
+            let address = args.get(1).map_or(DEFAULT_ADDRESS, |arg| arg.as_str());
+            run_http_interface(&load_network(&options), address);
+        },
+        Some(_) => println!("{}", USAGE),
     }
 }
@@ -1,31 +1,83 @@
+use crate::error::{Error, Result};
+use crate::query::Engine;
 use crate::gtfs::*;
 use crate::geo_utils::*;
 use crate::str_utils::*;
 
 use core::cmp::Ordering;
 use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
 use std::path::Path;
-use std::hash::Hash;
-use std::borrow::Borrow;
-
-use std::rc::Rc;
+use std::io::{Read, Write};
+use std::sync::{Mutex, OnceLock};
 
+use chrono::Duration;
+use chrono::LocalResult;
+use chrono::NaiveDate;
 use chrono::NaiveDateTime;
+use chrono::TimeZone;
 use chrono::Weekday;
 use chrono::Datelike;
-use chrono::Timelike;
+use chrono_tz::Tz;
+use geo_types::{MultiPolygon, Point};
+use serde::{Deserialize, Serialize};
+
+#[cfg(test)]
+use crate::test_feed::*;
+
+mod csa;
+mod raptor;
 
 const MAX_PEDESTRIAN_DIST: f32 = 500.0;
 const PEDESTRIAN_SPEED: f32 = 1.0;
+/// Wheelchair users move between stops slower than the other pedestrians
+const WHEELCHAIR_SPEED: f32 = 0.6;
+/// Cyclists ride to the stops up to this far, the radius of a bicycle profile cannot exceed it
+pub const MAX_CYCLING_DIST: f32 = 3000.0;
+/// Metres per second ridden by the bicycle profile of the interfaces
+pub const DEFAULT_CYCLING_SPEED: f32 = 4.0;
 pub static MINIMAL_TRANSFER_TIME: u32 = 60;
+/// The longest stay at a via stop, the searches only span a few service days
+pub const MAX_VIA_DWELL_MINUTES: u32 = 24 * 60;
+/// Multi-criteria searches do not look for alternatives arriving later than this after the fastest one
+const PARETO_MAX_DELAY: i64 = 3600;
+/// Multi-criteria searches do not consider connections using more vehicles than this
+const PARETO_MAX_BOARDINGS: u32 = 6;
+/// Number of service days a single search spans, starting with the day before the query date.
+/// The previous day is needed for trips running past midnight, the next one for late queries.
+const SEARCH_DAYS: usize = 3;
+/// Used when the feed does not specify a valid agency timezone
+const DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Prague;
+/// Size of the grid squares isochrones are drawn with, in metres
+const ISOCHRONE_CELL_SIZE: f32 = 50.0;
+
+/// Index of a stop in the table of stops of the network, see `Network::get_stop_by_index`
+pub type StopIndex = u32;
+/// Index of a trip in the table of trips of the network, see `Network::get_trip_by_index`
+pub type TripIndex = u32;
+/// Index of a service in the table of services of the network, see `Network::get_service_by_index`
+pub type ServiceIndex = u32;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Location {
-    Stop(Rc<Stop>), // Tohle je blbě, je to kvůli tomu zbytečně veliké
-    Trip(Rc<Trip>, Rc<Service>),
+    /// waiting at a stop
+    Stop(StopIndex),
+    /// riding a trip running on the service, at the stop where the vehicle is,
+    /// which is the stop time at the position in the trip
+    Trip(TripIndex, ServiceIndex, StopIndex, u32),
+}
+
+impl Location {
+    /// Returns the stop the passenger waits at or the vehicle is at
+    pub fn get_stop(&self) -> StopIndex {
+        match self {
+            Location::Stop(stop) => *stop,
+            Location::Trip(_, _, stop, _) => *stop,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     pub location: Location,
     pub node_id: usize,
@@ -52,7 +104,7 @@ impl Node {
     }
 
     pub fn add_edge(&mut self, node: usize) {
-        &self.edges.push(node);
+        self.edges.push(node);
     }
 
     pub fn get_location(&self) -> &Location {
@@ -61,7 +113,283 @@ impl Node {
 }
 
 pub struct Connection {
-    pub nodes: Vec<Node>,
+    /// the locations of the nodes the connection passes through
+    pub locations: Vec<Location>,
+    /// local time at which the connection passes through the location with the same index
+    pub times: Vec<NaiveDateTime>,
+    pub transfers: u32,
+    /// distance walked between stops in metres, or ridden by the bicycle profile
+    pub walking_distance: f32,
+    /// the ranges of the locations before, between and after the via stops, a single one without them
+    pub segments: Vec<Range<usize>>,
+}
+
+impl Connection {
+    /// Returns when the passenger got to the stop of the location before @index. After a ride it is
+    /// the arrival of the vehicle, not the time at the stop, which includes the transfer time.
+    pub fn get_arrival_before(&self, index: usize) -> NaiveDateTime {
+        match index.checked_sub(2).map(|ride_index| (self.locations[ride_index], self.times[ride_index])) {
+            Some((Location::Trip(..), vehicle_arrival)) => vehicle_arrival,
+            _ => self.times[index - 1],
+        }
+    }
+}
+
+/// The earliest arrival at a stop found by a one-to-all search
+#[derive(Debug)]
+pub struct StopArrival<'a> {
+    pub stop: &'a Stop,
+    pub arrival: NaiveDateTime,
+    /// seconds from the departure time
+    pub travel_time: i64,
+}
+
+/// A trip leaving a stop, as listed on a departure board
+#[derive(Debug)]
+pub struct Departure<'a> {
+    pub stop: &'a Stop,
+    pub trip: &'a Trip,
+    pub departure: NaiveDateTime,
+}
+
+/// How the passenger gets around between the vehicles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mobility {
+    Walking,
+    /// only boards accessible trips at accessible stops and moves between accessible stops at WHEELCHAIR_SPEED
+    Wheelchair,
+    /// only boards trips taking bikes and rides at @speed in metres per second to the stops within @radius metres
+    Bicycle { speed: f32, radius: f32 },
+}
+
+/// Which routes the searches may take, by their route_type, route_short_name and agency_id.
+/// An empty list of the included values includes all of them, the excluded values are left out either way.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteFilter {
+    pub include_route_types: Vec<u32>,
+    pub exclude_route_types: Vec<u32>,
+    pub include_lines: Vec<String>,
+    pub exclude_lines: Vec<String>,
+    pub include_agencies: Vec<String>,
+    pub exclude_agencies: Vec<String>,
+}
+
+impl RouteFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == RouteFilter::default()
+    }
+
+    pub fn allows(&self, route: &Route) -> bool {
+        fn passes<T: PartialEq>(value: &T, include: &Vec<T>, exclude: &Vec<T>) -> bool {
+            (include.is_empty() || include.contains(value)) && !exclude.contains(value)
+        }
+        passes(&route.route_type, &self.include_route_types, &self.exclude_route_types)
+            && passes(&route.route_short_name, &self.include_lines, &self.exclude_lines)
+            && passes(&route.agency_id, &self.include_agencies, &self.exclude_agencies)
+    }
+}
+
+/// What the connection searches may use, the default profile takes any trip and walks at the usual pace
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchProfile {
+    pub mobility: Mobility,
+    pub routes: RouteFilter,
+}
+
+impl SearchProfile {
+    pub fn wheelchair() -> SearchProfile {
+        SearchProfile { mobility: Mobility::Wheelchair, routes: RouteFilter::default() }
+    }
+
+    /// The radius is limited by MAX_CYCLING_DIST
+    pub fn bicycle(speed: f32, radius: f32) -> SearchProfile {
+        let mobility = Mobility::Bicycle { speed: speed, radius: radius.min(MAX_CYCLING_DIST) };
+        SearchProfile { mobility: mobility, routes: RouteFilter::default() }
+    }
+
+    /// Only takes the trips of the routes @routes allows
+    pub fn routes(mut self, routes: RouteFilter) -> SearchProfile {
+        self.routes = routes;
+        self
+    }
+
+    /// Whether the profile moves between stops at another pace than the pedestrian edges of the network assume
+    fn has_own_walks(&self) -> bool {
+        self.mobility != Mobility::Walking
+    }
+
+    /// Converts the walking time between two stops to the time the profile needs
+    fn get_travel_time(&self, walking_time: u32) -> u32 {
+        match self.mobility {
+            Mobility::Walking => walking_time,
+            Mobility::Wheelchair => (walking_time as f32 * PEDESTRIAN_SPEED / WHEELCHAIR_SPEED) as u32,
+            Mobility::Bicycle { speed, .. } => (walking_time as f32 * PEDESTRIAN_SPEED / speed) as u32,
+        }
+    }
+}
+
+impl Default for SearchProfile {
+    fn default() -> SearchProfile {
+        SearchProfile { mobility: Mobility::Walking, routes: RouteFilter::default() }
+    }
+}
+
+/// A label of the multi-criteria search. Unlike the plain search, a state can hold several labels
+/// as long as none of them has both fewer boardings and less walking than another.
+#[derive(Debug)]
+struct ParetoLabel {
+    state: usize,
+    time: i64,
+    boardings: u32,
+    walking_distance: f32,
+    prev: Option<usize>,
+    // the label where the passenger last got off a vehicle, see SearchState::pending_transfer
+    pending_transfer: Option<usize>,
+    dominated: bool,
+}
+
+impl ParetoLabel {
+    fn is_at_least_as_good(&self, other: &ParetoLabel) -> bool {
+        self.time <= other.time
+            && self.boardings <= other.boardings
+            && self.walking_distance <= other.walking_distance
+    }
+
+    /// A label restricted by a transfer rule cannot replace a label without the restriction
+    fn dominates(&self, other: &ParetoLabel) -> bool {
+        self.is_at_least_as_good(other)
+            && (self.pending_transfer.is_none() || self.pending_transfer == other.pending_transfer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SearchDirection {
+    Forward,
+    Backward,
+}
+
+impl SearchDirection {
+    fn unreached(&self) -> i64 {
+        match self {
+            SearchDirection::Forward => i64::MAX,
+            SearchDirection::Backward => i64::MIN,
+        }
+    }
+
+    /// Converts a time to the heap key and back. Backward searches pop the latest times first.
+    fn key(&self, time: i64) -> i64 {
+        match self {
+            SearchDirection::Forward => time,
+            SearchDirection::Backward => -time,
+        }
+    }
+
+    fn is_better(&self, time: i64, other: i64) -> bool {
+        match self {
+            SearchDirection::Forward => time < other,
+            SearchDirection::Backward => time > other,
+        }
+    }
+}
+
+/// The service days covered by a search, see SEARCH_DAYS
+struct SearchDays {
+    dates: Vec<NaiveDate>,
+    // unix timestamps of the starts of the service days
+    starts: Vec<i64>,
+}
+
+/// The labels of the states reached by a search. Since the time of every state is fixed, a state
+/// is never reached again by a later search sharing the labels, which prunes the later searches.
+/// The vectors span all the states, so the network keeps the states of finished queries and resets
+/// only the labels they touched, see `Network::take_search_state`.
+#[derive(Debug)]
+struct SearchState {
+    direction: SearchDirection,
+    times: Vec<i64>,
+    came_from: Vec<Option<usize>>,
+    // the stop state where the passenger last got off a vehicle (or boards the next one when searching
+    // backward), only kept if there are transfer rules depending on that trip. A state labelled with
+    // such a stop can be reached again at the same time without it, as the restriction might
+    // have prevented a transfer there.
+    pending_transfer: Vec<Option<usize>>,
+    // the best times at which the search moved on to the next service day at a stop (or to the previous
+    // one when searching backward), moving on later than that is always dominated
+    rolled_over: HashMap<(StopIndex, usize), i64>,
+    // the states with a label, in the order they were first reached
+    reached: Vec<usize>,
+}
+
+impl SearchState {
+    fn new(state_count: usize, direction: SearchDirection) -> SearchState {
+        SearchState {
+            direction: direction,
+            times: vec![direction.unreached(); state_count],
+            came_from: vec![None; state_count],
+            pending_transfer: vec![None; state_count],
+            rolled_over: HashMap::new(),
+            reached: Vec::new(),
+        }
+    }
+
+    /// Clears the labels for a new search in @direction
+    fn reset(&mut self, direction: SearchDirection) {
+        if direction == self.direction {
+            for state in self.reached.drain(..) {
+                self.times[state] = direction.unreached();
+                self.came_from[state] = None;
+                self.pending_transfer[state] = None;
+            }
+        } else {
+            self.times.fill(direction.unreached());
+            self.came_from.fill(None);
+            self.pending_transfer.fill(None);
+            self.reached.clear();
+        }
+        self.rolled_over.clear();
+        self.direction = direction;
+    }
+
+    fn set_label(&mut self, state: usize, time: i64, came_from: Option<usize>, pending_transfer: Option<usize>) {
+        if self.times[state] == self.direction.unreached() {
+            self.reached.push(state);
+        }
+        self.times[state] = time;
+        self.came_from[state] = came_from;
+        self.pending_transfer[state] = pending_transfer;
+    }
+
+    /// Checks whether the search has already moved on to another day at the stop at a time
+    /// at least as good as @time and records the move otherwise
+    fn roll_over(&mut self, stop: StopIndex, day: usize, time: i64, direction: SearchDirection) -> bool {
+        match self.rolled_over.get(&(stop, day)) {
+            Some(rolled_time) if !direction.is_better(time, *rolled_time) => false,
+            _ => {
+                self.rolled_over.insert((stop, day), time);
+                true
+            },
+        }
+    }
+}
+
+/// An entry of the Dijkstra heap. The search state identifies a node on one of the searched
+/// service days, see `Network::get_state`. Ordered so that the lowest time is popped first.
+#[derive(Debug, PartialEq, Eq)]
+struct SearchEntry {
+    time: i64,
+    state: usize,
+}
+
+impl Ord for SearchEntry {
+    fn cmp(&self, other: &SearchEntry) -> Ordering {
+        other.time.cmp(&self.time).then_with(|| other.state.cmp(&self.state))
+    }
+}
+
+impl PartialOrd for SearchEntry {
+    fn partial_cmp(&self, other: &SearchEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl Eq for Node {}
@@ -84,10 +412,10 @@ impl PartialOrd for Node {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct StopGroup {
     pub names: Vec<String>,
-    pub stops: HashSet<String>,
+    pub stops: HashSet<StopIndex>,
 }
 
 fn does_trip_operate(day: &Weekday, service: &Service) -> bool {
@@ -102,15 +430,217 @@ fn does_trip_operate(day: &Weekday, service: &Service) -> bool {
     }
 }
 
+/// Decides whether a service runs on the given service day. Exceptions from calendar_dates.txt
+/// take precedence, otherwise the weekday flags apply within the validity range of the service.
+pub fn does_service_operate(date: &NaiveDate, service: &Service) -> bool {
+    for exception in &service.exceptions {
+        if exception.date == *date {
+            match exception.exception_type {
+                1 => return true,
+                2 => return false,
+                _ => (),
+            }
+        }
+    }
+    if *date < service.start_date || *date > service.end_date {
+        return false;
+    }
+    does_trip_operate(&date.weekday(), service)
+}
+
+#[cfg(test)]
+fn saturday_service() -> Service {
+    Service {
+        service_id: String::from("0000010-1"),
+        monday: false,
+        tuesday: false,
+        wednesday: false,
+        thursday: false,
+        friday: false,
+        saturday: true,
+        sunday: false,
+        start_date: NaiveDate::from_ymd_opt(2020, 1, 25).unwrap(),
+        end_date: NaiveDate::from_ymd_opt(2020, 2, 7).unwrap(),
+        exceptions: Vec::new(),
+    }
+}
+
+#[test]
+fn test_service_date_range() {
+    let service = saturday_service();
+    assert!(does_service_operate(&NaiveDate::from_ymd_opt(2020, 2, 1).unwrap(), &service));
+    assert!(!does_service_operate(&NaiveDate::from_ymd_opt(2020, 2, 2).unwrap(), &service));
+    assert!(!does_service_operate(&NaiveDate::from_ymd_opt(2020, 1, 18).unwrap(), &service));
+    assert!(!does_service_operate(&NaiveDate::from_ymd_opt(2020, 2, 8).unwrap(), &service));
+}
+
+#[test]
+fn test_service_exceptions() {
+    let mut service = saturday_service();
+    service.exceptions.push(ServiceException {
+        service_id: service.service_id.clone(),
+        date: NaiveDate::from_ymd_opt(2020, 2, 1).unwrap(),
+        exception_type: 2,
+    });
+    service.exceptions.push(ServiceException {
+        service_id: service.service_id.clone(),
+        date: NaiveDate::from_ymd_opt(2020, 2, 3).unwrap(),
+        exception_type: 1,
+    });
+    assert!(!does_service_operate(&NaiveDate::from_ymd_opt(2020, 2, 1).unwrap(), &service));
+    assert!(does_service_operate(&NaiveDate::from_ymd_opt(2020, 2, 3).unwrap(), &service));
+    assert!(does_service_operate(&NaiveDate::from_ymd_opt(2020, 1, 25).unwrap(), &service));
+}
+
+/// Transfer rules from transfers.txt indexed by the pair of stops they connect
+#[derive(Debug, Serialize, Deserialize)]
+struct TransferRules {
+    by_stops: HashMap<(String, String), Vec<Transfer>>,
+    // rules that can only be checked when the search knows the trip it got off, by the stop they start at,
+    // and the same rules by the stop they end at for backward searches
+    restrictions: HashMap<String, Vec<Transfer>>,
+    restrictions_to: HashMap<String, Vec<Transfer>>,
+}
+
+impl TransferRules {
+    fn new(transfers: Vec<Transfer>) -> TransferRules {
+        let mut by_stops: HashMap<(String, String), Vec<Transfer>> = HashMap::new();
+        let mut restrictions: HashMap<String, Vec<Transfer>> = HashMap::new();
+        let mut restrictions_to: HashMap<String, Vec<Transfer>> = HashMap::new();
+        for transfer in transfers {
+            let stop_level = TransferRules::is_stop_level(&transfer);
+            let same_stop = transfer.from_stop_id == transfer.to_stop_id;
+            if (transfer.transfer_type == 3 && (!stop_level || same_stop)) || (transfer.transfer_type == 2 && !stop_level) {
+                restrictions.entry(transfer.from_stop_id.clone())
+                    .or_insert_with(Vec::new)
+                    .push(transfer.clone());
+                restrictions_to.entry(transfer.to_stop_id.clone())
+                    .or_insert_with(Vec::new)
+                    .push(transfer.clone());
+            }
+            by_stops.entry((transfer.from_stop_id.clone(), transfer.to_stop_id.clone()))
+                .or_insert_with(Vec::new)
+                .push(transfer);
+        }
+        TransferRules { by_stops: by_stops, restrictions: restrictions, restrictions_to: restrictions_to }
+    }
+
+    fn is_stop_level(transfer: &Transfer) -> bool {
+        transfer.from_trip_id.is_none() && transfer.to_trip_id.is_none()
+            && transfer.from_route_id.is_none() && transfer.to_route_id.is_none()
+    }
+
+    /// Returns the rule between two stops that does not depend on trips or routes
+    fn get_stop_rule(&self, from_stop_id: &str, to_stop_id: &str) -> Option<&Transfer> {
+        self.by_stops.get(&(String::from(from_stop_id), String::from(to_stop_id)))?
+            .iter()
+            .find(|transfer| TransferRules::is_stop_level(transfer))
+    }
+
+    /// Returns the most specific rule for a transfer between two trips. Rules naming trips
+    /// take precedence over rules naming routes, which take precedence over stop-level rules.
+    /// The trips expanded from frequencies.txt are named by the id of their template.
+    fn get_rule(&self, from_stop_id: &String, to_stop_id: &String, from_trip: &Trip, to_trip: &Trip) -> Option<&Transfer> {
+        let matches = |rule: &Option<String>, id: &str| rule.as_ref().is_none_or(|rule_id| rule_id == id);
+        let specificity = |transfer: &Transfer| {
+            let trips = transfer.from_trip_id.is_some() as u8 + transfer.to_trip_id.is_some() as u8;
+            let routes = transfer.from_route_id.is_some() as u8 + transfer.to_route_id.is_some() as u8;
+            3 * trips + routes
+        };
+        self.by_stops.get(&(from_stop_id.clone(), to_stop_id.clone()))?
+            .iter()
+            .filter(|transfer| matches(&transfer.from_trip_id, from_trip.get_template_id())
+                && matches(&transfer.to_trip_id, to_trip.get_template_id())
+                && matches(&transfer.from_route_id, &from_trip.route_id)
+                && matches(&transfer.to_route_id, &to_trip.route_id))
+            .max_by_key(|transfer| specificity(transfer))
+    }
+
+    /// Checks whether getting off @trip at the stop can restrict the trips boarded afterwards,
+    /// or for backward searches, whether boarding @trip can restrict the trips it is reached from
+    fn is_restricted(&self, stop_id: &String, trip: &Trip, direction: SearchDirection) -> bool {
+        let rules = match direction {
+            SearchDirection::Forward => self.restrictions.get(stop_id),
+            SearchDirection::Backward => self.restrictions_to.get(stop_id),
+        };
+        match rules {
+            Some(rules) => rules.iter().any(|rule| {
+                let (trip_id, route_id) = match direction {
+                    SearchDirection::Forward => (&rule.from_trip_id, &rule.from_route_id),
+                    SearchDirection::Backward => (&rule.to_trip_id, &rule.to_route_id),
+                };
+                trip_id.as_ref().is_none_or(|trip_id| trip_id == trip.get_template_id())
+                    && route_id.as_ref().is_none_or(|route_id| route_id == &trip.route_id)
+            }),
+            None => false,
+        }
+    }
+
+    /// Returns the time needed to transfer at a single stop
+    fn get_min_transfer_time(&self, stop_id: &str) -> u32 {
+        match self.get_stop_rule(stop_id, stop_id) {
+            Some(Transfer { transfer_type: 1, .. }) => 0,
+            Some(Transfer { transfer_type: 2, min_transfer_time: Some(time), .. }) => *time,
+            _ => MINIMAL_TRANSFER_TIME,
+        }
+    }
+
+    /// Returns the trip-level rules that allow a transfer between two specific trips
+    fn get_timed_trip_transfers(&self) -> Vec<&Transfer> {
+        self.by_stops.values()
+            .flatten()
+            .filter(|transfer| transfer.from_trip_id.is_some() && transfer.to_trip_id.is_some())
+            .filter(|transfer| transfer.transfer_type == 1 || transfer.transfer_type == 2)
+            .collect()
+    }
+}
+
+/// The time-expanded graph searched by the Dijkstra algorithm
+#[derive(Debug)]
+struct Graph {
+    nodes: Vec<Node>,
+    // the departure and arrival nodes of every stop ordered by their time, indexed by the stop index
+    stop_node_chains: Vec<Vec<usize>>,
+    // the edges of the nodes in reverse, used by backward searches
+    reverse_edges: Vec<Vec<usize>>,
+}
+
 #[derive(Debug)]
 pub struct Network {
-    stops: HashMap<String, Rc<Stop>>,
+    timezone: Tz,
+    // the stops, trips and services are kept in tables sorted by their ids, the nodes refer to them by their index
+    stops: Vec<Stop>,
+    stop_indices: HashMap<String, StopIndex>,
     routes: HashMap<String, Route>,
-    trips: HashMap<String, Rc<Trip>>,
-    services: HashMap<String, Rc<Service>>,
-    stop_node_chains: HashMap<String, Vec<usize>>,
+    trips: Vec<Trip>,
+    trip_indices: HashMap<String, TripIndex>,
+    services: Vec<Service>,
+    // the trip the vehicle continues as after every trip, the passengers can stay seated
+    block_successors: Vec<Option<TripIndex>>,
+    // the pedestrian tables below are indexed by the stop index, with travel times in seconds between neighbouring stops
+    pedestrian_connections: Vec<Vec<(StopIndex, u32)>>,
+    stop_coords: Vec<Point<f32>>,
+    incoming_pedestrian_connections: Vec<Vec<(StopIndex, u32)>>,
+    // distances in metres to the stops within MAX_CYCLING_DIST, they are the same both ways,
+    // created by the first search with a bicycle
+    cycling_connections: OnceLock<Vec<Vec<(StopIndex, f32)>>>,
+    // the pairs of different stops with a stop-level rule from transfers.txt, created with the cycling connections
+    stop_rule_pairs: OnceLock<HashSet<(StopIndex, StopIndex)>>,
+    transfer_rules: TransferRules,
     stop_groups: HashMap<String, StopGroup>,
-    nodes: Vec<Node>,
+    // the structures of the engines are created by their first search, so that a network
+    // searched by one engine does not hold the others
+    graph: OnceLock<Graph>,
+    // the labels of finished searches, reused by the next ones
+    search_states: Mutex<Vec<SearchState>>,
+    // the elementary connections for find_connection_by_scan
+    timetable: OnceLock<csa::Timetable>,
+    // the trips grouped by their stops for find_connection_by_raptor
+    raptor_routes: OnceLock<raptor::RaptorRoutes>,
+    // the service of every trip, looked up by the scans
+    trip_services: Vec<ServiceIndex>,
+    // the time needed to transfer at every stop, the same the arrival nodes add to the arrival
+    transfer_times: Vec<u32>,
 }
 
 impl Network {
@@ -119,19 +649,104 @@ impl Network {
         println!("Number of routes: {}", self.routes.len());
         println!("Number of trips: {}", self.trips.len());
         println!("Number of services: {}", self.services.len());
-        println!("Number of nodes: {}", self.nodes.len());
+        if let Some(graph) = self.graph.get() {
+            println!("Number of nodes: {}", graph.nodes.len());
+        }
     }
 
     pub fn get_node(&self, id: usize) -> &Node {
-        &self.nodes[id]
+        &self.graph().nodes[id]
+    }
+
+    /// Creates the structures searched by @engine, which is otherwise left to its first search
+    pub fn prepare(&self, engine: Engine) {
+        match engine {
+            Engine::Graph => { self.graph(); },
+            Engine::ConnectionScan => { self.timetable(); },
+            Engine::Raptor => { self.raptor_routes(); },
+        }
+    }
+
+    /// Returns the time-expanded graph, it is created by the first call
+    fn graph(&self) -> &Graph {
+        self.graph.get_or_init(|| {
+            let mut nodes = Vec::new();
+            let trip_node_chains = Network::create_transport_nodes(&mut nodes, &self.trips, &self.stop_indices, &self.get_service_indices(), &self.transfer_rules);
+            Network::add_trip_transfers(&mut nodes, &self.trips, &Network::index_templates(&self.trips), &trip_node_chains, &self.transfer_rules);
+            Network::add_block_transfers(&mut nodes, &self.block_successors, &trip_node_chains);
+            let stop_node_chains = Network::create_node_chains(&mut nodes, self.stops.len());
+            Network::add_pedestrian_connections(&mut nodes, &self.pedestrian_connections, &stop_node_chains);
+            Network::create_graph(nodes, stop_node_chains)
+        })
+    }
+
+    fn create_graph(nodes: Vec<Node>, stop_node_chains: Vec<Vec<usize>>) -> Graph {
+        Graph {
+            reverse_edges: Network::create_reverse_edges(&nodes),
+            nodes: nodes,
+            stop_node_chains: stop_node_chains,
+        }
+    }
+
+    /// Returns the elementary connections of the connection scan, they are created by the first call
+    fn timetable(&self) -> &csa::Timetable {
+        self.timetable.get_or_init(|| {
+            csa::Timetable::new(&self.stop_indices, &self.trips, &Network::index_templates(&self.trips), &self.transfer_rules, &self.block_successors)
+        })
+    }
+
+    /// Returns the trips grouped by their stops for RAPTOR, they are created by the first call
+    fn raptor_routes(&self) -> &raptor::RaptorRoutes {
+        self.raptor_routes.get_or_init(|| raptor::RaptorRoutes::new(&self.stop_indices, &self.trips))
+    }
+
+    fn get_service_indices(&self) -> HashMap<String, ServiceIndex> {
+        self.services.iter()
+            .enumerate()
+            .map(|(index, service)| (service.service_id.clone(), index as ServiceIndex))
+            .collect()
+    }
+
+    fn get_trip_services(trips: &[Trip], services: &[Service]) -> Vec<ServiceIndex> {
+        let service_indices: HashMap<&String, ServiceIndex> = services.iter()
+            .enumerate()
+            .map(|(index, service)| (&service.service_id, index as ServiceIndex))
+            .collect();
+        trips.iter().map(|trip| service_indices[&trip.service_id]).collect()
+    }
+
+    fn get_transfer_times(stops: &[Stop], transfer_rules: &TransferRules) -> Vec<u32> {
+        stops.iter().map(|stop| transfer_rules.get_min_transfer_time(&stop.stop_id)).collect()
+    }
+
+    pub fn get_stop(&self, id: &str) -> Option<&Stop> {
+        self.stop_indices.get(id).map(|index| &self.stops[*index as usize])
     }
 
-    pub fn get_stop(&self, id: &String) -> Option<&Rc<Stop>> {
-        self.stops.get(id)
+    pub fn get_trip(&self, id: &String) -> Option<&Trip> {
+        self.trip_indices.get(id).map(|index| &self.trips[*index as usize])
     }
 
-    pub fn get_trip(&self, id: &String) -> Option<&Rc<Trip>> {
-        self.trips.get(id)
+    pub fn get_stop_by_index(&self, index: StopIndex) -> &Stop {
+        &self.stops[index as usize]
+    }
+
+    pub fn get_trip_by_index(&self, index: TripIndex) -> &Trip {
+        &self.trips[index as usize]
+    }
+
+    pub fn get_service_by_index(&self, index: ServiceIndex) -> &Service {
+        &self.services[index as usize]
+    }
+
+    /// Returns the next trip of the block of @trip, which the passengers of @trip continue in without getting off
+    pub fn get_block_successor(&self, trip: TripIndex) -> Option<TripIndex> {
+        self.block_successors[trip as usize]
+    }
+
+    /// Returns the stop time at the position in the trip, see Location::Trip
+    pub fn get_stop_time(&self, trip: TripIndex, position: u32) -> &StopTime {
+        &self.trips[trip as usize].stop_times[position as usize]
     }
 
     /// Creates a node, adds it to the node vector, returns the id
@@ -142,78 +757,180 @@ impl Network {
         return node_id;
     }
 
-    fn create_transport_nodes(nodes: &mut Vec<Node>, trips: &HashMap<String, Rc<Trip>>, stops: &HashMap<String, Rc<Stop>>, services: &HashMap<String, Rc<Service>>) {
-        // creates transport nodes and the corresponding arrival and departure ones.
+    /// Creates transport nodes and the corresponding arrival and departure ones.
+    /// Returns the transport nodes of every trip in the order of its stop times, indexed by the trip index.
+    fn create_transport_nodes(nodes: &mut Vec<Node>, trips: &Vec<Trip>, stop_indices: &HashMap<String, StopIndex>, service_indices: &HashMap<String, ServiceIndex>, transfer_rules: &TransferRules) -> Vec<Vec<usize>> {
         // FIXME extract to a function outside.
-
-        for trip in trips.values() {
+        let mut trip_node_chains = Vec::with_capacity(trips.len());
+        for (trip_index, trip) in trips.iter().enumerate() {
             let mut prev_transport: Option<usize> = None;
+            let mut trip_nodes = Vec::new();
+            // the references were checked when loading the feed
+            let service = service_indices[&trip.service_id];
             for j in 0..trip.stop_times.len() {
                 let stop_time = &trip.stop_times[j];
-                let stop = stops.get(&stop_time.stop_id).unwrap();
+                let stop = stop_indices[&*stop_time.stop_id];
 
-                let service_ptr = services.get(&trip.service_id).unwrap();
-                let transport: usize = Network::create_node(nodes, Location::Trip(trip.clone(), service_ptr.clone()), stop_time.departure_time);
+                let transport: usize = Network::create_node(nodes, Location::Trip(trip_index as TripIndex, service, stop, j as u32), stop_time.departure_time);
                 // add edge from previous transport node
-                match prev_transport {
-                    Some(id) => nodes[id].add_edge(transport),
-                    None => (),
+                if let Some(id) = prev_transport {
+                    nodes[id].add_edge(transport);
+                }
+                let dep = Network::create_node(nodes, Location::Stop(stop), stop_time.departure_time - trip.get_expected_wait());
+                let transfer_time = transfer_rules.get_min_transfer_time(&stop_time.stop_id);
+                let arr = Network::create_node(nodes, Location::Stop(stop), stop_time.arrival_time + transfer_time);
+                // the vehicle can pass the stop without letting the passengers board or get off
+                if stop_time.can_get_off() {
+                    nodes[transport].add_edge(arr);
+                }
+                // there is no point in boarding a trip at its last stop
+                if j + 1 < trip.stop_times.len() && stop_time.can_board() {
+                    nodes[dep].add_edge(transport);
                 }
-                let dep = Network::create_node(nodes, Location::Stop(stop.clone()), stop_time.departure_time);
-                let arr = Network::create_node(nodes, Location::Stop(stop.clone()), stop_time.arrival_time + MINIMAL_TRANSFER_TIME);
-                nodes[transport].add_edge(arr);
-                nodes[dep].add_edge(transport);
                 prev_transport = Some(transport);
+                trip_nodes.push(transport);
             }
+            trip_node_chains.push(trip_nodes);
         }
+        trip_node_chains
     }
 
-    fn sort_node_ids_by_time(nodes: &Vec<Node>, ids: &mut Vec<usize>) -> Vec<usize> {
-        ids.sort_by(|a, b| nodes[*a].get_time().cmp(&nodes[*b].get_time()));
-        ids.clone()
+    /// Indexes the trips by their id in trips.txt, which the trips expanded from frequencies.txt share, see Trip::get_template_id
+    fn index_templates(trips: &Vec<Trip>) -> HashMap<&str, Vec<TripIndex>> {
+        let mut result: HashMap<&str, Vec<TripIndex>> = HashMap::new();
+        for (trip_index, trip) in trips.iter().enumerate() {
+            result.entry(trip.get_template_id()).or_insert_with(Vec::new).push(trip_index as TripIndex);
+        }
+        result
     }
 
-    /// Adds the departure transfer chain, locks the departure nodes
-    fn add_node_chaining(nodes: &mut Vec<Node>, node_ids_by_stop: &mut HashMap<String, Vec<usize>>) {
-        let sorted: Vec<Vec<usize>> = node_ids_by_stop.values_mut()
-            .map(|ids_by_stop| Network::sort_node_ids_by_time(nodes, ids_by_stop))
-            .collect();
-        for sorted_ids_at_stop in sorted {
-            for index in 0..(sorted_ids_at_stop.len() - 1) {
-                let departure = sorted_ids_at_stop[index];
-                let arrival = sorted_ids_at_stop[index + 1];
-                nodes[departure].add_edge(arrival);
+    /// Adds direct edges between transport nodes for timed and minimum-time transfers between two trips,
+    /// these transfers do not need to respect the minimal transfer time at the stop. A rule naming a trip
+    /// expanded from frequencies.txt connects all of its instances.
+    fn add_trip_transfers(nodes: &mut Vec<Node>, trips: &Vec<Trip>, template_indices: &HashMap<&str, Vec<TripIndex>>, trip_node_chains: &Vec<Vec<usize>>, transfer_rules: &TransferRules) {
+        for transfer in transfer_rules.get_timed_trip_transfers() {
+            let from_trip_id = transfer.from_trip_id.as_ref().unwrap();
+            let to_trip_id = transfer.to_trip_id.as_ref().unwrap();
+            let find_transports = |trip_id: &String, stop_id: &String, allowed: fn(&StopTime) -> bool| -> Vec<usize> {
+                template_indices.get(trip_id.as_str()).into_iter().flatten()
+                    .filter_map(|trip_index| {
+                        let trip_index = *trip_index as usize;
+                        let index = trips[trip_index].stop_times.iter().position(|stop_time| *stop_time.stop_id == **stop_id)?;
+                        allowed(&trips[trip_index].stop_times[index]).then(|| trip_node_chains[trip_index][index])
+                    })
+                    .collect()
+            };
+            let transfer_time = if transfer.transfer_type == 2 { transfer.min_transfer_time.unwrap_or(0) } else { 0 };
+            let to_transports = find_transports(to_trip_id, &transfer.to_stop_id, StopTime::can_board);
+            for from in find_transports(from_trip_id, &transfer.from_stop_id, StopTime::can_get_off) {
+                for to in to_transports.iter() {
+                    if nodes[from].get_time() + transfer_time <= nodes[*to].get_time() {
+                        nodes[from].add_edge(*to);
+                    }
+                }
             }
         }
     }
 
-    fn create_node_chains(nodes: &mut Vec<Node>) -> HashMap<String, Vec<usize>> {
-        let mut nodes_by_stops: HashMap<String, Vec<usize>> = HashMap::new();
-        
-        for (index, node) in nodes.iter().enumerate() {
-            match &node.location {
-                Location::Stop(stop) => {
-                    match nodes_by_stops.get_mut(&stop.stop_id) {
-                        Some(vector) => vector.push(index),
-                        None => {
-                            nodes_by_stops.insert(stop.stop_id.clone(), vec![index]);
-                        },
-                    };  
-                },
-                Location::Trip(_, _) => (),
+    /// Finds the trip every trip continues as: the next trip of its block with the same service, if it starts
+    /// at the stop where the trip ends and not before it ends
+    fn create_block_successors(trips: &Vec<Trip>) -> Vec<Option<TripIndex>> {
+        let mut blocks: HashMap<(&String, &String), Vec<TripIndex>> = HashMap::new();
+        for (trip_index, trip) in trips.iter().enumerate() {
+            if let (Some(block_id), false) = (&trip.block_id, trip.stop_times.is_empty()) {
+                blocks.entry((block_id, &trip.service_id)).or_insert_with(Vec::new).push(trip_index as TripIndex);
+            }
+        }
+        let mut successors = vec![None; trips.len()];
+        for block in blocks.values_mut() {
+            block.sort_by_key(|trip| trips[*trip as usize].stop_times[0].departure_time);
+            for pair in block.windows(2) {
+                let last = trips[pair[0] as usize].stop_times.last().unwrap();
+                let first = &trips[pair[1] as usize].stop_times[0];
+                if last.stop_id == first.stop_id && last.departure_time <= first.departure_time {
+                    successors[pair[0] as usize] = Some(pair[1]);
+                }
+            }
+        }
+        successors
+    }
+
+    /// Adds edges from the last transport node of every trip to the first one of the trip it continues as,
+    /// staying seated needs no transfer time and is possible even where the passengers cannot board or get off
+    fn add_block_transfers(nodes: &mut Vec<Node>, block_successors: &Vec<Option<TripIndex>>, trip_node_chains: &Vec<Vec<usize>>) {
+        for (trip_index, successor) in block_successors.iter().enumerate() {
+            if let Some(successor) = successor {
+                let (from, to) = (trip_node_chains[trip_index].last(), trip_node_chains[*successor as usize].first());
+                if let (Some(from), Some(to)) = (from, to) {
+                    nodes[*from].add_edge(*to);
+                }
+            }
+        }
+    }
+
+    /// Turns the walking distances between stops into transfer times. Stop-level rules from transfers.txt
+    /// replace the walking times, transfers that are not possible are left out.
+    fn create_stop_transfers(
+        distances: &HashMap<String, Vec<(String, f32)>>,
+        coords: &HashMap<String, Point<f32>>,
+        transfer_rules: &TransferRules,
+    ) -> HashMap<String, Vec<(String, u32)>> {
+        let walking_time = |from: &String, to: &String| {
+            let (from, to) = (coords.get(from).unwrap(), coords.get(to).unwrap());
+            (((from.x() - to.x()).abs() + (from.y() - to.y()).abs()) / PEDESTRIAN_SPEED) as u32
+        };
+        let mut result: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+        for (stop_id, neighbours) in distances {
+            for (neighbouring_stop_id, dist) in neighbours {
+                if stop_id == neighbouring_stop_id || transfer_rules.get_stop_rule(stop_id, neighbouring_stop_id).is_none() {
+                    result.entry(stop_id.clone())
+                        .or_insert_with(Vec::new)
+                        .push((neighbouring_stop_id.clone(), (dist / PEDESTRIAN_SPEED) as u32));
+                }
+            }
+        }
+        for transfer in transfer_rules.by_stops.values().flatten() {
+            let (from, to) = (&transfer.from_stop_id, &transfer.to_stop_id);
+            if from == to || !TransferRules::is_stop_level(transfer) || !coords.contains_key(from) || !coords.contains_key(to) {
+                continue;
             }
+            let travel_time = match transfer.transfer_type {
+                0 => walking_time(from, to),
+                1 => 0,
+                2 => transfer.min_transfer_time.unwrap_or_else(|| walking_time(from, to)),
+                _ => continue,
+            };
+            result.entry(from.clone())
+                .or_insert_with(Vec::new)
+                .push((to.clone(), travel_time));
         }
-        Network::add_node_chaining(nodes, &mut nodes_by_stops);
-        nodes_by_stops
+        result
     }
 
-    /// returns the first departure from stop with id @stop_id after time @time
-    fn get_first_departure(&self, stop_id: &String, time: u32) -> Option<usize> {
-        let stop_node_chain_opt = self.stop_node_chains.get(stop_id);
-        match stop_node_chain_opt {
-            Some(stop_node_chain) => Network::bin_search(&self.nodes, time, stop_node_chain),
-            None => None,
+    /// Sorts the arrival and departure nodes at every stop by time and chains them with edges,
+    /// so that one can wait at the stop. Returns the chains indexed by the stop index.
+    fn create_node_chains(nodes: &mut Vec<Node>, stop_count: usize) -> Vec<Vec<usize>> {
+        let mut chains: Vec<Vec<usize>> = vec![Vec::new(); stop_count];
+        for (index, node) in nodes.iter().enumerate() {
+            if let Location::Stop(stop) = node.location {
+                chains[stop as usize].push(index);
+            }
         }
+        for chain in chains.iter_mut() {
+            chain.sort_by(|a, b| nodes[*a].get_time().cmp(&nodes[*b].get_time()));
+        }
+        for chain in &chains {
+            for pair in chain.windows(2) {
+                nodes[pair[0]].add_edge(pair[1]);
+            }
+        }
+        chains
+    }
+
+    /// returns the first departure from the stop after time @time
+    fn get_first_departure(&self, stop: StopIndex, time: u32) -> Option<usize> {
+        let graph = self.graph();
+        Network::bin_search(&graph.nodes, time, &graph.stop_node_chains[stop as usize])
     }
 
     fn bin_search(nodes: &Vec<Node>, time: u32, vector: &Vec<usize>) -> Option<usize> {
@@ -233,28 +950,97 @@ impl Network {
         best
     }
 
-    fn add_pedestrian_connections(nodes: &mut Vec<Node>, stops: &HashMap<String, Rc<Stop>>, stop_node_chains: &HashMap<String, Vec<usize>>) {
-        let coords = get_stop_coords_in_utm(stops);
-        let squares = calculate_proximity_squares(&coords, MAX_PEDESTRIAN_DIST);
-        let connections = get_pedestrian_connections(stops, &coords, &squares, MAX_PEDESTRIAN_DIST);
-        for (stop_id, connection_vector) in connections {
-            let empty_ary = vec![];
-            let stop_node_ids = stop_node_chains.get(&stop_id).unwrap_or(&empty_ary);
-            for (neighbouring_stop_id, dist) in connection_vector {
-                let neighbouring_nodes = stop_node_chains.get(&neighbouring_stop_id).unwrap_or(&empty_ary); 
-                let travel_time = (dist / PEDESTRIAN_SPEED) as u32;
-                for node_id in stop_node_ids {
+    fn add_pedestrian_connections(nodes: &mut Vec<Node>, connections: &Vec<Vec<(StopIndex, u32)>>, stop_node_chains: &Vec<Vec<usize>>) {
+        for (stop, connection_vector) in connections.iter().enumerate() {
+            for (neighbouring_stop, travel_time) in connection_vector {
+                let neighbouring_nodes = &stop_node_chains[*neighbouring_stop as usize];
+                for node_id in &stop_node_chains[stop] {
                     let node_time = nodes[*node_id].time;
                     let dest_node = Network::bin_search(nodes, node_time + travel_time, neighbouring_nodes);
-                    match dest_node {
-                        Some(id) => {
-                            nodes[*node_id].add_edge(id);
-                        },
-                        None => (),
-                    };
+                    if let Some(id) = dest_node {
+                        nodes[*node_id].add_edge(id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn create_reverse_edges(nodes: &Vec<Node>) -> Vec<Vec<usize>> {
+        let mut reverse_edges = vec![Vec::new(); nodes.len()];
+        for node in nodes {
+            for edge in node.get_edges() {
+                reverse_edges[*edge].push(node.node_id);
+            }
+        }
+        reverse_edges
+    }
+
+    /// Converts the pedestrian connections between stop ids to ones between stop indices
+    fn index_connections(connections: &HashMap<String, Vec<(String, u32)>>, stop_indices: &HashMap<String, StopIndex>) -> Vec<Vec<(StopIndex, u32)>> {
+        let mut result = vec![Vec::new(); stop_indices.len()];
+        for (stop_id, connection_vector) in connections {
+            result[stop_indices[stop_id] as usize] = connection_vector.iter()
+                .map(|(neighbouring_stop_id, travel_time)| (stop_indices[neighbouring_stop_id], *travel_time))
+                .collect();
+        }
+        result
+    }
+
+    /// Finds the stops within MAX_CYCLING_DIST of every stop, indexed by the stop index. The stops are divided
+    /// into squares the same way as in calculate_proximity_squares, only the neighbouring squares are searched.
+    fn create_cycling_connections(stop_coords: &Vec<Point<f32>>) -> Vec<Vec<(StopIndex, f32)>> {
+        let square_of = |coord: &Point<f32>| (
+            (coord.x() / MAX_CYCLING_DIST).floor() as i32,
+            (coord.y() / MAX_CYCLING_DIST).floor() as i32,
+        );
+        let mut squares: HashMap<(i32, i32), Vec<StopIndex>> = HashMap::new();
+        for (stop, coord) in stop_coords.iter().enumerate() {
+            squares.entry(square_of(coord)).or_insert_with(Vec::new).push(stop as StopIndex);
+        }
+        let mut result = vec![Vec::new(); stop_coords.len()];
+        for (stop, coord) in stop_coords.iter().enumerate() {
+            let (x, y) = square_of(coord);
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    for neighbouring_stop in squares.get(&(x + dx, y + dy)).into_iter().flatten() {
+                        let neighbouring_coord = stop_coords[*neighbouring_stop as usize];
+                        let distance = (coord.x() - neighbouring_coord.x()).abs() + (coord.y() - neighbouring_coord.y()).abs();
+                        if distance <= MAX_CYCLING_DIST {
+                            result[stop].push((*neighbouring_stop, distance));
+                        }
+                    }
                 }
             }
         }
+        result
+    }
+
+    /// Returns the cycling connections, they are created the first time they are needed
+    fn get_cycling_connections(&self) -> &Vec<Vec<(StopIndex, f32)>> {
+        self.cycling_connections.get_or_init(|| Network::create_cycling_connections(&self.stop_coords))
+    }
+
+    /// Checks whether a stop-level rule from transfers.txt is given for moving between two different stops
+    fn has_stop_rule(&self, from: StopIndex, to: StopIndex) -> bool {
+        let pairs = self.stop_rule_pairs.get_or_init(|| self.transfer_rules.by_stops.values()
+            .flatten()
+            .filter(|transfer| TransferRules::is_stop_level(transfer) && transfer.from_stop_id != transfer.to_stop_id)
+            .filter_map(|transfer| Some((
+                *self.stop_indices.get(&transfer.from_stop_id)?,
+                *self.stop_indices.get(&transfer.to_stop_id)?,
+            )))
+            .collect());
+        pairs.contains(&(from, to))
+    }
+
+    fn reverse_connections(connections: &Vec<Vec<(StopIndex, u32)>>) -> Vec<Vec<(StopIndex, u32)>> {
+        let mut result = vec![Vec::new(); connections.len()];
+        for (stop, connection_vector) in connections.iter().enumerate() {
+            for (neighbouring_stop, travel_time) in connection_vector {
+                result[*neighbouring_stop as usize].push((stop as StopIndex, *travel_time));
+            }
+        }
+        result
     }
 
     fn get_root_stop_id(stop_id: &String) -> String {
@@ -268,72 +1054,200 @@ impl Network {
         return result;
     }
 
-    fn create_stop_groups(stops: &HashMap<String, Rc<Stop>>) -> HashMap<String, StopGroup> {
+    fn create_stop_groups(stops: &Vec<Stop>) -> HashMap<String, StopGroup> {
         let mut result: HashMap<String, StopGroup> = HashMap::new();
-        for (stop_id, stop) in stops {
-            let root_id = Network::get_root_stop_id(stop_id);
+        for (index, stop) in stops.iter().enumerate() {
+            let root_id = Network::get_root_stop_id(&stop.stop_id);
             if let Some(stop_group) = result.get_mut(&root_id) {
                 stop_group.names.push(stop.stop_name.clone());
-                stop_group.stops.insert(stop_id.clone());
+                stop_group.stops.insert(index as StopIndex);
             } else {
                 let mut stops_in_group = HashSet::new();
-                stops_in_group.insert(stop_id.clone());
+                stops_in_group.insert(index as StopIndex);
                 result.insert(root_id, StopGroup {names: vec![stop.stop_name.clone()], stops: stops_in_group });
             }
         }
         result
     }
-    
-    fn get_as_rc<K: Eq + Hash, V>(raw: HashMap<K, V>) -> HashMap<K, Rc<V>> {
-        let mut result = HashMap::new();
-        for (k, v) in raw {
-            result.insert(k, Rc::new(v));
-        }
-        result
+
+    /// Puts the values into a table sorted by their ids and returns it with the index of every id
+    fn create_table<T>(raw: HashMap<String, T>) -> (Vec<T>, HashMap<String, u32>) {
+        let mut entries: Vec<(String, T)> = raw.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let indices = entries.iter()
+            .enumerate()
+            .map(|(index, (id, _))| (id.clone(), index as u32))
+            .collect();
+        (entries.into_iter().map(|(_, value)| value).collect(), indices)
+    }
+
+    /// The timezone of the feed is the timezone of its agencies, GTFS requires them to share one
+    fn get_feed_timezone(agencies: &HashMap<String, Agency>) -> Tz {
+        agencies.values()
+            .next()
+            .and_then(|agency| agency.agency_timezone.parse::<Tz>().ok())
+            .unwrap_or(DEFAULT_TIMEZONE)
     }
 
+    /// Loads the GTFS feed in the directory and builds the network from it, any invalid record is an error
     pub fn new(
         path: &Path
-    ) -> Network {
-        let stops = Network::get_as_rc(load_stops(path));
-        let routes = load_routes(path);
-        let mut raw_trips = load_trips(path);
-        load_stop_times(path, &mut raw_trips);
-        let trips = Network::get_as_rc(raw_trips);
-        let mut raw_services = load_services(path);
-        load_service_exceptions(path, &mut raw_services);
-        let services = Network::get_as_rc(raw_services);
-        let mut nodes = Vec::new();
+    ) -> Result<Network> {
+        Network::from_feed(&mut FeedReader::new(path))
+    }
+
+    /// Builds the network from the feed read by @feed. A lenient reader leaves out invalid records and keeps them as warnings.
+    pub fn from_feed(
+        feed: &mut FeedReader
+    ) -> Result<Network> {
+        let timezone = Network::get_feed_timezone(&load_agencies(feed)?);
+        let raw_stops = load_stops(feed)?;
+        let routes = load_routes(feed)?;
+        let mut raw_services = load_services(feed)?;
+        load_service_exceptions(feed, &mut raw_services)?;
+        let mut raw_trips = load_trips(feed, &routes, &raw_services)?;
+        load_stop_times(feed, &mut raw_trips, &raw_stops)?;
+        load_frequencies(feed, &mut raw_trips)?;
+        let (stops, stop_indices) = Network::create_table(raw_stops);
+        let (trips, trip_indices) = Network::create_table(raw_trips);
+        let (services, _) = Network::create_table(raw_services);
+        let transfer_rules = TransferRules::new(load_transfers(feed)?);
         let stop_groups = Network::create_stop_groups(&stops);
-        Network::create_transport_nodes(&mut nodes, &trips, &stops, &services);
-        let stop_node_chains = Network::create_node_chains(&mut nodes);
-        Network::add_pedestrian_connections(&mut nodes, &stops, &stop_node_chains);
+        let coords = get_stop_coords_in_utm(&stops);
+        let squares = calculate_proximity_squares(&coords, MAX_PEDESTRIAN_DIST);
+        let distances = get_pedestrian_connections(&coords, &squares, MAX_PEDESTRIAN_DIST);
+        let pedestrian_connections = Network::index_connections(&Network::create_stop_transfers(&distances, &coords, &transfer_rules), &stop_indices);
+        let incoming_pedestrian_connections = Network::reverse_connections(&pedestrian_connections);
+        let block_successors = Network::create_block_successors(&trips);
+        let trip_services = Network::get_trip_services(&trips, &services);
+        let transfer_times = Network::get_transfer_times(&stops, &transfer_rules);
 
-        let nw = Network {
+        Ok(Network {
+            timezone: timezone,
+            stop_coords: stops.iter().map(|stop| coords[&stop.stop_id]).collect(),
             stops: stops,
+            stop_indices: stop_indices,
             routes: routes,
             trips: trips,
+            trip_indices: trip_indices,
             services: services,
-            stop_node_chains: stop_node_chains,
+            block_successors: block_successors,
+            pedestrian_connections: pedestrian_connections,
+            incoming_pedestrian_connections: incoming_pedestrian_connections,
+            cycling_connections: OnceLock::new(),
+            stop_rule_pairs: OnceLock::new(),
+            transfer_rules: transfer_rules,
             stop_groups: stop_groups,
-            nodes: nodes,
-        };
+            graph: OnceLock::new(),
+            search_states: Mutex::new(Vec::new()),
+            timetable: OnceLock::new(),
+            raptor_routes: OnceLock::new(),
+            trip_services: trip_services,
+            transfer_times: transfer_times,
+        })
+    }
 
-        nw
+    /// Writes everything needed to restore the network without reading the feed again, see `snapshot`.
+    /// The indices of the ids, the edges leading back and the incoming pedestrian connections are cheap
+    /// to derive, so they are left out. The cycling connections are only created by searches with a bicycle.
+    pub(crate) fn write_snapshot<W: Write>(&self, writer: &mut W) -> bincode::Result<()> {
+        let services: Vec<(&Service, &Vec<ServiceException>)> = self.services.iter()
+            .map(|service| (service, &service.exceptions))
+            .collect();
+        let trips: Vec<(&Trip, &Vec<StopTime>, Option<Headway>)> = self.trips.iter()
+            .map(|trip| (trip, &trip.stop_times, trip.headway))
+            .collect();
+        let coords: Vec<(f32, f32)> = self.stop_coords.iter().map(|point| (point.x(), point.y())).collect();
+        bincode::serialize_into(&mut *writer, self.timezone.name())?;
+        bincode::serialize_into(&mut *writer, &self.stops)?;
+        bincode::serialize_into(&mut *writer, &self.routes)?;
+        bincode::serialize_into(&mut *writer, &services)?;
+        bincode::serialize_into(&mut *writer, &trips)?;
+        bincode::serialize_into(&mut *writer, &self.pedestrian_connections)?;
+        bincode::serialize_into(&mut *writer, &coords)?;
+        bincode::serialize_into(&mut *writer, &self.transfer_rules)?;
+        bincode::serialize_into(&mut *writer, &self.stop_groups)?;
+        bincode::serialize_into(&mut *writer, &self.graph.get().map(|graph| (&graph.stop_node_chains, &graph.nodes)))
     }
 
-    /// Compares the names with the supplied name and returns the most similar one (by Levehnstein)
-    fn get_stop_group_by_name(&self, name: &String) -> Option<&StopGroup> {
-        let mut closest = None;
-        let mut best_score = None;
-        for (_, g) in &self.stop_groups {
-            for stop_name in &g.names {
-                let score = get_common_prefix_len(&name, stop_name);
-                best_score = match best_score {
-                    Some(past_score) => {
-                        if score > past_score {
-                            closest = Some(g);
-                            Some(score)
+    /// Restores the network written by write_snapshot
+    pub(crate) fn read_snapshot<R: Read>(reader: &mut R) -> bincode::Result<Network> {
+        let timezone: String = bincode::deserialize_from(&mut *reader)?;
+        let stops: Vec<Stop> = bincode::deserialize_from(&mut *reader)?;
+        let routes: HashMap<String, Route> = bincode::deserialize_from(&mut *reader)?;
+        let services: Vec<(Service, Vec<ServiceException>)> = bincode::deserialize_from(&mut *reader)?;
+        let trips: Vec<(Trip, Vec<StopTime>, Option<Headway>)> = bincode::deserialize_from(&mut *reader)?;
+        let pedestrian_connections: Vec<Vec<(StopIndex, u32)>> = bincode::deserialize_from(&mut *reader)?;
+        let coords: Vec<(f32, f32)> = bincode::deserialize_from(&mut *reader)?;
+        let transfer_rules: TransferRules = bincode::deserialize_from(&mut *reader)?;
+        let stop_groups: HashMap<String, StopGroup> = bincode::deserialize_from(&mut *reader)?;
+        let graph: Option<(Vec<Vec<usize>>, Vec<Node>)> = bincode::deserialize_from(&mut *reader)?;
+
+        let services: Vec<Service> = services.into_iter()
+            .map(|(mut service, exceptions)| {
+                service.exceptions = exceptions;
+                service
+            })
+            .collect();
+        let trips: Vec<Trip> = trips.into_iter()
+            .map(|(mut trip, stop_times, headway)| {
+                trip.stop_times = stop_times;
+                trip.headway = headway;
+                trip
+            })
+            .collect();
+        let index = |ids: Vec<&String>| -> HashMap<String, u32> {
+            ids.into_iter().enumerate().map(|(index, id)| (id.clone(), index as u32)).collect()
+        };
+        let stop_indices = index(stops.iter().map(|stop| &stop.stop_id).collect());
+        let trip_indices = index(trips.iter().map(|trip| &trip.trip_id).collect());
+        let incoming_pedestrian_connections = Network::reverse_connections(&pedestrian_connections);
+        let coords: Vec<Point<f32>> = coords.into_iter().map(|(x, y)| Point::new(x, y)).collect();
+        let graph = match graph {
+            Some((stop_node_chains, nodes)) => OnceLock::from(Network::create_graph(nodes, stop_node_chains)),
+            None => OnceLock::new(),
+        };
+        let trip_services = Network::get_trip_services(&trips, &services);
+        let transfer_times = Network::get_transfer_times(&stops, &transfer_rules);
+        let block_successors = Network::create_block_successors(&trips);
+
+        Ok(Network {
+            timezone: timezone.parse::<Tz>().unwrap_or(DEFAULT_TIMEZONE),
+            stop_indices: stop_indices,
+            stops: stops,
+            routes: routes,
+            trip_indices: trip_indices,
+            trips: trips,
+            services: services,
+            block_successors: block_successors,
+            pedestrian_connections: pedestrian_connections,
+            stop_coords: coords,
+            incoming_pedestrian_connections: incoming_pedestrian_connections,
+            cycling_connections: OnceLock::new(),
+            stop_rule_pairs: OnceLock::new(),
+            transfer_rules: transfer_rules,
+            stop_groups: stop_groups,
+            graph: graph,
+            search_states: Mutex::new(Vec::new()),
+            timetable: OnceLock::new(),
+            raptor_routes: OnceLock::new(),
+            trip_services: trip_services,
+            transfer_times: transfer_times,
+        })
+    }
+
+    /// Compares the names with the supplied name and returns the most similar one (by Levehnstein)
+    fn get_stop_group_by_name(&self, name: &String) -> Option<&StopGroup> {
+        let mut closest = None;
+        let mut best_score = None;
+        for g in self.stop_groups.values() {
+            for stop_name in &g.names {
+                let score = get_common_prefix_len(name, stop_name);
+                best_score = match best_score {
+                    Some(past_score) => {
+                        if score > past_score {
+                            closest = Some(g);
+                            Some(score)
                         } else {
                             Some(past_score)
                         }
@@ -348,86 +1262,1508 @@ impl Network {
         closest
     }
 
-    fn get_departures_from_stop_group_after_time(&self, group: &StopGroup, time: u32) -> Vec<&Node> {
+    pub fn get_trip_short_name(&self, trip: &Trip) -> String {
+        let route = self.routes.get(&trip.route_id).expect("No route found for trip!");
+        match &trip.trip_headsign {
+            Some(_) => route.route_short_name.clone(),
+            None => String::from("Unnamed trip"),
+        }
+    }    
+
+    fn is_destination(&self, location: &Location, dest_stop_group: &StopGroup) -> bool {
+        match location {
+            Location::Stop(stop) => {
+                dest_stop_group.stops.contains(stop)
+            },
+            Location::Trip(_, _, _, _) => false,
+        }
+    }
+
+    fn can_take_edge(&self, date: &NaiveDate, profile: &SearchProfile, dep_node: &Node, dest_node: &Node) -> bool {
+        match (dep_node.get_location(), dest_node.get_location()) {
+            (_, Location::Trip(trip, service, _, _))
+                if !does_service_operate(date, &self.services[*service as usize]) || !self.is_trip_allowed(*trip, profile) => false,
+            // boarding or getting off, the backward searches take the edges the other way round
+            (Location::Stop(stop), Location::Trip(_, _, _, _)) | (Location::Trip(_, _, _, _), Location::Stop(stop)) => self.is_stop_allowed(*stop, profile),
+            (Location::Stop(stop), Location::Stop(target_stop)) if stop != target_stop => {
+                self.is_stop_allowed(*stop, profile) && self.is_stop_allowed(*target_stop, profile)
+            },
+            _ => true,
+        }
+    }
+
+    fn is_trip_allowed(&self, trip: TripIndex, profile: &SearchProfile) -> bool {
+        let trip = &self.trips[trip as usize];
+        let mobility_allowed = match profile.mobility {
+            Mobility::Walking => true,
+            Mobility::Wheelchair => trip.wheelchair_accessible == Some(1),
+            Mobility::Bicycle { .. } => trip.bikes_allowed == Some(1),
+        };
+        // most searches do not filter the routes, so their lookup is skipped
+        mobility_allowed && (profile.routes.is_empty() || profile.routes.allows(&self.routes[&trip.route_id]))
+    }
+
+    /// Checks whether the passenger can board, get off and transfer at the stop. A platform
+    /// without the accessibility information takes it from its station.
+    fn is_stop_allowed(&self, stop: StopIndex, profile: &SearchProfile) -> bool {
+        match profile.mobility {
+            Mobility::Walking | Mobility::Bicycle { .. } => true,
+            Mobility::Wheelchair => {
+                let stop = &self.stops[stop as usize];
+                match stop.wheelchair_boarding {
+                    Some(1) => true,
+                    Some(2) => false,
+                    _ => stop.parent_station.as_ref()
+                        .and_then(|parent_station| self.stop_indices.get(parent_station))
+                        .is_some_and(|parent| self.stops[*parent as usize].wheelchair_boarding == Some(1)),
+                }
+            },
+        }
+    }
+
+    /// Checks whether the service operates on the searched @day. The scans ask for every trip they go
+    /// through, so the answers are kept in @operates, indexed by the service index multiplied by SEARCH_DAYS plus the day.
+    fn does_service_operate_on_day(&self, operates: &mut Vec<Option<bool>>, days: &SearchDays, service: ServiceIndex, day: usize) -> bool {
+        let service_state = service as usize * SEARCH_DAYS + day;
+        *operates[service_state].get_or_insert_with(|| does_service_operate(&days.dates[day], &self.services[service as usize]))
+    }
+
+    /// Checks the rules from transfers.txt that depend on the trips of a transfer. Both ends of the transfer
+    /// consist of the stop node, the transport node and the time of the transport node.
+    fn is_transfer_allowed(&self, from: (&Node, &Node, i64), to: (&Node, &Node, i64)) -> bool {
+        let ((from_stop_node, from_trip_node, from_time), (to_stop_node, to_trip_node, to_time)) = (from, to);
+        match (from_stop_node.get_location(), from_trip_node.get_location(), to_stop_node.get_location(), to_trip_node.get_location()) {
+            (Location::Stop(from_stop), Location::Trip(from_trip, _, _, _), Location::Stop(to_stop), Location::Trip(to_trip, _, _, _)) => {
+                let (from_stop_id, to_stop_id) = (&self.stops[*from_stop as usize].stop_id, &self.stops[*to_stop as usize].stop_id);
+                match self.transfer_rules.get_rule(from_stop_id, to_stop_id, &self.trips[*from_trip as usize], &self.trips[*to_trip as usize]) {
+                    Some(Transfer { transfer_type: 3, .. }) => false,
+                    Some(Transfer { transfer_type: 2, min_transfer_time: Some(time), .. }) => from_time + *time as i64 <= to_time,
+                    _ => true,
+                }
+            },
+            _ => true,
+        }
+    }
+
+    /// The counterpart of is_transfer_allowed for the scans, which know the stop, the trip and its departure
+    /// both where the passenger got off and where they board
+    fn is_boarding_allowed(&self, from: (StopIndex, TripIndex, i64), to: (StopIndex, TripIndex, i64)) -> bool {
+        let ((from_stop, from_trip, from_time), (to_stop, to_trip, to_time)) = (from, to);
+        if !self.is_restricted(from_stop, from_trip, SearchDirection::Forward) {
+            return true;
+        }
+        let (from_stop_id, to_stop_id) = (&self.stops[from_stop as usize].stop_id, &self.stops[to_stop as usize].stop_id);
+        match self.transfer_rules.get_rule(from_stop_id, to_stop_id, &self.trips[from_trip as usize], &self.trips[to_trip as usize]) {
+            Some(Transfer { transfer_type: 3, .. }) => false,
+            Some(Transfer { transfer_type: 2, min_transfer_time: Some(time), .. }) => from_time + *time as i64 <= to_time,
+            _ => true,
+        }
+    }
+
+    /// Returns the unix timestamp at which the service day starts. GTFS measures stop times from
+    /// "noon minus 12h" in local time, which keeps the times correct on days with DST changes.
+    fn get_service_day_start(&self, date: &NaiveDate) -> i64 {
+        let noon = self.timezone.from_local_datetime(&date.and_hms_opt(12, 0, 0).unwrap()).unwrap();
+        noon.timestamp() - 12 * 3600
+    }
+
+    /// Converts local time to a unix timestamp. Ambiguous times resolve to the earlier instant,
+    /// times skipped by a DST change are moved forward by an hour.
+    fn get_timestamp(&self, datetime: &NaiveDateTime) -> i64 {
+        match self.timezone.from_local_datetime(datetime) {
+            LocalResult::Single(time) => time.timestamp(),
+            LocalResult::Ambiguous(earliest, _) => earliest.timestamp(),
+            LocalResult::None => self.get_timestamp(&(*datetime + Duration::hours(1))),
+        }
+    }
+
+    fn get_local_time(&self, timestamp: i64) -> NaiveDateTime {
+        self.timezone.timestamp_opt(timestamp, 0).unwrap().naive_local()
+    }
+
+    /// Search states are the nodes of the network repeated for every searched service day
+    fn get_state(&self, node_id: usize, day: usize) -> usize {
+        day * self.graph().nodes.len() + node_id
+    }
+
+    fn get_state_node(&self, state: usize) -> &Node {
+        let nodes = &self.graph().nodes;
+        &nodes[state % nodes.len()]
+    }
+
+    fn get_state_day(&self, state: usize) -> usize {
+        state / self.graph().nodes.len()
+    }
+
+    /// Takes the labels of a finished search to run another one in @direction, or allocates new ones
+    /// when all of them are being used by the queries running in other threads
+    fn take_search_state(&self, direction: SearchDirection) -> SearchState {
+        let pooled = self.search_states.lock().unwrap().pop();
+        match pooled {
+            Some(mut search_state) => {
+                search_state.reset(direction);
+                search_state
+            },
+            None => SearchState::new(self.graph().nodes.len() * SEARCH_DAYS, direction),
+        }
+    }
+
+    /// Gives the labels back to be reused by the next search, see take_search_state
+    fn return_search_state(&self, search_state: SearchState) {
+        self.search_states.lock().unwrap().push(search_state);
+    }
+
+    /// Returns the first node in the stop node chain that is there at @timestamp or later
+    /// on the service day starting at @day_start
+    fn get_first_departure_on_day(&self, stop: StopIndex, timestamp: i64, day_start: i64) -> Option<usize> {
+        let offset = if timestamp > day_start { timestamp - day_start } else { 0 };
+        self.get_first_departure(stop, offset as u32)
+    }
+
+    /// Returns the first nodes on the service day starting at @day_start that can be reached
+    /// from the stop at @timestamp, either by waiting or by walking to a neighbouring stop
+    /// (every stop is its own neighbour). The network only contains pedestrian edges within
+    /// a single service day. The profiles with their own walks use it within the same day too.
+    fn get_next_day_nodes(&self, stop: StopIndex, timestamp: i64, day_start: i64, profile: &SearchProfile) -> Vec<usize> {
         let mut result = Vec::new();
-        for stop_id in &group.stops {
-            if let Some(dep) = self.get_first_departure(&stop_id, time) {
-                result.push(&self.nodes[dep]);
+        for (neighbouring_stop, travel_time) in self.get_neighbours(stop, profile, SearchDirection::Forward) {
+            let arrival = timestamp + travel_time as i64;
+            if let Some(node) = self.get_first_departure_on_day(neighbouring_stop, arrival, day_start) {
+                result.push(node);
             }
         }
         result
     }
 
-    pub fn get_trip_short_name(&self, trip: &Rc<Trip>) -> String {
-        let route = self.routes.get(&trip.route_id).expect("No route found for trip!");
-        match &trip.trip_headsign {
-            Some(name) => route.route_short_name.clone(),
-            None => String::from("Unnamed trip"),
+    /// Returns the last node in the stop node chain that is there at @timestamp or earlier
+    /// on the service day starting at @day_start
+    fn get_last_arrival_on_day(&self, stop: StopIndex, timestamp: i64, day_start: i64) -> Option<usize> {
+        if timestamp < day_start {
+            return None;
         }
-    }    
+        let graph = self.graph();
+        let chain = &graph.stop_node_chains[stop as usize];
+        let offset = (timestamp - day_start).min(u32::MAX as i64) as u32;
+        let index = chain.partition_point(|node_id| graph.nodes[*node_id].get_time() <= offset);
+        if index == 0 { None } else { Some(chain[index - 1]) }
+    }
 
-    fn is_destination(&self, node: &Node, dest_stop_group: &StopGroup) -> bool {
-        match node.get_location() {
-            Location::Stop(stop) => {
-                dest_stop_group.stops.contains(&stop.stop_id)
+    /// The backward counterpart of get_next_day_nodes, returns the last nodes on the service day
+    /// starting at @day_start from which the stop can be reached at @timestamp.
+    fn get_previous_day_nodes(&self, stop: StopIndex, timestamp: i64, day_start: i64, profile: &SearchProfile) -> Vec<usize> {
+        let mut result = Vec::new();
+        for (neighbouring_stop, travel_time) in self.get_neighbours(stop, profile, SearchDirection::Backward) {
+            let departure = timestamp - travel_time as i64;
+            if let Some(node) = self.get_last_arrival_on_day(neighbouring_stop, departure, day_start) {
+                result.push(node);
+            }
+        }
+        result
+    }
+
+    /// Returns the stops the profile can move to from the stop (or from which it can move to the stop
+    /// in backward searches), along with the travel times. Cyclists ride to the stops within their radius,
+    /// the others walk along the pedestrian connections.
+    fn get_neighbours(&self, stop: StopIndex, profile: &SearchProfile, direction: SearchDirection) -> Vec<(StopIndex, u32)> {
+        let pedestrian_connections = match direction {
+            SearchDirection::Forward => &self.pedestrian_connections,
+            SearchDirection::Backward => &self.incoming_pedestrian_connections,
+        };
+        match profile.mobility {
+            Mobility::Bicycle { speed, radius } => {
+                // the stop-level rules from transfers.txt replace the rides as they replace the walks,
+                // the pedestrian connections already hold the transfers they allow
+                let has_stop_rule = |neighbouring_stop: StopIndex| match direction {
+                    SearchDirection::Forward => self.has_stop_rule(stop, neighbouring_stop),
+                    SearchDirection::Backward => self.has_stop_rule(neighbouring_stop, stop),
+                };
+                let mut neighbours: Vec<(StopIndex, u32)> = self.get_cycling_connections()[stop as usize].iter()
+                    .filter(|(neighbouring_stop, distance)| *distance <= radius && !has_stop_rule(*neighbouring_stop))
+                    .map(|(neighbouring_stop, distance)| (*neighbouring_stop, (*distance / speed) as u32))
+                    .collect();
+                neighbours.extend(pedestrian_connections[stop as usize].iter()
+                    .filter(|(neighbouring_stop, _)| has_stop_rule(*neighbouring_stop)));
+                neighbours
+            },
+            _ => pedestrian_connections[stop as usize].iter()
+                .filter(|(neighbouring_stop, _)| {
+                    *neighbouring_stop == stop || (self.is_stop_allowed(stop, profile) && self.is_stop_allowed(*neighbouring_stop, profile))
+                })
+                .map(|(neighbouring_stop, travel_time)| (*neighbouring_stop, profile.get_travel_time(*travel_time)))
+                .collect(),
+        }
+    }
+
+    fn get_search_days(&self, datetime: &NaiveDateTime) -> SearchDays {
+        let first_date = datetime.date().pred_opt().unwrap();
+        let dates: Vec<NaiveDate> = (0..SEARCH_DAYS).map(|day| first_date + Duration::days(day as i64)).collect();
+        let starts = dates.iter().map(|date| self.get_service_day_start(date)).collect();
+        SearchDays { dates: dates, starts: starts }
+    }
+
+    /// Returns the states in which a search can start at a stop group. Forward searches start at the first
+    /// nodes after @timestamp, backward searches at the last nodes before it.
+    fn get_search_starts(&self, group: &StopGroup, timestamp: i64, days: &SearchDays, direction: SearchDirection) -> Vec<usize> {
+        let mut result = Vec::new();
+        for day in 0..SEARCH_DAYS {
+            for stop in &group.stops {
+                let start = match direction {
+                    SearchDirection::Forward => self.get_first_departure_on_day(*stop, timestamp, days.starts[day]),
+                    SearchDirection::Backward => self.get_last_arrival_on_day(*stop, timestamp, days.starts[day]),
+                };
+                if let Some(start) = start {
+                    result.push(self.get_state(start, day));
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the states of the nodes in the stop node chains of the group that are there between
+    /// @from and @to (both inclusive), ordered by time
+    fn get_states_in_window(&self, group: &StopGroup, from: i64, to: i64, days: &SearchDays) -> Vec<usize> {
+        let mut result = Vec::new();
+        for day in 0..SEARCH_DAYS {
+            for stop in &group.stops {
+                let chain = &self.graph().stop_node_chains[*stop as usize];
+                let first = chain.partition_point(|node_id| days.starts[day] + (self.graph().nodes[*node_id].get_time() as i64) < from);
+                for node_id in &chain[first..] {
+                    if days.starts[day] + self.graph().nodes[*node_id].get_time() as i64 > to {
+                        break;
+                    }
+                    result.push(self.get_state(*node_id, day));
+                }
+            }
+        }
+        result.sort_by_key(|state| self.get_state_time(*state, days));
+        result
+    }
+
+    fn get_state_time(&self, state: usize, days: &SearchDays) -> i64 {
+        days.starts[self.get_state_day(state)] + self.get_state_node(state).get_time() as i64
+    }
+
+    /// Runs the Dijkstra over the states of the network from @starts until a state satisfying @is_target
+    /// is found. A backward search follows the edges in reverse and looks for the latest times. The search
+    /// gives up once the popped times are not better than @bound and only takes what @profile allows.
+    fn search<F: Fn(&Node) -> bool>(
+        &self,
+        days: &SearchDays,
+        search_state: &mut SearchState,
+        starts: Vec<usize>,
+        bound: i64,
+        profile: &SearchProfile,
+        is_target: F,
+    ) -> Option<usize> {
+        let direction = search_state.direction;
+        let mut heap = BinaryHeap::new();
+        for state in starts {
+            let start_time = self.get_state_time(state, days);
+            if direction.is_better(start_time, search_state.times[state]) {
+                search_state.set_label(state, start_time, None, None);
+                heap.push(SearchEntry { time: direction.key(start_time), state: state });
+            }
+        }
+
+        while let Some(SearchEntry { time: key, state }) = heap.pop() {
+            let time = direction.key(key);
+            if !direction.is_better(time, bound) {
+                break;
+            }
+            if time != search_state.times[state] {
+                continue;
+            }
+            let node = self.get_state_node(state);
+            let day = self.get_state_day(state);
+            if is_target(node) {
+                return Some(state);
+            }
+
+            let mut targets: Vec<usize> = match direction {
+                SearchDirection::Forward => node.get_edges().iter().map(|target| self.get_state(*target, day)).collect(),
+                SearchDirection::Backward => self.graph().reverse_edges[node.node_id].iter().map(|target| self.get_state(*target, day)).collect(),
+            };
+            if let Location::Stop(stop) = *node.get_location() {
+                if profile.has_own_walks() {
+                    self.replace_walks(&mut targets, state, time, days, direction, profile);
+                }
+                match direction {
+                    SearchDirection::Forward if day + 1 < SEARCH_DAYS && search_state.roll_over(stop, day + 1, time, direction) => {
+                        targets.extend(self.get_next_day_nodes(stop, time, days.starts[day + 1], profile)
+                            .into_iter()
+                            .map(|next_day_node| self.get_state(next_day_node, day + 1)));
+                    },
+                    SearchDirection::Backward if day > 0 && search_state.roll_over(stop, day - 1, time, direction) => {
+                        targets.extend(self.get_previous_day_nodes(stop, time, days.starts[day - 1], profile)
+                            .into_iter()
+                            .map(|previous_day_node| self.get_state(previous_day_node, day - 1)));
+                    },
+                    _ => (),
+                }
+            }
+            // the other end of a transfer that is being made, with the trip it connects to
+            let pending = search_state.pending_transfer[state].map(|stop_state| {
+                let trip_state = search_state.came_from[stop_state].unwrap();
+                (self.get_state_node(stop_state), self.get_state_node(trip_state), search_state.times[trip_state])
+            });
+            for target in targets {
+                let target_node = self.get_state_node(target);
+                let target_day = self.get_state_day(target);
+                let target_time = self.get_state_time(target, days);
+                let target_pending = match (node.get_location(), target_node.get_location()) {
+                    (Location::Trip(trip, _, _, _), Location::Stop(stop)) if self.is_restricted(*stop, *trip, direction) => Some(target),
+                    (Location::Stop(_), Location::Stop(_)) => search_state.pending_transfer[state],
+                    _ => None,
+                };
+                let transfer_allowed = match pending {
+                    Some(pending) => match direction {
+                        SearchDirection::Forward => self.is_transfer_allowed(pending, (node, target_node, target_time)),
+                        SearchDirection::Backward => self.is_transfer_allowed((node, target_node, target_time), pending),
+                    },
+                    None => true,
+                };
+                let lifts_restriction = target_time == search_state.times[target] && search_state.pending_transfer[target].is_some() && target_pending.is_none();
+                if (direction.is_better(target_time, search_state.times[target]) || lifts_restriction)
+                    && self.can_take_edge(&days.dates[target_day], profile, node, target_node)
+                    && transfer_allowed {
+                    heap.push(SearchEntry { time: direction.key(target_time), state: target });
+                    search_state.set_label(target, target_time, Some(state), target_pending);
+                }
+            }
+        }
+        None
+    }
+
+    /// Replaces the targets reached by the pedestrian edges from the stop by those the profile walks to
+    /// at its own pace on the same service day
+    fn replace_walks(&self, targets: &mut Vec<usize>, state: usize, time: i64, days: &SearchDays, direction: SearchDirection, profile: &SearchProfile) {
+        let day = self.get_state_day(state);
+        let stop = match *self.get_state_node(state).get_location() {
+            Location::Stop(stop) => stop,
+            Location::Trip(..) => return,
+        };
+        targets.retain(|target| match *self.get_state_node(*target).get_location() {
+            Location::Stop(target_stop) => target_stop == stop,
+            Location::Trip(..) => true,
+        });
+        let walks = match direction {
+            SearchDirection::Forward => self.get_next_day_nodes(stop, time, days.starts[day], profile),
+            SearchDirection::Backward => self.get_previous_day_nodes(stop, time, days.starts[day], profile),
+        };
+        targets.extend(walks.into_iter().map(|node| self.get_state(node, day)));
+    }
+
+    /// Builds the connection ending in @found by a forward search, or starting in @found by a backward search
+    fn build_connection(&self, search_state: &SearchState, found: usize, direction: SearchDirection) -> Connection {
+        let mut state = found;
+        let mut locations = vec![*self.get_state_node(state).get_location()];
+        let mut times = vec![self.get_local_time(search_state.times[state])];
+        while let Some(prev) = search_state.came_from[state] {
+            state = prev;
+            locations.push(*self.get_state_node(state).get_location());
+            times.push(self.get_local_time(search_state.times[state]));
+        }
+        if direction == SearchDirection::Forward {
+            locations.reverse();
+            times.reverse();
+        }
+        self.create_connection(locations, times)
+    }
+
+    /// Checks whether the transfer rules can restrict the trips taken after getting off @trip at @stop,
+    /// see TransferRules::is_restricted
+    fn is_restricted(&self, stop: StopIndex, trip: TripIndex, direction: SearchDirection) -> bool {
+        self.transfer_rules.is_restricted(&self.stops[stop as usize].stop_id, &self.trips[trip as usize], direction)
+    }
+
+    /// Returns the walking distance between two stops, measured the same way as in get_pedestrian_connections
+    fn get_walking_distance(&self, from: StopIndex, to: StopIndex) -> f32 {
+        let (from, to) = (self.stop_coords[from as usize], self.stop_coords[to as usize]);
+        (from.x() - to.x()).abs() + (from.y() - to.y()).abs()
+    }
+
+    /// Returns the number of vehicles boarded and the distance walked when moving from @location to @target
+    fn get_edge_cost(&self, location: &Location, target: &Location) -> (u32, f32) {
+        match (location, target) {
+            (Location::Stop(_), Location::Trip(_, _, _, _)) => (1, 0.0),
+            // staying seated when the vehicle continues as another trip is not a transfer
+            (Location::Trip(trip, _, _, _), Location::Trip(target_trip, _, _, _))
+                if trip != target_trip && self.block_successors[*trip as usize] != Some(*target_trip) => (1, 0.0),
+            (Location::Stop(stop), Location::Stop(target_stop)) if stop != target_stop => {
+                (0, self.get_walking_distance(*stop, *target_stop))
             },
-            Location::Trip(_, _) => false,
+            _ => (0, 0.0),
+        }
+    }
+
+    fn create_connection(&self, locations: Vec<Location>, times: Vec<NaiveDateTime>) -> Connection {
+        let mut boardings = 0;
+        let mut walking_distance = 0.0;
+        for index in 1..locations.len() {
+            let (edge_boardings, edge_distance) = self.get_edge_cost(&locations[index - 1], &locations[index]);
+            boardings += edge_boardings;
+            walking_distance += edge_distance;
+        }
+        let segments = vec![Range { start: 0, end: locations.len() }];
+        Connection {
+            locations: locations,
+            times: times,
+            transfers: if boardings > 0 { boardings - 1 } else { 0 },
+            walking_distance: walking_distance,
+            segments: segments,
+        }
+    }
+
+    fn find_connection_in_direction(
+        &self,
+        dep_stop_name: &String,
+        dest_stop_name: &String,
+        datetime: NaiveDateTime,
+        direction: SearchDirection,
+        profile: &SearchProfile,
+    ) -> Result<Option<Connection>> {
+        let days = self.get_search_days(&datetime);
+        let query_time = self.get_timestamp(&datetime);
+
+        // this potentially belongs higher-up in the hierarchy and not in the model
+        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or_else(|| Error::StopNotFound(dep_stop_name.clone()))?;
+        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or_else(|| Error::StopNotFound(dest_stop_name.clone()))?;
+        let (from, to) = match direction {
+            SearchDirection::Forward => (start_stop_group, dest_stop_group),
+            SearchDirection::Backward => (dest_stop_group, start_stop_group),
+        };
+        Ok(self.find_connection_between_groups(from, to, query_time, &days, direction, profile))
+    }
+
+    /// Runs the search from the stop group @from to the stop group @to, which are the other way round for backward searches
+    fn find_connection_between_groups(
+        &self,
+        from: &StopGroup,
+        to: &StopGroup,
+        query_time: i64,
+        days: &SearchDays,
+        direction: SearchDirection,
+        profile: &SearchProfile,
+    ) -> Option<Connection> {
+        let starts = self.get_search_starts(from, query_time, days, direction);
+        let mut search_state = self.take_search_state(direction);
+        let found = self.search(days, &mut search_state, starts, direction.unreached(), profile,
+            |node| self.is_destination(node.get_location(), to));
+        let connection = found.map(|found| self.build_connection(&search_state, found, direction));
+        self.return_search_state(search_state);
+        connection
+    }
+
+    /// Finds the earliest arriving connection that passes through the stop group @via_stop_name and stays there
+    /// for at least @min_dwell. The connection consists of the segment to the via stop and the segment after it.
+    pub fn find_connection_via(
+        &self,
+        dep_stop_name: &String,
+        via_stop_name: &String,
+        dest_stop_name: &String,
+        datetime: NaiveDateTime,
+        min_dwell: Duration,
+        profile: &SearchProfile,
+    ) -> Result<Option<Connection>> {
+        if min_dwell < Duration::zero() || min_dwell > Duration::minutes(MAX_VIA_DWELL_MINUTES as i64) {
+            return Err(Error::UnsupportedQuery("the stay at the via stop has to be between zero and a day"));
+        }
+        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or_else(|| Error::StopNotFound(dest_stop_name.clone()))?;
+        let first = match self.find_connection(dep_stop_name, via_stop_name, datetime, profile)? {
+            Some(connection) => connection,
+            None => return Ok(None),
+        };
+        // the second segment continues from the stop the first one got to, it can walk to the other stops of the group
+        let via_stop = first.locations.last().unwrap().get_stop();
+        let via_group = StopGroup { names: Vec::new(), stops: HashSet::from([via_stop]) };
+        // the stay starts when the vehicle arrives, but the transfer time at the stop is kept
+        let departure = std::cmp::max(first.get_arrival_before(first.locations.len()) + min_dwell, *first.times.last().unwrap());
+        let second = match self.find_connection_between_groups(&via_group, dest_stop_group, self.get_timestamp(&departure),
+            &self.get_search_days(&departure), SearchDirection::Forward, profile) {
+            Some(connection) => connection,
+            None => return Ok(None),
+        };
+
+        let split = first.locations.len();
+        let locations = first.locations.into_iter().chain(second.locations).collect();
+        let times = first.times.into_iter().chain(second.times).collect();
+        let mut connection = self.create_connection(locations, times);
+        connection.segments = vec![0..split, split..connection.locations.len()];
+        Ok(Some(connection))
+    }
+
+    /// Returns the stops with a name containing @name, ignoring case. The stops with a name starting with it go first.
+    pub fn find_stops(&self, name: &str) -> Vec<&Stop> {
+        let name = name.to_lowercase();
+        let mut result: Vec<&Stop> = self.stops.iter()
+            .filter(|stop| stop.stop_name.to_lowercase().contains(&name))
+            .collect();
+        result.sort_by_key(|stop| (!stop.stop_name.to_lowercase().starts_with(&name), &stop.stop_name, &stop.stop_id));
+        result
+    }
+
+    /// Returns the first @count trips leaving the stop group at @datetime or later
+    pub fn find_departures(
+        &self,
+        stop_name: &String,
+        datetime: NaiveDateTime,
+        count: usize,
+    ) -> Result<Vec<Departure<'_>>> {
+        let days = self.get_search_days(&datetime);
+        let query_time = self.get_timestamp(&datetime);
+        let stop_group = self.get_stop_group_by_name(stop_name).ok_or_else(|| Error::StopNotFound(stop_name.clone()))?;
+
+        let mut departures: Vec<(i64, &Stop, &Trip)> = Vec::new();
+        for day in 0..SEARCH_DAYS {
+            for stop in &stop_group.stops {
+                for node_id in &self.graph().stop_node_chains[*stop as usize] {
+                    // the edges to transport nodes are the boardings
+                    for edge in self.graph().nodes[*node_id].get_edges() {
+                        let transport_node = &self.graph().nodes[*edge];
+                        let time = days.starts[day] + transport_node.get_time() as i64;
+                        if let Location::Trip(trip, service, _, _) = *transport_node.get_location() {
+                            if time >= query_time && does_service_operate(&days.dates[day], &self.services[service as usize]) {
+                                departures.push((time, &self.stops[*stop as usize], &self.trips[trip as usize]));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        departures.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.2.trip_id.cmp(&b.2.trip_id)));
+        Ok(departures.into_iter()
+            .take(count)
+            .map(|(time, stop, trip)| Departure {
+                stop: stop,
+                trip: trip,
+                departure: self.get_local_time(time),
+            })
+            .collect())
+    }
+
+    /// Finds the earliest arrival at every stop reachable from the departure stop group at @datetime.
+    /// Runs the same search as find_connection, but until all the reachable nodes are exhausted.
+    pub fn find_arrivals_at_all_stops(
+        &self,
+        dep_stop_name: &String,
+        datetime: NaiveDateTime,
+    ) -> Result<Vec<StopArrival<'_>>> {
+        let days = self.get_search_days(&datetime);
+        let query_time = self.get_timestamp(&datetime);
+        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or_else(|| Error::StopNotFound(dep_stop_name.clone()))?;
+
+        let starts = self.get_search_starts(start_stop_group, query_time, &days, SearchDirection::Forward);
+        let mut search_state = self.take_search_state(SearchDirection::Forward);
+        self.search(&days, &mut search_state, starts, SearchDirection::Forward.unreached(), &SearchProfile::default(), |_| false);
+
+        let mut arrivals: HashMap<StopIndex, i64> = HashMap::new();
+        for state in &search_state.reached {
+            if let Location::Stop(stop) = *self.get_state_node(*state).get_location() {
+                // the passenger is already at the departure stops, the arrival nodes wait for the transfer time
+                // after the vehicle arrives
+                let time = search_state.times[*state];
+                let time = match search_state.came_from[*state].map(|prev| self.get_state_node(prev).get_location()) {
+                    _ if start_stop_group.stops.contains(&stop) => query_time,
+                    Some(Location::Trip(..)) => time - self.transfer_rules.get_min_transfer_time(&self.get_stop_by_index(stop).stop_id) as i64,
+                    _ => time,
+                };
+                let arrival = arrivals.entry(stop).or_insert(time);
+                if time < *arrival {
+                    *arrival = time;
+                }
+            }
         }
+        self.return_search_state(search_state);
+        // the nodes only exist when a vehicle stops, walking arrives at the neighbouring stops earlier
+        let mut walking_arrivals: Vec<(StopIndex, i64)> = Vec::new();
+        for (stop, time) in &arrivals {
+            for (neighbouring_stop, travel_time) in &self.pedestrian_connections[*stop as usize] {
+                walking_arrivals.push((*neighbouring_stop, time + *travel_time as i64));
+            }
+        }
+        for (stop, time) in walking_arrivals {
+            let arrival = arrivals.entry(stop).or_insert(time);
+            if time < *arrival {
+                *arrival = time;
+            }
+        }
+        let mut result: Vec<StopArrival> = arrivals.into_iter()
+            .map(|(stop, time)| StopArrival {
+                stop: &self.stops[stop as usize],
+                arrival: self.get_local_time(time),
+                travel_time: time - query_time,
+            })
+            .collect();
+        result.sort_by(|a, b| a.travel_time.cmp(&b.travel_time).then_with(|| a.stop.stop_id.cmp(&b.stop.stop_id)));
+        Ok(result)
     }
 
-    fn can_take_edge(&self, day: &Weekday, dep_node: &Node, dest_node: &Node) -> bool {
-        match dest_node.get_location() {
-            Location::Trip(_, service) => does_trip_operate(day, service.borrow()),
-            Location::Stop(_) => true,
+    /// Finds the areas reachable from the departure stop group at @datetime within each of the time @limits in minutes.
+    /// Besides the reached stops, the areas include the walk from them in the remaining time. The polygons are in WGS84.
+    pub fn find_isochrones(
+        &self,
+        dep_stop_name: &String,
+        datetime: NaiveDateTime,
+        limits: &[u32],
+    ) -> Result<Vec<(u32, MultiPolygon<f64>)>> {
+        let arrivals = self.find_arrivals_at_all_stops(dep_stop_name, datetime)?;
+        let mut isochrones: Vec<(u32, MultiPolygon<f64>)> = Vec::new();
+        for limit in limits {
+            let limit_seconds = *limit as i64 * 60;
+            let reached: Vec<(Point<f32>, f32)> = arrivals.iter()
+                .filter(|arrival| arrival.travel_time <= limit_seconds)
+                .filter_map(|arrival| self.stop_indices.get(&arrival.stop.stop_id)
+                    .map(|stop| (self.stop_coords[*stop as usize], (limit_seconds - arrival.travel_time) as f32 * PEDESTRIAN_SPEED)))
+                .collect();
+            let area = get_reachable_area(&reached, ISOCHRONE_CELL_SIZE);
+            isochrones.push((*limit, get_area_in_wgs84(&area)));
         }
+        Ok(isochrones)
     }
-    
+
+    /// Finds the connection with the earliest arrival that departs at @datetime or later
     pub fn find_connection(
         &self,
         dep_stop_name: &String,
         dest_stop_name: &String,
         datetime: NaiveDateTime,
-    ) -> Result<Option<Connection>, &str> {
-        let day = datetime.date().weekday();
-        let seconds = datetime.hour() * 3600 + datetime.minute() * 60 + datetime.second();
+        profile: &SearchProfile,
+    ) -> Result<Option<Connection>> {
+        self.find_connection_in_direction(dep_stop_name, dest_stop_name, datetime, SearchDirection::Forward, profile)
+    }
+
+    /// Adds a label to the labels of its state unless one of them dominates it. Returns whether it was added.
+    fn add_pareto_label(labels: &mut Vec<ParetoLabel>, state_labels: &mut HashMap<usize, Vec<usize>>, label: ParetoLabel) -> bool {
+        let at_state = state_labels.entry(label.state).or_insert_with(Vec::new);
+        if at_state.iter().any(|other| labels[*other].dominates(&label)) {
+            return false;
+        }
+        at_state.retain(|other| {
+            let dominated = label.dominates(&labels[*other]);
+            labels[*other].dominated |= dominated;
+            !dominated
+        });
+        at_state.push(labels.len());
+        labels.push(label);
+        true
+    }
+
+    /// Finds the connections departing at @datetime or later that are optimal with respect to the arrival
+    /// time, the number of transfers and the walking distance, meaning that no other connection is at least
+    /// as good in all three. Runs the Dijkstra with a set of such labels in every state. To keep the number
+    /// of labels in check, connections arriving more than `PARETO_MAX_DELAY` seconds after the fastest one
+    /// and connections using more than `PARETO_MAX_BOARDINGS` vehicles are not returned, even if they are optimal.
+    pub fn find_pareto_connections(
+        &self,
+        dep_stop_name: &String,
+        dest_stop_name: &String,
+        datetime: NaiveDateTime,
+        profile: &SearchProfile,
+    ) -> Result<Vec<Connection>> {
+        let days = self.get_search_days(&datetime);
+        let query_time = self.get_timestamp(&datetime);
 
-        let mut dists = vec![-1; self.nodes.len()];
-        let mut came_from: Vec<i32> = vec![-1; self.nodes.len()];
+        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or_else(|| Error::StopNotFound(dep_stop_name.clone()))?;
+        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or_else(|| Error::StopNotFound(dest_stop_name.clone()))?;
 
-        // this potentially belongs higher-up in the hierarchy and not in the model
-        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or("Departure stop not found")?;
-        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or("Destination stop not found")?;
-        
-        let starts = self.get_departures_from_stop_group_after_time(&start_stop_group, seconds);
-        for s in &starts {
-            dists[s.node_id] = seconds as i32;
-        }
-
-        let mut heap = BinaryHeap::from(starts);
-
-        while let Some(node) = heap.pop() {
-            if self.is_destination(node, dest_stop_group) {
-                let mut index = node.node_id;
-                let mut path = Vec::new();
-                while came_from[index] != -1 {
-                    path.push(self.nodes[index].clone());
-                    index = came_from[index] as usize;
+        let mut labels: Vec<ParetoLabel> = Vec::new();
+        let mut state_labels: HashMap<usize, Vec<usize>> = HashMap::new();
+        // the labels with which the search moved on to the next service day at a stop, as in SearchState,
+        // a label dominated by one of them does not need to move on again
+        let mut rolled_over: HashMap<(StopIndex, usize), Vec<usize>> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for state in self.get_search_starts(start_stop_group, query_time, &days, SearchDirection::Forward) {
+            let label = ParetoLabel {
+                state: state,
+                time: self.get_state_time(state, &days),
+                boardings: 0,
+                walking_distance: 0.0,
+                prev: None,
+                pending_transfer: None,
+                dominated: false,
+            };
+            heap.push(SearchEntry { time: label.time, state: labels.len() });
+            Network::add_pareto_label(&mut labels, &mut state_labels, label);
+        }
+
+        let mut results: Vec<usize> = Vec::new();
+        // the heap entries hold label ids instead of states here
+        while let Some(SearchEntry { time, state: label_id }) = heap.pop() {
+            if labels[label_id].dominated || results.iter().any(|result| labels[*result].is_at_least_as_good(&labels[label_id])) {
+                continue;
+            }
+            if results.first().is_some_and(|first| time > labels[*first].time + PARETO_MAX_DELAY) {
+                break;
+            }
+            let state = labels[label_id].state;
+            let node = self.get_state_node(state);
+            let day = self.get_state_day(state);
+            if self.is_destination(node.get_location(), dest_stop_group) {
+                results.retain(|result| !labels[label_id].is_at_least_as_good(&labels[*result]));
+                results.push(label_id);
+                continue;
+            }
+
+            let mut targets: Vec<usize> = node.get_edges().iter().map(|target| self.get_state(*target, day)).collect();
+            if let Location::Stop(stop) = *node.get_location() {
+                if profile.has_own_walks() {
+                    self.replace_walks(&mut targets, state, time, &days, SearchDirection::Forward, profile);
                 }
-                path.push(self.nodes[index].clone());
-                path.reverse();
-                return Ok(Some(Connection {nodes: path}));
+                if day + 1 < SEARCH_DAYS {
+                    let rolled = rolled_over.entry((stop, day + 1)).or_insert_with(Vec::new);
+                    if !rolled.iter().any(|other| labels[*other].dominates(&labels[label_id])) {
+                        rolled.push(label_id);
+                        targets.extend(self.get_next_day_nodes(stop, time, days.starts[day + 1], profile)
+                            .into_iter()
+                            .map(|next_day_node| self.get_state(next_day_node, day + 1)));
+                    }
+                }
+            }
+            let pending = labels[label_id].pending_transfer.map(|stop_label| {
+                let trip_label = labels[stop_label].prev.unwrap();
+                (self.get_state_node(labels[stop_label].state), self.get_state_node(labels[trip_label].state), labels[trip_label].time)
+            });
+            for target in targets {
+                let target_node = self.get_state_node(target);
+                let target_time = self.get_state_time(target, &days);
+                let (boardings, walking_distance) = self.get_edge_cost(node.get_location(), target_node.get_location());
+                let boardings = labels[label_id].boardings + boardings;
+                if boardings > PARETO_MAX_BOARDINGS
+                    || !self.can_take_edge(&days.dates[self.get_state_day(target)], profile, node, target_node)
+                    || !pending.is_none_or(|pending| self.is_transfer_allowed(pending, (node, target_node, target_time))) {
+                    continue;
+                }
+                // the new label gets the next id if it is added
+                let pending_transfer = match (node.get_location(), target_node.get_location()) {
+                    (Location::Trip(trip, _, _, _), Location::Stop(stop)) if self.is_restricted(*stop, *trip, SearchDirection::Forward) => Some(labels.len()),
+                    (Location::Stop(_), Location::Stop(_)) => labels[label_id].pending_transfer,
+                    _ => None,
+                };
+                let label = ParetoLabel {
+                    state: target,
+                    time: target_time,
+                    boardings: boardings,
+                    walking_distance: labels[label_id].walking_distance + walking_distance,
+                    prev: Some(label_id),
+                    pending_transfer: pending_transfer,
+                    dominated: false,
+                };
+                if Network::add_pareto_label(&mut labels, &mut state_labels, label) {
+                    heap.push(SearchEntry { time: target_time, state: labels.len() - 1 });
+                }
+            }
+        }
+
+        Ok(results.into_iter().map(|result| {
+            let mut label_id = result;
+            let mut locations = vec![*self.get_state_node(labels[label_id].state).get_location()];
+            let mut times = vec![self.get_local_time(labels[label_id].time)];
+            while let Some(prev) = labels[label_id].prev {
+                label_id = prev;
+                locations.push(*self.get_state_node(labels[label_id].state).get_location());
+                times.push(self.get_local_time(labels[label_id].time));
             }
+            locations.reverse();
+            times.reverse();
+            self.create_connection(locations, times)
+        }).collect())
+    }
+
+    /// Finds all connections departing between @from and @to that are not dominated by another
+    /// connection, meaning that no other connection departs later and arrives earlier or at the same time.
+    /// The departures are searched from the latest one, every search is pruned by the states the previous
+    /// searches reached, as those cannot lead to an earlier arrival. Altogether, this costs about as much
+    /// as a single search over the window.
+    pub fn find_connections_in_window(
+        &self,
+        dep_stop_name: &String,
+        dest_stop_name: &String,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        profile: &SearchProfile,
+    ) -> Result<Vec<Connection>> {
+        let days = self.get_search_days(&from);
+        let (from_time, to_time) = (self.get_timestamp(&from), self.get_timestamp(&to));
+
+        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or_else(|| Error::StopNotFound(dep_stop_name.clone()))?;
+        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or_else(|| Error::StopNotFound(dest_stop_name.clone()))?;
 
-            for target_node in node.get_edges() {
-                let target_node_time = self.nodes[*target_node].get_time() as i32;
-                if dists[*target_node] == -1 || (target_node_time < dists[*target_node]) && self.can_take_edge(&day, node, &self.nodes[*target_node]) {
-                    heap.push(&self.nodes[*target_node]);
-                    dists[*target_node] = target_node_time;
-                    came_from[*target_node] = node.node_id as i32;
+        let mut search_state = self.take_search_state(SearchDirection::Forward);
+        let mut best_arrival = SearchDirection::Forward.unreached();
+        let mut connections = Vec::new();
+        for start in self.get_states_in_window(start_stop_group, from_time, to_time, &days).into_iter().rev() {
+            let found = self.search(&days, &mut search_state, vec![start], best_arrival, profile,
+                |node| self.is_destination(node.get_location(), dest_stop_group));
+            if let Some(found) = found {
+                best_arrival = search_state.times[found];
+                let connection = self.build_connection(&search_state, found, SearchDirection::Forward);
+                // the connection might wait at the departure stops until after the window
+                let leaving = connection.locations.iter()
+                    .position(|location| !self.is_destination(location, start_stop_group))
+                    .map_or(0, |index| index.saturating_sub(1));
+                if connection.times[leaving] <= to {
+                    connections.push(connection);
                 }
             }
-            dists[node.node_id] = node.get_time() as i32;
         }
-        return Ok(None);
+        self.return_search_state(search_state);
+        connections.reverse();
+        Ok(connections)
+    }
+
+    /// Finds the connection with the latest departure that arrives at @datetime or earlier
+    pub fn find_connection_arriving_by(
+        &self,
+        dep_stop_name: &String,
+        dest_stop_name: &String,
+        datetime: NaiveDateTime,
+        profile: &SearchProfile,
+    ) -> Result<Option<Connection>> {
+        self.find_connection_in_direction(dep_stop_name, dest_stop_name, datetime, SearchDirection::Backward, profile)
+    }
+}
+
+/// Returns the times at which the connection boards its first vehicle and leaves its last one
+#[cfg(test)]
+fn get_ride_times(connection: &Connection) -> (NaiveDateTime, NaiveDateTime) {
+    let ride_times: Vec<NaiveDateTime> = connection.locations.iter()
+        .zip(connection.times.iter())
+        .filter(|(location, _)| matches!(location, Location::Trip(..)))
+        .map(|(_, time)| *time)
+        .collect();
+    (ride_times[0], *ride_times.last().unwrap())
+}
+
+/// Returns the ids of the trips the connection rides, in their order
+#[cfg(test)]
+fn get_ridden_trips<'a>(nw: &'a Network, connection: &Connection) -> Vec<&'a str> {
+    let mut trips: Vec<&str> = connection.locations.iter()
+        .filter_map(|location| match *location {
+            Location::Trip(trip, _, _, _) => Some(nw.get_trip_by_index(trip).trip_id.as_str()),
+            Location::Stop(_) => None,
+        })
+        .collect();
+    trips.dedup();
+    trips
+}
+
+#[cfg(test)]
+fn datetime(year: i32, month: u32, day: u32, hour: u32, min: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, min, 0).unwrap()
+}
+
+#[test]
+fn test_search_across_midnight() {
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_midnight", &[
+        FeedChange::Replace("stop_times.txt", "T3,08:05:00,08:05:00,U1Z1,1,,0,0,", "T3,23:50:00,23:50:00,U1Z1,1,,0,0,"),
+        FeedChange::Replace("stop_times.txt", "T3,08:30:00,08:30:00,U5Z1,2,,0,0,", "T3,24:15:00,24:15:00,U5Z1,2,,0,0,"),
+    ])).unwrap();
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    // the metro arrives after midnight
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 23, 40), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 23, 50), datetime(2020, 6, 11, 0, 15)));
+
+    // the metro is gone, the connection waits for the tram and the bus of the next day
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 23, 55), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 11, 8, 0), datetime(2020, 6, 11, 8, 25)));
+}
+
+#[test]
+fn test_search_previous_service_day() {
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_previous_day", &[
+        FeedChange::Replace("stop_times.txt", "T3,08:05:00,08:05:00,U1Z1,1,,0,0,", "T3,25:10:00,25:10:00,U1Z1,1,,0,0,"),
+        FeedChange::Replace("stop_times.txt", "T3,08:30:00,08:30:00,U5Z1,2,,0,0,", "T3,25:30:00,25:30:00,U5Z1,2,,0,0,"),
+    ])).unwrap();
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    // the metro at 01:10 belongs to the service day before
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 11, 0, 30), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 11, 1, 10), datetime(2020, 6, 11, 1, 30)));
+
+    // the service does not run on the last day of 2019, so there is no metro after midnight
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 1, 1, 0, 30), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 1, 1, 8, 0), datetime(2020, 1, 1, 8, 25)));
+}
+
+#[test]
+fn test_search_on_dst_changes() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_dst")).unwrap();
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    // Europe/Prague moves the clocks forward on 29 March 2020 and back on 25 October 2020,
+    // the stop times stay in local time on both days
+    for (month, day) in [(3, 29), (10, 25)] {
+        let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, month, day, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+        assert_eq!(get_ride_times(&connection), (datetime(2020, month, day, 8, 0), datetime(2020, month, day, 8, 25)));
+    }
+
+    // the night before the change the search waits over the shorter night
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 3, 28, 23, 0), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 3, 29, 8, 0), datetime(2020, 3, 29, 8, 25)));
+}
+
+#[cfg(test)]
+const TRANSFERS_HEADER: &str = "from_stop_id,to_stop_id,transfer_type,min_transfer_time,from_trip_id,to_trip_id,from_route_id,to_route_id";
+
+#[test]
+fn test_forbidden_transfer() {
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_forbidden_transfer", &[
+        FeedChange::Add("transfers.txt", TRANSFERS_HEADER),
+        FeedChange::Add("transfers.txt", "U2Z1,U2Z1,3,,,,,"),
+    ])).unwrap();
+    let connection = nw.find_connection(&String::from("Alfa"), &String::from("Epsilon"), datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    // the tram and the bus do not connect at Beta, the metro is left
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)));
+}
+
+#[test]
+fn test_timed_transfer() {
+    let bus_leaving_with_tram = FeedChange::Replace("stop_times.txt", "T2,08:15:00,08:15:00,U2Z1,1,,0,0,", "T2,08:10:00,08:10:00,U2Z1,1,,0,0,");
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    // without a rule there is not enough time to change from the tram to the bus
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_timed_transfer", &[bus_leaving_with_tram])).unwrap();
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)));
+
+    // the bus waits for the tram
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_timed_transfer", &[
+        bus_leaving_with_tram,
+        FeedChange::Add("transfers.txt", TRANSFERS_HEADER),
+        FeedChange::Add("transfers.txt", "U2Z1,U2Z1,1,,T1,T2,,"),
+    ])).unwrap();
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 0), datetime(2020, 6, 10, 8, 25)));
+}
+
+#[test]
+fn test_minimum_transfer_time() {
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    // four minutes at Beta are enough to change from the tram to the bus
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_minimum_transfer_time", &[
+        FeedChange::Add("transfers.txt", TRANSFERS_HEADER),
+        FeedChange::Add("transfers.txt", "U2Z1,U2Z1,2,240,,,,"),
+    ])).unwrap();
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 0), datetime(2020, 6, 10, 8, 25)));
+
+    // with ten minutes needed the bus is gone and the metro is left
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_minimum_transfer_time", &[
+        FeedChange::Add("transfers.txt", TRANSFERS_HEADER),
+        FeedChange::Add("transfers.txt", "U2Z1,U2Z1,2,600,,,,"),
+    ])).unwrap();
+    let connection = nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)));
+}
+
+#[test]
+fn test_arrive_by() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_arrive_by")).unwrap();
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    // the metro arrives too late, the tram and the bus leave before it
+    let connection = nw.find_connection_arriving_by(&alfa, &epsilon, datetime(2020, 6, 10, 8, 28), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 0), datetime(2020, 6, 10, 8, 25)));
+
+    let connection = nw.find_connection_arriving_by(&alfa, &epsilon, datetime(2020, 6, 10, 8, 40), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)));
+}
+
+#[test]
+fn test_arrive_by_across_midnight() {
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_arrive_by_midnight", &[
+        FeedChange::Replace("stop_times.txt", "T3,08:05:00,08:05:00,U1Z1,1,,0,0,", "T3,23:50:00,23:50:00,U1Z1,1,,0,0,"),
+        FeedChange::Replace("stop_times.txt", "T3,08:30:00,08:30:00,U5Z1,2,,0,0,", "T3,24:15:00,24:15:00,U5Z1,2,,0,0,"),
+    ])).unwrap();
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+
+    let connection = nw.find_connection_arriving_by(&alfa, &epsilon, datetime(2020, 6, 11, 0, 30), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 23, 50), datetime(2020, 6, 11, 0, 15)));
+
+    // the metro of the day before arrives too late, the bus of that morning is the last one that makes it
+    let connection = nw.find_connection_arriving_by(&alfa, &epsilon, datetime(2020, 6, 11, 0, 10), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 6, 10, 8, 0), datetime(2020, 6, 10, 8, 25)));
+}
+
+#[test]
+fn test_window_dominance() {
+    let alfa = String::from("Alfa");
+    let epsilon = String::from("Epsilon");
+    let (from, to) = (datetime(2020, 6, 10, 7, 55), datetime(2020, 6, 10, 8, 10));
+
+    let nw = Network::new(&write_test_feed("prahadlo_test_window")).unwrap();
+    let connections = nw.find_connections_in_window(&alfa, &epsilon, from, to, &SearchProfile::default()).unwrap();
+    let ride_times: Vec<_> = connections.iter().map(get_ride_times).collect();
+    assert_eq!(ride_times, vec![
+        (datetime(2020, 6, 10, 8, 0), datetime(2020, 6, 10, 8, 25)),
+        (datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)),
+    ]);
+
+    // a later tram still catches the same bus, so the earlier one is not worth taking
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_window", &[
+        FeedChange::Add("trips.txt", "L1,daily,T4,Beta,,0,,,1,1,0,1"),
+        FeedChange::Add("stop_times.txt", "T4,08:03:00,08:03:00,U1Z1,1,,0,0,"),
+        FeedChange::Add("stop_times.txt", "T4,08:12:00,08:12:00,U2Z1,2,,0,0,"),
+    ])).unwrap();
+    let connections = nw.find_connections_in_window(&alfa, &epsilon, from, to, &SearchProfile::default()).unwrap();
+    let ride_times: Vec<_> = connections.iter().map(get_ride_times).collect();
+    assert_eq!(ride_times, vec![
+        (datetime(2020, 6, 10, 8, 3), datetime(2020, 6, 10, 8, 25)),
+        (datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)),
+    ]);
+}
+
+#[test]
+fn test_pareto_connections() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_pareto")).unwrap();
+    let connections = nw.find_pareto_connections(&String::from("Alfa"), &String::from("Epsilon"), datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap();
+    // the tram with the bus is faster, the metro goes without a transfer
+    let found: Vec<_> = connections.iter().map(|connection| (get_ride_times(connection), connection.transfers)).collect();
+    assert_eq!(found, vec![
+        ((datetime(2020, 6, 10, 8, 0), datetime(2020, 6, 10, 8, 25)), 1),
+        ((datetime(2020, 6, 10, 8, 5), datetime(2020, 6, 10, 8, 30)), 0),
+    ]);
+}
+
+#[test]
+fn test_arrivals_at_all_stops() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_arrivals")).unwrap();
+    let arrivals = nw.find_arrivals_at_all_stops(&String::from("Alfa"), datetime(2020, 6, 10, 7, 50)).unwrap();
+    let get_arrival = |stop_name: &str| arrivals.iter().find(|arrival| arrival.stop.stop_name == stop_name).unwrap();
+    assert_eq!(get_arrival("Alfa").arrival, datetime(2020, 6, 10, 7, 50));
+    assert_eq!(get_arrival("Beta").arrival, datetime(2020, 6, 10, 8, 10));
+    assert_eq!(get_arrival("Gama").arrival, datetime(2020, 6, 10, 8, 20));
+    assert_eq!(get_arrival("Epsilon").arrival, datetime(2020, 6, 10, 8, 25));
+    assert_eq!(get_arrival("Epsilon").travel_time, 35 * 60);
+    // Delta is a walk from the tram at Gama
+    let (gama, delta) = (nw.stop_indices["U3Z1"], nw.stop_indices["U4Z1"]);
+    let walking_time = nw.pedestrian_connections[gama as usize].iter().find(|(stop, _)| *stop == delta).unwrap().1;
+    assert_eq!(get_arrival("Delta").arrival, datetime(2020, 6, 10, 8, 20) + Duration::seconds(walking_time as i64));
+}
+
+#[test]
+fn test_isochrones() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_isochrones")).unwrap();
+    let isochrones = nw.find_isochrones(&String::from("Alfa"), datetime(2020, 6, 10, 7, 50), &[15, 30, 45]).unwrap();
+    // whether the stop lies within the bounds of one of the polygons
+    let covers = |area: &MultiPolygon<f64>, stop_id: &str| {
+        let stop = nw.get_stop(&String::from(stop_id)).unwrap();
+        let (lon, lat) = (stop.stop_lon as f64, stop.stop_lat as f64);
+        area.0.iter().any(|polygon| {
+            let coords: Vec<_> = polygon.exterior().points_iter().collect();
+            coords.iter().any(|point| point.x() <= lon) && coords.iter().any(|point| point.x() >= lon)
+                && coords.iter().any(|point| point.y() <= lat) && coords.iter().any(|point| point.y() >= lat)
+        })
+    };
+    let limits: Vec<u32> = isochrones.iter().map(|(limit, _)| *limit).collect();
+    assert_eq!(limits, vec![15, 30, 45]);
+    // the tram reaches Beta in 20 minutes and Gama in 30, the bus reaches Epsilon in 35
+    let covered: Vec<Vec<bool>> = isochrones.iter()
+        .map(|(_, area)| ["U1Z1", "U2Z1", "U3Z1", "U5Z1"].iter().map(|stop_id| covers(area, stop_id)).collect())
+        .collect();
+    assert_eq!(covered, vec![
+        vec![true, false, false, false],
+        vec![true, true, true, false],
+        vec![true, true, true, true],
+    ]);
+}
+
+#[test]
+fn test_optional_calendar_files() {
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_no_calendar_dates", &[FeedChange::Remove("calendar_dates.txt")])).unwrap();
+    let (alfa, gama) = (String::from("Alfa"), String::from("Gama"));
+    let connection = nw.find_connection(&alfa, &gama, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 4, 14, 8, 0), datetime(2020, 4, 14, 8, 20)));
+
+    // the trams only run on the dates of calendar_dates.txt
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_no_calendar", &[
+        FeedChange::Remove("calendar.txt"),
+        FeedChange::Add("calendar_dates.txt", "daily,20200414,1"),
+    ])).unwrap();
+    let connection = nw.find_connection(&alfa, &gama, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&connection), (datetime(2020, 4, 14, 8, 0), datetime(2020, 4, 14, 8, 20)));
+    assert!(nw.find_connection(&alfa, &gama, datetime(2020, 4, 15, 7, 50), &SearchProfile::default()).unwrap().is_none());
+
+    match Network::new(&write_test_feed_with("prahadlo_test_no_services", &[
+        FeedChange::Remove("calendar.txt"),
+        FeedChange::Remove("calendar_dates.txt"),
+    ])) {
+        Err(Error::Gtfs(crate::error::GtfsError::Io { file, .. })) => assert_eq!(file, "calendar.txt"),
+        _ => panic!("a feed needs calendar.txt or calendar_dates.txt"),
+    }
+}
+
+#[test]
+fn test_network_shared_across_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Network>();
+
+    let nw = Network::new(&write_test_feed("prahadlo_test_threads")).unwrap();
+    let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+    let connection = std::thread::scope(|scope| {
+        scope.spawn(|| nw.find_connection(&String::from("Alfa"), &String::from("Gama"), datetime, &SearchProfile::default())).join().unwrap()
+    }).unwrap().unwrap();
+    let trips: Vec<&str> = connection.locations.iter()
+        .filter_map(|location| match *location {
+            Location::Trip(trip, _, _, _) => Some(nw.get_trip_by_index(trip).trip_id.as_str()),
+            Location::Stop(_) => None,
+        })
+        .collect();
+    assert_eq!(trips, vec!["T1", "T1", "T1"]);
+    assert_eq!(nw.get_stop_by_index(connection.locations.last().unwrap().get_stop()).stop_name, "Gama");
+}
+
+#[test]
+fn test_batch_queries() {
+    use crate::query::ConnectionQuery;
+
+    let nw = Network::new(&write_test_feed("prahadlo_test_batch")).unwrap();
+    let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+    let mut queries = Vec::new();
+    for minutes in 0..20 {
+        let time = datetime + Duration::minutes(minutes);
+        queries.push(ConnectionQuery::departing_at("Alfa", "Gama", time));
+        queries.push(ConnectionQuery::departing_at("Alfa", "Epsilon", time).departing_until(time + Duration::hours(1)));
+        queries.push(ConnectionQuery::arriving_by("Beta", "Epsilon", time + Duration::hours(1)));
+        queries.push(ConnectionQuery::departing_at("Alfa", "Omega", time));
+    }
+    let results = ConnectionQuery::run_batch(&queries, &nw);
+    assert_eq!(results.len(), queries.len());
+    assert_eq!(results[0].as_ref().unwrap().len(), 1);
+    // the sequential queries reuse the labels left behind by the batch
+    for (query, result) in queries.iter().zip(results) {
+        match (query.run(&nw), result) {
+            (Ok(expected), Ok(found)) => {
+                assert_eq!(expected.len(), found.len());
+                for (expected, found) in expected.iter().zip(found.iter()) {
+                    assert_eq!(expected.locations, found.locations);
+                    assert_eq!(expected.times, found.times);
+                }
+            },
+            (Err(Error::StopNotFound(expected)), Err(Error::StopNotFound(found))) => assert_eq!(expected, found),
+            _ => panic!("the batch and the sequential query differ"),
+        }
+    }
+}
+
+#[test]
+fn test_engines_built_by_their_first_search() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_lazy_engines")).unwrap();
+    let (alfa, epsilon) = (String::from("Alfa"), String::from("Epsilon"));
+    assert!(nw.graph.get().is_none() && nw.timetable.get().is_none() && nw.raptor_routes.get().is_none());
+
+    // the scan does not need the nodes
+    nw.find_connection_by_scan(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50)).unwrap().unwrap();
+    assert!(nw.graph.get().is_none() && nw.timetable.get().is_some() && nw.raptor_routes.get().is_none());
+    nw.find_connection_by_raptor(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50), None).unwrap().unwrap();
+    assert!(nw.graph.get().is_none() && nw.raptor_routes.get().is_some());
+    let mut snapshot = Vec::new();
+    nw.write_snapshot(&mut snapshot).unwrap();
+    assert!(Network::read_snapshot(&mut snapshot.as_slice()).unwrap().graph.get().is_none());
+
+    nw.find_connection(&alfa, &epsilon, datetime(2020, 6, 10, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert!(nw.graph.get().is_some());
+    let mut snapshot = Vec::new();
+    nw.write_snapshot(&mut snapshot).unwrap();
+    let loaded = Network::read_snapshot(&mut snapshot.as_slice()).unwrap();
+    assert_eq!(loaded.graph.get().map(|graph| graph.nodes.len()), Some(nw.graph().nodes.len()));
+}
+
+#[test]
+fn test_pickup_and_drop_off_types() {
+    // the bus does not take passengers at Beta and the tram only stops there on request
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_pickup_types", &[
+        FeedChange::Replace("stop_times.txt", "T1,08:10:00,08:10:00,U2Z1,2,,0,0,", "T1,08:10:00,08:10:00,U2Z1,2,,0,3,"),
+        FeedChange::Replace("stop_times.txt", "T2,08:15:00,08:15:00,U2Z1,1,,0,0,", "T2,08:15:00,08:15:00,U2Z1,1,,1,0,"),
+    ])).unwrap();
+
+    let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+    let (alfa, beta, epsilon) = (String::from("Alfa"), String::from("Beta"), String::from("Epsilon"));
+    let metro_arrival = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(8, 31, 0).unwrap();
+    let by_graph = nw.find_connection(&alfa, &epsilon, datetime, &SearchProfile::default()).unwrap().unwrap();
+    let by_scan = nw.find_connection_by_scan(&alfa, &epsilon, datetime).unwrap().unwrap();
+    let by_raptor = nw.find_connection_by_raptor(&alfa, &epsilon, datetime, None).unwrap().unwrap();
+    for conn in [&by_graph, &by_scan, &by_raptor] {
+        assert_eq!(*conn.times.last().unwrap(), metro_arrival);
+        assert_eq!(conn.transfers, 0);
+    }
+    assert!(nw.find_departures(&beta, datetime, 10).unwrap().iter().all(|departure| departure.trip.trip_id != "T2"));
+
+    let conn = nw.find_connection(&alfa, &beta, datetime, &SearchProfile::default()).unwrap().unwrap();
+    let getting_off = conn.locations.iter().rev().find_map(|location| match *location {
+        Location::Trip(trip, _, _, position) => Some(nw.get_stop_time(trip, position)),
+        Location::Stop(_) => None,
+    });
+    assert!(getting_off.unwrap().is_getting_off_on_request());
+}
+
+#[test]
+fn test_loop_trips() {
+    // the bus goes from Beta to Epsilon and back, getting off on the way back is on request
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_loop_trips", &[
+        FeedChange::Add("trips.txt", "L2,daily,T5,Beta,,0,,,1,1,0,1"),
+        FeedChange::Add("stop_times.txt", "T5,09:00:00,09:00:00,U2Z1,1,,0,0,"),
+        FeedChange::Add("stop_times.txt", "T5,09:05:00,09:05:00,U5Z1,2,,0,0,"),
+        FeedChange::Add("stop_times.txt", "T5,09:10:00,09:10:00,U2Z1,3,,1,3,"),
+    ])).unwrap();
+
+    let (beta, epsilon) = (String::from("Beta"), String::from("Epsilon"));
+    let by_graph = nw.find_connection(&epsilon, &beta, datetime(2020, 4, 14, 9, 0), &SearchProfile::default()).unwrap().unwrap();
+    let by_scan = nw.find_connection_by_scan(&epsilon, &beta, datetime(2020, 4, 14, 9, 0)).unwrap().unwrap();
+    let by_raptor = nw.find_connection_by_raptor(&epsilon, &beta, datetime(2020, 4, 14, 9, 0), None).unwrap().unwrap();
+    for conn in [&by_graph, &by_scan, &by_raptor] {
+        let getting_off = conn.locations.iter().rev().find_map(|location| match *location {
+            Location::Trip(trip, _, _, position) => Some((position, nw.get_stop_time(trip, position))),
+            Location::Stop(_) => None,
+        });
+        let (position, stop_time) = getting_off.unwrap();
+        assert_eq!(position, 2);
+        assert!(stop_time.is_getting_off_on_request());
+        assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 9, 11));
+    }
+}
+
+#[test]
+fn test_stay_seated_transfers() {
+    let path = write_test_feed("prahadlo_test_blocks");
+    // the tram of line 1 continues from Gama to Delta as line 4, sooner than the passengers could transfer
+    let trips = std::fs::read_to_string(path.join("trips.txt")).unwrap()
+        .replace("L1,daily,T1,Gama,,0,,", "L1,daily,T1,Gama,,0,B1,")
+        + "L4,daily,T4,Delta,,0,B1,,1,1,0,1\n";
+    let routes = std::fs::read_to_string(path.join("routes.txt")).unwrap() + "L4,99,4,Gama - Delta,0,,,,0\n";
+    let stop_times = std::fs::read_to_string(path.join("stop_times.txt")).unwrap()
+        + "T4,08:20:30,08:20:30,U3Z1,1,,0,0,\nT4,08:24:00,08:24:00,U4Z1,2,,0,0,\n";
+    std::fs::write(path.join("trips.txt"), trips).unwrap();
+    std::fs::write(path.join("routes.txt"), routes).unwrap();
+    std::fs::write(path.join("stop_times.txt"), stop_times).unwrap();
+    let nw = Network::new(&path).unwrap();
+    let (t1, t4) = (nw.trip_indices["T1"], nw.trip_indices["T4"]);
+    assert_eq!(nw.get_block_successor(t1), Some(t4));
+    assert_eq!(nw.get_block_successor(t4), None);
+
+    let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 55, 0).unwrap();
+    let (alfa, delta) = (String::from("Alfa"), String::from("Delta"));
+    let by_graph = nw.find_connection(&alfa, &delta, datetime, &SearchProfile::default()).unwrap().unwrap();
+    let by_scan = nw.find_connection_by_scan(&alfa, &delta, datetime).unwrap().unwrap();
+    // staying seated is not a transfer, so raptor finds it in the first round
+    let by_raptor = nw.find_connection_by_raptor(&alfa, &delta, datetime, Some(0)).unwrap().unwrap();
+    for conn in [&by_graph, &by_scan, &by_raptor] {
+        assert_eq!(*conn.times.last().unwrap(), NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(8, 25, 0).unwrap());
+        assert_eq!(conn.transfers, 0);
+    }
+    for conn in [&by_graph, &by_raptor] {
+        assert!(conn.locations.windows(2).any(|pair| match (pair[0], pair[1]) {
+            (Location::Trip(from, _, _, _), Location::Trip(to, _, _, _)) => from == t1 && to == t4,
+            _ => false,
+        }));
+    }
+}
+
+#[test]
+fn test_frequencies() {
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_network_frequencies", &[
+        FeedChange::Add("frequencies.txt", "trip_id,start_time,end_time,headway_secs,exact_times"),
+        FeedChange::Add("frequencies.txt", "T3,08:00:00,09:00:00,600,1"),
+        FeedChange::Add("frequencies.txt", "T2,08:00:00,08:30:00,900,0"),
+    ])).unwrap();
+    let queries = [
+        // the metro now leaves Alfa at 08:00 and beats the transfer to the bus
+        ("Alfa", "Epsilon", datetime(2020, 4, 14, 7, 50), "T3@08:00:00", datetime(2020, 4, 14, 8, 26)),
+        // the bus expected to come half of the headway after 08:15
+        ("Beta", "Epsilon", datetime(2020, 4, 14, 8, 12), "T2@08:15:00", datetime(2020, 4, 14, 8, 33) + Duration::seconds(30)),
+    ];
+    for (from, to, datetime, trip, arrival) in queries.iter() {
+        let (from, to) = (String::from(*from), String::from(*to));
+        let by_graph = nw.find_connection(&from, &to, *datetime, &SearchProfile::default()).unwrap().unwrap();
+        let by_scan = nw.find_connection_by_scan(&from, &to, *datetime).unwrap().unwrap();
+        let by_raptor = nw.find_connection_by_raptor(&from, &to, *datetime, None).unwrap().unwrap();
+        for conn in [&by_graph, &by_scan, &by_raptor] {
+            assert_eq!(get_ridden_trips(&nw, conn), vec![*trip], "{} -> {}", from, to);
+            assert_eq!(conn.times.last().unwrap(), arrival, "{} -> {}", from, to);
+        }
+    }
+    // the passenger has to be at the stop when the bus is expected at the earliest
+    let conn = nw.find_connection(&String::from("Beta"), &String::from("Epsilon"), datetime(2020, 4, 14, 8, 12), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ride_times(&conn).0, datetime(2020, 4, 14, 8, 22) + Duration::seconds(30));
+    let boarding = conn.locations.iter().position(|location| matches!(location, Location::Trip(..))).unwrap();
+    assert_eq!(conn.times[boarding - 1], datetime(2020, 4, 14, 8, 15));
+
+    // the transfer rules naming the trip apply to all of its instances
+    let with_rule = |name: &str, headway: &str, rule: &str| Network::new(&write_test_feed_with(name, &[
+        FeedChange::Add("frequencies.txt", "trip_id,start_time,end_time,headway_secs,exact_times"),
+        FeedChange::Add("frequencies.txt", headway),
+        FeedChange::Add("transfers.txt", "from_stop_id,to_stop_id,transfer_type,min_transfer_time,from_trip_id,to_trip_id"),
+        FeedChange::Add("transfers.txt", rule),
+    ])).unwrap();
+    let (alfa, epsilon) = (String::from("Alfa"), String::from("Epsilon"));
+    // the transfer from the tram to the bus is not possible, the metro is left
+    let nw = with_rule("prahadlo_test_frequencies_forbidden", "T2,08:00:00,08:30:00,900,1", "U2Z1,U2Z1,3,,T1,T2");
+    let conn = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &conn), vec!["T3"]);
+    assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 8, 31));
+    // the bus leaving Beta at 08:10:30 waits for the tram, it could not be caught otherwise
+    let nw = with_rule("prahadlo_test_frequencies_timed", "T2,08:00:00,08:30:00,630,1", "U2Z1,U2Z1,1,,T1,T2");
+    let by_graph = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    let by_scan = nw.find_connection_by_scan(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50)).unwrap().unwrap();
+    for conn in [&by_graph, &by_scan] {
+        assert_eq!(get_ridden_trips(&nw, conn), vec!["T1", "T2@08:10:30"]);
+        assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 8, 21) + Duration::seconds(30));
+    }
+    let nw = with_rule("prahadlo_test_frequencies_recommended", "T2,08:00:00,08:30:00,630,1", "U2Z1,U2Z1,0,,T1,T2");
+    let conn = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &conn), vec!["T3"]);
+    assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 8, 31));
+}
+
+#[test]
+fn test_wheelchair_profile() {
+    // the bus cannot take a wheelchair
+    let bus_inaccessible = FeedChange::Replace("trips.txt", "L2,daily,T2,Epsilon,,0,,,1,1,0,1", "L2,daily,T2,Epsilon,,0,,,2,1,0,1");
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_wheelchair", &[bus_inaccessible])).unwrap();
+    let (alfa, epsilon) = (String::from("Alfa"), String::from("Epsilon"));
+    let by_bus = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &by_bus), vec!["T1", "T2"]);
+    assert_eq!(*by_bus.times.last().unwrap(), datetime(2020, 4, 14, 8, 26));
+    let by_metro = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::wheelchair()).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &by_metro), vec!["T3"]);
+    assert_eq!(get_ride_times(&by_metro), (datetime(2020, 4, 14, 8, 5), datetime(2020, 4, 14, 8, 30)));
+    assert_eq!(*by_metro.times.last().unwrap(), datetime(2020, 4, 14, 8, 31));
+
+    // nor can the metro station at Epsilon
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_wheelchair_station", &[
+        bus_inaccessible,
+        FeedChange::Replace("stops.txt", "U5Z1,Epsilon,50.1000,14.4300,P,,0,,1,,", "U5Z1,Epsilon,50.1000,14.4300,P,,0,,2,,"),
+    ])).unwrap();
+    assert!(nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::wheelchair()).unwrap().is_none());
+}
+
+#[test]
+fn test_bicycle_profile() {
+    // the bus does not take bikes, but Epsilon is close enough to Beta by bike
+    let bus_without_bikes = FeedChange::Replace("trips.txt", "L2,daily,T2,Epsilon,,0,,,1,1,0,1", "L2,daily,T2,Epsilon,,0,,,1,2,0,1");
+    let nw = Network::new(&write_test_feed_with("prahadlo_test_bicycle", &[bus_without_bikes])).unwrap();
+    let (alfa, epsilon) = (String::from("Alfa"), String::from("Epsilon"));
+    let full_radius = SearchProfile::bicycle(DEFAULT_CYCLING_SPEED, MAX_CYCLING_DIST);
+    let short_radius = SearchProfile::bicycle(DEFAULT_CYCLING_SPEED, 1000.0);
+    let by_bike = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &full_radius).unwrap().unwrap();
+    // riding through Beta beats the tram, it arrives at Epsilon when the graph is there again, as the bus stops there
+    assert_eq!(by_bike.locations, vec![Location::Stop(0), Location::Stop(1), Location::Stop(4)]);
+    assert_eq!(by_bike.times, vec![datetime(2020, 4, 14, 8, 0), datetime(2020, 4, 14, 8, 10), datetime(2020, 4, 14, 8, 25)]);
+    assert!(by_bike.walking_distance > MAX_PEDESTRIAN_DIST);
+
+    // within a shorter radius, only the metro is left
+    let by_metro = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &short_radius).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &by_metro), vec!["T3"]);
+    assert_eq!(*by_metro.times.last().unwrap(), datetime(2020, 4, 14, 8, 31));
+    assert_eq!(by_metro.walking_distance, 0.0);
+    // riding all the way is the latest departure
+    let by_bike = nw.find_connection_arriving_by(&alfa, &epsilon, datetime(2020, 4, 14, 8, 28), &full_radius).unwrap().unwrap();
+    assert!(get_ridden_trips(&nw, &by_bike).is_empty());
+    assert_eq!((by_bike.times[0], *by_bike.times.last().unwrap()), (datetime(2020, 4, 14, 8, 6), datetime(2020, 4, 14, 8, 26)));
+
+    // the stop-level transfer rules apply to the rides as well
+    let with_transfer = |name: &str, rule: &str| Network::new(&write_test_feed_with(name, &[
+        bus_without_bikes,
+        FeedChange::Add("transfers.txt", "from_stop_id,to_stop_id,transfer_type,min_transfer_time"),
+        FeedChange::Add("transfers.txt", rule),
+    ])).unwrap();
+    let nw = with_transfer("prahadlo_test_bicycle_forbidden", "U2Z1,U5Z1,3,");
+    let conn = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &full_radius).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &conn), vec!["T3"]);
+    assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 8, 31));
+    // riding from Beta is forbidden in backward searches too, the metro of the day before is left
+    let conn = nw.find_connection_arriving_by(&alfa, &epsilon, datetime(2020, 4, 14, 8, 28), &full_radius).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &conn), vec!["T3"]);
+    assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 13, 8, 31));
+    // a minimum time replaces the ride, even between stops out of the radius
+    let nw = with_transfer("prahadlo_test_bicycle_minimum", "U1Z1,U5Z1,2,600");
+    let conn = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &short_radius).unwrap().unwrap();
+    assert_eq!(conn.locations, vec![Location::Stop(0), Location::Stop(4)]);
+    assert_eq!(conn.times, vec![datetime(2020, 4, 14, 8, 0), datetime(2020, 4, 14, 8, 25)]);
+    let nw = with_transfer("prahadlo_test_bicycle_long_minimum", "U1Z1,U5Z1,2,3600");
+    let conn = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &short_radius).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &conn), vec!["T3"]);
+    assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 8, 31));
+}
+
+#[test]
+fn test_route_filters() {
+    use crate::query::{ConnectionQuery, Engine};
+
+    let nw = Network::new(&write_test_feed("prahadlo_test_route_filters")).unwrap();
+    let trips_and_arrival = |routes: RouteFilter| {
+        let conn = ConnectionQuery::departing_at("Alfa", "Epsilon", datetime(2020, 4, 14, 7, 50)).routes(routes).run(&nw).unwrap();
+        conn.first().map(|conn| (get_ridden_trips(&nw, conn), *conn.times.last().unwrap()))
+    };
+    let by_bus = Some((vec!["T1", "T2"], datetime(2020, 4, 14, 8, 26)));
+    let by_metro = Some((vec!["T3"], datetime(2020, 4, 14, 8, 31)));
+    assert_eq!(trips_and_arrival(RouteFilter::default()), by_bus);
+    assert_eq!(trips_and_arrival(RouteFilter { exclude_route_types: vec![3], ..Default::default() }), by_metro);
+    assert_eq!(trips_and_arrival(RouteFilter { include_route_types: vec![0, 3], ..Default::default() }), by_bus);
+    assert_eq!(trips_and_arrival(RouteFilter { exclude_lines: vec![String::from("2")], ..Default::default() }), by_metro);
+    assert_eq!(trips_and_arrival(RouteFilter { include_lines: vec![String::from("1"), String::from("A")], ..Default::default() }), by_metro);
+    assert_eq!(trips_and_arrival(RouteFilter { exclude_route_types: vec![1, 3], ..Default::default() }), None);
+    assert_eq!(trips_and_arrival(RouteFilter { exclude_agencies: vec![String::from("99")], ..Default::default() }), None);
+
+    let routes = RouteFilter { exclude_route_types: vec![3], ..Default::default() };
+    let by_scan = ConnectionQuery::departing_at("Alfa", "Epsilon", datetime(2020, 4, 14, 7, 50)).engine(Engine::ConnectionScan).routes(routes).run(&nw);
+    assert!(matches!(by_scan, Err(Error::UnsupportedQuery(_))));
+}
+
+#[test]
+fn test_via_stop() {
+    use crate::query::ConnectionQuery;
+
+    let nw = Network::new(&write_test_feed("prahadlo_test_via")).unwrap();
+    let (alfa, beta, epsilon) = (String::from("Alfa"), String::from("Beta"), String::from("Epsilon"));
+    let conn = nw.find_connection_via(&alfa, &beta, &epsilon, datetime(2020, 4, 14, 7, 50), Duration::zero(), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(get_ridden_trips(&nw, &conn), vec!["T1", "T2"]);
+    assert_eq!(*conn.times.last().unwrap(), datetime(2020, 4, 14, 8, 26));
+    assert_eq!(conn.transfers, 1);
+    assert_eq!(conn.segments.len(), 2);
+    assert_eq!(conn.segments[0].end, conn.segments[1].start);
+    assert_eq!(conn.segments[1].end, conn.locations.len());
+    let via = conn.locations[conn.segments[1].start];
+    assert_eq!(nw.get_stop_by_index(via.get_stop()).stop_name, "Beta");
+    assert!(conn.times[conn.segments[1].start] >= conn.times[conn.segments[0].end - 1]);
+
+    // the stay starts when the tram arrives at 08:10, so five minutes at Beta still catch the bus at 08:15
+    let query = ConnectionQuery::departing_at("Alfa", "Epsilon", datetime(2020, 4, 14, 7, 50)).via("Beta", Duration::minutes(5));
+    let conn = query.run(&nw).unwrap();
+    // the times of the first boarding and the last getting off within the segment
+    let segment_times = |conn: &Connection, segment: usize| {
+        let ride_times: Vec<NaiveDateTime> = conn.segments[segment].clone()
+            .filter(|index| matches!(conn.locations[*index], Location::Trip(..)))
+            .map(|index| conn.times[index])
+            .collect();
+        (ride_times[0], *ride_times.last().unwrap())
+    };
+    assert_eq!(get_ridden_trips(&nw, &conn[0]), vec!["T1", "T2"]);
+    assert_eq!(segment_times(&conn[0], 0).1, datetime(2020, 4, 14, 8, 10));
+    assert_eq!(segment_times(&conn[0], 1).0, datetime(2020, 4, 14, 8, 15));
+    assert_eq!(*conn[0].times.last().unwrap(), datetime(2020, 4, 14, 8, 26));
+    // a minute more misses it, the next one goes the day after
+    let query = ConnectionQuery::departing_at("Alfa", "Epsilon", datetime(2020, 4, 14, 7, 50)).via("Beta", Duration::minutes(6));
+    let conn = query.run(&nw).unwrap();
+    assert_eq!(segment_times(&conn[0], 1).0, datetime(2020, 4, 15, 8, 15));
+    assert_eq!(*conn[0].times.last().unwrap(), datetime(2020, 4, 15, 8, 26));
+    // a shorter stay than the transfer time keeps the transfer time
+    let conn = nw.find_connection_via(&alfa, &beta, &epsilon, datetime(2020, 4, 14, 7, 50), Duration::seconds(10), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(conn.times[conn.segments[1].start], datetime(2020, 4, 14, 8, 11));
+
+    let conn = nw.find_connection(&alfa, &epsilon, datetime(2020, 4, 14, 7, 50), &SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(conn.segments, vec![0..conn.locations.len()]);
+    for min_dwell in [Duration::minutes(-5), Duration::days(1000)] {
+        let query = ConnectionQuery::departing_at("Alfa", "Epsilon", datetime(2020, 4, 14, 7, 50)).via("Beta", min_dwell);
+        assert!(matches!(query.run(&nw), Err(Error::UnsupportedQuery(_))));
     }
 }
@@ -0,0 +1,331 @@
+//! The Connection Scan Algorithm, another way to answer the earliest arrival queries. Instead of a graph,
+//! it goes once through the elementary connections of all trips, a vehicle going from a stop to the next one,
+//! ordered by their departure.
+
+use super::*;
+
+/// A vehicle of a trip going from a stop to the next one without stopping
+#[derive(Debug)]
+struct ElementaryConnection {
+    trip: TripIndex,
+    // the position of the departure stop in the stop times of the trip
+    position: u32,
+    from: StopIndex,
+    to: StopIndex,
+    departure: u32,
+    arrival: u32,
+    // the passenger has to be at the stop this long before the departure of a trip without exact times
+    wait: u32,
+    // whether the passengers can board at the departure stop and get off at the arrival stop
+    boarding: bool,
+    getting_off: bool,
+}
+
+/// The trips and positions a transfer from a trip at a position leads to, with the minimum time of each
+type TripTransfers = HashMap<(TripIndex, u32), Vec<(TripIndex, u32, u32)>>;
+
+/// The elementary connections of all trips and what the scan needs to know about the stops and trips,
+/// derived from the trips of the network
+#[derive(Debug)]
+pub(super) struct Timetable {
+    // ordered by the departure, then by the arrival
+    connections: Vec<ElementaryConnection>,
+    // the timed and minimum-time transfers between two trips and the stay-seated ones by the trip and the position where they begin,
+    // with the trip and the position where they end and the minimum time
+    trip_transfers: TripTransfers,
+}
+
+impl Timetable {
+    pub(super) fn new(
+        stop_indices: &HashMap<String, StopIndex>,
+        trips: &Vec<Trip>,
+        template_indices: &HashMap<&str, Vec<TripIndex>>,
+        transfer_rules: &TransferRules,
+        block_successors: &Vec<Option<TripIndex>>,
+    ) -> Timetable {
+        let mut connections = Vec::new();
+        for (trip_index, trip) in trips.iter().enumerate() {
+            for (position, pair) in trip.stop_times.windows(2).enumerate() {
+                connections.push(ElementaryConnection {
+                    trip: trip_index as TripIndex,
+                    position: position as u32,
+                    from: stop_indices[&*pair[0].stop_id],
+                    to: stop_indices[&*pair[1].stop_id],
+                    departure: pair[0].departure_time,
+                    arrival: pair[1].arrival_time,
+                    wait: trip.get_expected_wait(),
+                    boarding: pair[0].can_board(),
+                    getting_off: pair[1].can_get_off(),
+                });
+            }
+        }
+        connections.sort_by_key(|connection| (connection.departure, connection.arrival));
+
+        let mut trip_transfers: TripTransfers = HashMap::new();
+        for transfer in transfer_rules.get_timed_trip_transfers() {
+            // a rule naming a trip expanded from frequencies.txt connects all of its instances
+            let find_positions = |trip_id: &String, stop_id: &String, allowed: fn(&StopTime) -> bool| -> Vec<(TripIndex, u32)> {
+                template_indices.get(trip_id.as_str()).into_iter().flatten()
+                    .filter_map(|trip_index| {
+                        let stop_times = &trips[*trip_index as usize].stop_times;
+                        let position = stop_times.iter().position(|stop_time| *stop_time.stop_id == **stop_id)?;
+                        allowed(&stop_times[position]).then_some((*trip_index, position as u32))
+                    })
+                    .collect()
+            };
+            let departure = |(trip, position): (TripIndex, u32)| trips[trip as usize].stop_times[position as usize].departure_time;
+            let transfer_time = if transfer.transfer_type == 2 { transfer.min_transfer_time.unwrap_or(0) } else { 0 };
+            let to_positions = find_positions(transfer.to_trip_id.as_ref().unwrap(), &transfer.to_stop_id, StopTime::can_board);
+            for from in find_positions(transfer.from_trip_id.as_ref().unwrap(), &transfer.from_stop_id, StopTime::can_get_off) {
+                for (to_trip, to_position) in to_positions.iter().cloned() {
+                    if departure(from) + transfer_time <= departure((to_trip, to_position)) {
+                        trip_transfers.entry(from).or_insert_with(Vec::new).push((to_trip, to_position, transfer_time));
+                    }
+                }
+            }
+        }
+        // the passengers stay seated when the vehicle continues as the next trip of its block
+        for (trip_index, successor) in block_successors.iter().enumerate() {
+            if let Some(successor) = successor {
+                let last = trips[trip_index].stop_times.len() as u32 - 1;
+                trip_transfers.entry((trip_index as TripIndex, last)).or_insert_with(Vec::new).push((*successor, 0, 0));
+            }
+        }
+
+        Timetable {
+            connections: connections,
+            trip_transfers: trip_transfers,
+        }
+    }
+}
+
+/// How the scan reached a stop
+#[derive(Debug, Clone, Copy)]
+enum StopLabel {
+    Unreached,
+    Start,
+    /// getting off the trip boarded in the trip state at the position in its stop times
+    Ride { trip_state: usize, position: u32 },
+    Walk { from: StopIndex, departure: i64 },
+}
+
+/// Where a trip was boarded on a service day
+#[derive(Debug, Clone, Copy)]
+enum Boarding {
+    Stop { position: u32 },
+    /// a transfer from the trip state at the position in its stop times, which does not need the minimal transfer time
+    Transfer { position: u32, from_state: usize, from_position: u32 },
+}
+
+/// The labels of a single scan. The trips are scanned on every searched service day, a trip state
+/// is the index of the trip multiplied by SEARCH_DAYS plus the day.
+struct ScanState {
+    times: Vec<i64>,
+    labels: Vec<StopLabel>,
+    boardings: Vec<Option<Boarding>>,
+    // whether the service operates on a searched day, by the service state computed like the trip state
+    operates: Vec<Option<bool>>,
+}
+
+impl Network {
+    /// Finds the connection with the earliest arrival that departs at @datetime or later by scanning the
+    /// elementary connections. The walks are not bound to the times at which vehicles stop like in the graph,
+    /// so a connection ending with a walk can arrive a bit earlier than the one found by find_connection.
+    pub fn find_connection_by_scan(
+        &self,
+        dep_stop_name: &String,
+        dest_stop_name: &String,
+        datetime: NaiveDateTime,
+    ) -> Result<Option<Connection>> {
+        let days = self.get_search_days(&datetime);
+        let query_time = self.get_timestamp(&datetime);
+        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or_else(|| Error::StopNotFound(dep_stop_name.clone()))?;
+        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or_else(|| Error::StopNotFound(dest_stop_name.clone()))?;
+
+        let timetable = self.timetable();
+        let mut scan = ScanState {
+            times: vec![i64::MAX; self.stops.len()],
+            labels: vec![StopLabel::Unreached; self.stops.len()],
+            boardings: vec![None; self.trips.len() * SEARCH_DAYS],
+            operates: vec![None; self.services.len() * SEARCH_DAYS],
+        };
+        for stop in &start_stop_group.stops {
+            scan.times[*stop as usize] = query_time;
+            scan.labels[*stop as usize] = StopLabel::Start;
+        }
+        for stop in &start_stop_group.stops {
+            self.relax_walks(&mut scan, *stop);
+        }
+
+        // the connections of the searched days are merged by their departure
+        let mut cursors: Vec<usize> = (0..SEARCH_DAYS)
+            .map(|day| timetable.connections.partition_point(|connection| days.starts[day] + (connection.departure as i64) < query_time))
+            .collect();
+        loop {
+            let next = (0..SEARCH_DAYS)
+                .filter(|day| cursors[*day] < timetable.connections.len())
+                .min_by_key(|day| days.starts[*day] + timetable.connections[cursors[*day]].departure as i64);
+            let day = match next {
+                Some(day) => day,
+                None => break,
+            };
+            let connection = &timetable.connections[cursors[day]];
+            cursors[day] += 1;
+            let departure = days.starts[day] + connection.departure as i64;
+            let best_arrival = dest_stop_group.stops.iter().map(|stop| scan.times[*stop as usize]).min().unwrap_or(i64::MAX);
+            if departure >= best_arrival {
+                break;
+            }
+            if !self.does_service_operate_on_day(&mut scan.operates, &days, self.trip_services[connection.trip as usize], day) {
+                continue;
+            }
+
+            let trip_state = connection.trip as usize * SEARCH_DAYS + day;
+            if scan.boardings[trip_state].is_none() {
+                if !connection.boarding || scan.times[connection.from as usize] > departure - connection.wait as i64
+                    || !self.can_board_by_scan(&scan, &days, connection.from, connection.trip, departure) {
+                    continue;
+                }
+                scan.boardings[trip_state] = Some(Boarding::Stop { position: connection.position });
+            }
+
+            let arrival = days.starts[day] + (connection.arrival + self.transfer_times[connection.to as usize]) as i64;
+            if connection.getting_off && arrival < scan.times[connection.to as usize] {
+                scan.times[connection.to as usize] = arrival;
+                scan.labels[connection.to as usize] = StopLabel::Ride { trip_state: trip_state, position: connection.position + 1 };
+                self.relax_walks(&mut scan, connection.to);
+            }
+            if let Some(transfers) = timetable.trip_transfers.get(&(connection.trip, connection.position + 1)) {
+                let from_time = self.trips[connection.trip as usize].stop_times[connection.position as usize + 1].departure_time;
+                for (to_trip, to_position, transfer_time) in transfers {
+                    let to_state = *to_trip as usize * SEARCH_DAYS + day;
+                    let to_time = self.trips[*to_trip as usize].stop_times[*to_position as usize].departure_time;
+                    if scan.boardings[to_state].is_none() && from_time + transfer_time <= to_time
+                        && self.does_service_operate_on_day(&mut scan.operates, &days, self.trip_services[*to_trip as usize], day) {
+                        scan.boardings[to_state] = Some(Boarding::Transfer {
+                            position: *to_position,
+                            from_state: trip_state,
+                            from_position: connection.position + 1,
+                        });
+                    }
+                }
+            }
+        }
+
+        let found = dest_stop_group.stops.iter()
+            .filter(|stop| scan.times[**stop as usize] != i64::MAX)
+            .min_by_key(|stop| (scan.times[**stop as usize], **stop));
+        Ok(found.map(|found| {
+            let mut journey = Vec::new();
+            self.push_scanned_stop(&scan, &days, *found, &mut journey);
+            let (locations, times) = journey.into_iter().map(|(location, time)| (location, self.get_local_time(time))).unzip();
+            self.create_connection(locations, times)
+        }))
+    }
+
+    /// Walks from a stop to its neighbours right after reaching it. The pedestrian connections already lead
+    /// to every stop in the walking distance, so the scan never walks twice in a row.
+    fn relax_walks(&self, scan: &mut ScanState, stop: StopIndex) {
+        let departure = scan.times[stop as usize];
+        for (neighbouring_stop, travel_time) in &self.pedestrian_connections[stop as usize] {
+            let arrival = departure + *travel_time as i64;
+            if arrival < scan.times[*neighbouring_stop as usize] {
+                scan.times[*neighbouring_stop as usize] = arrival;
+                scan.labels[*neighbouring_stop as usize] = StopLabel::Walk { from: stop, departure: departure };
+            }
+        }
+    }
+
+    /// Checks the rules from transfers.txt that depend on the trip the passenger got off before boarding @trip
+    /// at @stop. The rules are checked against the way the stop was reached with the earliest arrival.
+    fn can_board_by_scan(&self, scan: &ScanState, days: &SearchDays, stop: StopIndex, trip: TripIndex, departure: i64) -> bool {
+        let alighting = match scan.labels[stop as usize] {
+            StopLabel::Ride { trip_state, position } => Some((trip_state, position, stop)),
+            StopLabel::Walk { from, .. } => match scan.labels[from as usize] {
+                StopLabel::Ride { trip_state, position } => Some((trip_state, position, from)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let (trip_state, position, from_stop) = match alighting {
+            Some(alighting) => alighting,
+            None => return true,
+        };
+        let from_trip = (trip_state / SEARCH_DAYS) as TripIndex;
+        let from_time = days.starts[trip_state % SEARCH_DAYS]
+            + self.trips[from_trip as usize].stop_times[position as usize].departure_time as i64;
+        self.is_boarding_allowed((from_stop, from_trip, from_time), (stop, trip, departure))
+    }
+
+    /// Appends the locations of the journey to @stop in the same form as the graph search builds them
+    fn push_scanned_stop(&self, scan: &ScanState, days: &SearchDays, stop: StopIndex, journey: &mut Vec<(Location, i64)>) {
+        match scan.labels[stop as usize] {
+            StopLabel::Unreached | StopLabel::Start => (),
+            StopLabel::Walk { from, departure } => {
+                self.push_scanned_stop(scan, days, from, journey);
+                if journey.last().is_none_or(|(_, time)| *time != departure) {
+                    journey.push((Location::Stop(from), departure));
+                }
+            },
+            StopLabel::Ride { trip_state, position } => self.push_scanned_ride(scan, days, trip_state, position, journey),
+        }
+        journey.push((Location::Stop(stop), scan.times[stop as usize]));
+    }
+
+    /// Appends the locations of the ride on the trip state up to the stop at @position
+    fn push_scanned_ride(&self, scan: &ScanState, days: &SearchDays, trip_state: usize, position: u32, journey: &mut Vec<(Location, i64)>) {
+        let trip = (trip_state / SEARCH_DAYS) as TripIndex;
+        let day_start = days.starts[trip_state % SEARCH_DAYS];
+        let stop_times = &self.trips[trip as usize].stop_times;
+        let first = match scan.boardings[trip_state].unwrap() {
+            Boarding::Stop { position } => {
+                let stop = self.stop_indices[&*stop_times[position as usize].stop_id];
+                let departure = day_start + (stop_times[position as usize].departure_time - self.trips[trip as usize].get_expected_wait()) as i64;
+                self.push_scanned_stop(scan, days, stop, journey);
+                if journey.last().is_none_or(|(_, time)| *time != departure) {
+                    journey.push((Location::Stop(stop), departure));
+                }
+                position
+            },
+            Boarding::Transfer { position, from_state, from_position } => {
+                self.push_scanned_ride(scan, days, from_state, from_position, journey);
+                position
+            },
+        };
+        let service = self.trip_services[trip as usize];
+        for (stop_position, stop_time) in stop_times.iter().enumerate().take(position as usize + 1).skip(first as usize) {
+            let location = Location::Trip(trip, service, self.stop_indices[&*stop_time.stop_id], stop_position as u32);
+            journey.push((location, day_start + stop_time.departure_time as i64));
+        }
+    }
+}
+
+#[test]
+fn test_engines_agree() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_csa")).unwrap();
+    let stops = ["Alfa", "Beta", "Gama", "Epsilon"];
+    let mut found = 0;
+    for minutes in (0..60).step_by(5) {
+        let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 30, 0).unwrap() + Duration::minutes(minutes);
+        for from in &stops {
+            for to in stops.iter().filter(|to| *to != from) {
+                let (from, to) = (String::from(*from), String::from(*to));
+                let by_graph = nw.find_connection(&from, &to, datetime, &SearchProfile::default()).unwrap();
+                let by_scan = nw.find_connection_by_scan(&from, &to, datetime).unwrap();
+                assert_eq!(by_graph.as_ref().map(|conn| *conn.times.last().unwrap()), by_scan.as_ref().map(|conn| *conn.times.last().unwrap()),
+                    "{} -> {} at {}", from, to, datetime);
+                if let (Some(by_graph), Some(by_scan)) = (by_graph, by_scan) {
+                    assert_eq!(by_graph.transfers, by_scan.transfers);
+                    found += 1;
+                }
+            }
+        }
+    }
+    assert!(found > 0);
+
+    // changing at Beta to the bus arrives earlier than the direct metro
+    let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+    let conn = nw.find_connection_by_scan(&String::from("Alfa"), &String::from("Epsilon"), datetime).unwrap().unwrap();
+    assert_eq!(*conn.times.last().unwrap(), NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(8, 26, 0).unwrap());
+    assert_eq!(conn.transfers, 1);
+}
@@ -0,0 +1,333 @@
+//! RAPTOR (Round-bAsed Public Transit Optimized Router), a third engine for the earliest arrival queries.
+//! It works in rounds, round k finds the earliest arrivals using k vehicles, so it can limit the transfers.
+
+use super::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Trips with the same sequence of stops, none of them overtaking another
+#[derive(Debug)]
+struct RaptorRoute {
+    stops: Vec<StopIndex>,
+    // ordered by the departure, which is then the order at every stop
+    trips: Vec<TripIndex>,
+}
+
+/// The routes the rounds go through, derived from the trips of the network
+#[derive(Debug)]
+pub(super) struct RaptorRoutes {
+    routes: Vec<RaptorRoute>,
+    // the routes passing through every stop with the position of the stop in them
+    stop_routes: Vec<Vec<(usize, u32)>>,
+}
+
+impl RaptorRoutes {
+    /// Groups the trips by their stops. A trip that would overtake another one of its group gets a route of its own,
+    /// as the rounds rely on the trips of a route keeping their order.
+    pub(super) fn new(stop_indices: &HashMap<String, StopIndex>, trips: &Vec<Trip>) -> RaptorRoutes {
+        let mut by_stops: HashMap<Vec<StopIndex>, Vec<TripIndex>> = HashMap::new();
+        for (trip_index, trip) in trips.iter().enumerate() {
+            if trip.stop_times.len() < 2 {
+                continue;
+            }
+            let stops = trip.stop_times.iter().map(|stop_time| stop_indices[&*stop_time.stop_id]).collect();
+            by_stops.entry(stops).or_insert_with(Vec::new).push(trip_index as TripIndex);
+        }
+        let mut groups: Vec<(Vec<StopIndex>, Vec<TripIndex>)> = by_stops.into_iter().collect();
+        groups.sort();
+
+        let overtakes = |trip: TripIndex, previous: TripIndex| {
+            let (stop_times, previous_stop_times) = (&trips[trip as usize].stop_times, &trips[previous as usize].stop_times);
+            stop_times.iter().zip(previous_stop_times).any(|(stop_time, previous)| {
+                stop_time.arrival_time < previous.arrival_time || stop_time.departure_time < previous.departure_time
+            })
+        };
+        let mut routes: Vec<RaptorRoute> = Vec::new();
+        for (stops, mut group) in groups {
+            group.sort_by_key(|trip| (trips[*trip as usize].stop_times[0].departure_time, *trip));
+            let first_route = routes.len();
+            for trip in group {
+                let route = routes[first_route..].iter_mut().find(|route| !overtakes(trip, *route.trips.last().unwrap()));
+                match route {
+                    Some(route) => route.trips.push(trip),
+                    None => routes.push(RaptorRoute { stops: stops.clone(), trips: vec![trip] }),
+                }
+            }
+        }
+
+        let mut stop_routes = vec![Vec::new(); stop_indices.len()];
+        for (route_index, route) in routes.iter().enumerate() {
+            for (position, stop) in route.stops.iter().enumerate() {
+                stop_routes[*stop as usize].push((route_index, position as u32));
+            }
+        }
+        RaptorRoutes { routes: routes, stop_routes: stop_routes }
+    }
+}
+
+/// How a round reached a stop, along with the round that created the label
+#[derive(Debug, Clone, Copy)]
+enum RoundLabel {
+    Unreached,
+    Start,
+    /// riding on the searched day from a position in the stop times of @boarded to a position in those of @trip,
+    /// which is either the same trip or one the vehicle continues as
+    Ride { round: usize, boarded: TripIndex, trip: TripIndex, day: usize, from: u32, to: u32 },
+    Walk { round: usize, from: StopIndex, departure: i64 },
+}
+
+/// The labels of all the rounds of a query, every round starts with a copy of the previous one
+struct RaptorState {
+    times: Vec<Vec<i64>>,
+    labels: Vec<Vec<RoundLabel>>,
+    // the earliest arrival at every stop in any round so far
+    best: Vec<i64>,
+    operates: Vec<Option<bool>>,
+}
+
+impl Network {
+    /// Finds the connection with the earliest arrival that departs at @datetime or later and uses at most
+    /// @max_transfers transfers between vehicles, or any number of them without the limit. Among the connections
+    /// with the earliest arrival, it returns the one with the fewest transfers.
+    pub fn find_connection_by_raptor(
+        &self,
+        dep_stop_name: &String,
+        dest_stop_name: &String,
+        datetime: NaiveDateTime,
+        max_transfers: Option<u32>,
+    ) -> Result<Option<Connection>> {
+        let days = self.get_search_days(&datetime);
+        let query_time = self.get_timestamp(&datetime);
+        let start_stop_group = self.get_stop_group_by_name(dep_stop_name).ok_or_else(|| Error::StopNotFound(dep_stop_name.clone()))?;
+        let dest_stop_group = self.get_stop_group_by_name(dest_stop_name).ok_or_else(|| Error::StopNotFound(dest_stop_name.clone()))?;
+        let raptor_routes = self.raptor_routes();
+
+        let mut state = RaptorState {
+            times: vec![vec![i64::MAX; self.stops.len()]],
+            labels: vec![vec![RoundLabel::Unreached; self.stops.len()]],
+            best: vec![i64::MAX; self.stops.len()],
+            operates: vec![None; self.services.len() * SEARCH_DAYS],
+        };
+        let mut marked: BTreeSet<StopIndex> = BTreeSet::new();
+        for stop in &start_stop_group.stops {
+            state.times[0][*stop as usize] = query_time;
+            state.labels[0][*stop as usize] = RoundLabel::Start;
+            state.best[*stop as usize] = query_time;
+            marked.insert(*stop);
+        }
+        marked.extend(self.relax_round_walks(&mut state, 0, &marked.clone()));
+
+        let rounds = max_transfers.map_or(usize::MAX, |max_transfers| max_transfers as usize + 1);
+        let mut round = 0;
+        while !marked.is_empty() && round < rounds {
+            round += 1;
+            let times = state.times[round - 1].clone();
+            let labels = state.labels[round - 1].clone();
+            state.times.push(times);
+            state.labels.push(labels);
+
+            // every route is scanned once from the first marked stop on it
+            let mut queue: BTreeMap<usize, u32> = BTreeMap::new();
+            for stop in std::mem::take(&mut marked) {
+                for (route, position) in &raptor_routes.stop_routes[stop as usize] {
+                    let first = queue.entry(*route).or_insert(*position);
+                    *first = (*first).min(*position);
+                }
+            }
+            let mut reached: BTreeSet<StopIndex> = BTreeSet::new();
+            for (route_index, first) in queue {
+                let route = &raptor_routes.routes[route_index];
+                let mut current: Option<(TripIndex, usize, u32)> = None;
+                for position in first as usize..route.stops.len() {
+                    let stop = route.stops[position];
+                    if let Some((trip, day, from)) = current {
+                        let ride = RoundLabel::Ride { round: round, boarded: trip, trip: trip, day: day, from: from, to: position as u32 };
+                        self.relax_ride(&mut state, &days, dest_stop_group, ride, stop, &mut reached);
+                    }
+                    // an earlier trip can be caught if the stop was reached in the previous round
+                    let previous_time = state.times[round - 1][stop as usize];
+                    let current_departure = current.map(|(trip, day, _)| {
+                        days.starts[day] + self.trips[trip as usize].stop_times[position].departure_time as i64
+                    });
+                    if position + 1 < route.stops.len() && previous_time != i64::MAX
+                        && current_departure.is_none_or(|departure| previous_time <= departure) {
+                        if let Some((trip, day)) = self.get_earliest_trip(&mut state, &days, route, position, round) {
+                            let departure = days.starts[day] + self.trips[trip as usize].stop_times[position].departure_time as i64;
+                            if current_departure.is_none_or(|current_departure| departure < current_departure) {
+                                current = Some((trip, day, position as u32));
+                            }
+                        }
+                    }
+                }
+                // staying seated when the vehicle continues as another trip does not take another round
+                if let Some((boarded, day, from)) = current {
+                    let mut trip = boarded;
+                    while let Some(successor) = self.block_successors[trip as usize] {
+                        for (position, stop_time) in self.trips[successor as usize].stop_times.iter().enumerate().skip(1) {
+                            let ride = RoundLabel::Ride { round: round, boarded: boarded, trip: successor, day: day, from: from, to: position as u32 };
+                            self.relax_ride(&mut state, &days, dest_stop_group, ride, self.stop_indices[&*stop_time.stop_id], &mut reached);
+                        }
+                        trip = successor;
+                    }
+                }
+            }
+            marked.extend(self.relax_round_walks(&mut state, round, &reached));
+            marked.extend(reached);
+        }
+
+        let found = dest_stop_group.stops.iter()
+            .filter(|stop| state.best[**stop as usize] != i64::MAX)
+            .min_by_key(|stop| (state.best[**stop as usize], **stop));
+        Ok(found.map(|found| {
+            let round = (0..state.times.len()).find(|round| state.times[*round][*found as usize] == state.best[*found as usize]).unwrap();
+            let mut journey = Vec::new();
+            self.push_round_stop(&state, &days, *found, round, &mut journey);
+            let (locations, times) = journey.into_iter().map(|(location, time)| (location, self.get_local_time(time))).unzip();
+            self.create_connection(locations, times)
+        }))
+    }
+
+    /// Gets off the @ride at the @stop it rides to, if that arrives there earlier than before and than at the destination
+    fn relax_ride(&self, state: &mut RaptorState, days: &SearchDays, dest_stop_group: &StopGroup, ride: RoundLabel, stop: StopIndex, reached: &mut BTreeSet<StopIndex>) {
+        let (round, trip, day, to) = match ride {
+            RoundLabel::Ride { round, trip, day, to, .. } => (round, trip, day, to),
+            _ => return,
+        };
+        let stop_time = &self.trips[trip as usize].stop_times[to as usize];
+        let arrival = days.starts[day] + (stop_time.arrival_time + self.transfer_times[stop as usize]) as i64;
+        let best_arrival = dest_stop_group.stops.iter().map(|stop| state.best[*stop as usize]).min().unwrap_or(i64::MAX);
+        if stop_time.can_get_off() && arrival < state.best[stop as usize] && arrival < best_arrival {
+            state.times[round][stop as usize] = arrival;
+            state.best[stop as usize] = arrival;
+            state.labels[round][stop as usize] = ride;
+            reached.insert(stop);
+        }
+    }
+
+    /// Returns the trip of the route departing first from the stop at @position after it was reached
+    /// in the previous round, on any of the searched days, which the transfer rules allow to board
+    fn get_earliest_trip(&self, state: &mut RaptorState, days: &SearchDays, route: &RaptorRoute, position: usize, round: usize) -> Option<(TripIndex, usize)> {
+        let stop = route.stops[position];
+        let time = state.times[round - 1][stop as usize];
+        let alighting = match state.labels[round - 1][stop as usize] {
+            RoundLabel::Ride { trip, day, to, .. } => Some((stop, trip, day, to)),
+            RoundLabel::Walk { round: walk_round, from, .. } => match state.labels[walk_round][from as usize] {
+                RoundLabel::Ride { trip, day, to, .. } => Some((from, trip, day, to)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let mut earliest: Option<(i64, TripIndex, usize)> = None;
+        for day in 0..SEARCH_DAYS {
+            let first = route.trips.partition_point(|trip| {
+                days.starts[day] + (self.trips[*trip as usize].stop_times[position].departure_time as i64) < time
+            });
+            for trip in &route.trips[first..] {
+                let departure = days.starts[day] + self.trips[*trip as usize].stop_times[position].departure_time as i64;
+                if earliest.is_some_and(|(earliest, _, _)| departure >= earliest) {
+                    break;
+                }
+                // the trips without exact times need the passenger to wait for them
+                let trip_data = &self.trips[*trip as usize];
+                if !trip_data.stop_times[position].can_board() || departure - (trip_data.get_expected_wait() as i64) < time {
+                    continue;
+                }
+                let allowed = alighting.is_none_or(|(from_stop, from_trip, from_day, from_position)| {
+                    let from_time = days.starts[from_day] + self.trips[from_trip as usize].stop_times[from_position as usize].departure_time as i64;
+                    self.is_boarding_allowed((from_stop, from_trip, from_time), (stop, *trip, departure))
+                });
+                if allowed && self.does_service_operate_on_day(&mut state.operates, days, self.trip_services[*trip as usize], day) {
+                    earliest = Some((departure, *trip, day));
+                    break;
+                }
+            }
+        }
+        earliest.map(|(_, trip, day)| (trip, day))
+    }
+
+    /// Walks from the stops reached by a vehicle in the round to their neighbours, returns the stops it improved
+    fn relax_round_walks(&self, state: &mut RaptorState, round: usize, stops: &BTreeSet<StopIndex>) -> Vec<StopIndex> {
+        let mut improved = Vec::new();
+        for stop in stops {
+            let departure = state.times[round][*stop as usize];
+            for (neighbouring_stop, travel_time) in &self.pedestrian_connections[*stop as usize] {
+                let arrival = departure + *travel_time as i64;
+                if arrival < state.best[*neighbouring_stop as usize] {
+                    state.times[round][*neighbouring_stop as usize] = arrival;
+                    state.best[*neighbouring_stop as usize] = arrival;
+                    state.labels[round][*neighbouring_stop as usize] = RoundLabel::Walk { round: round, from: *stop, departure: departure };
+                    improved.push(*neighbouring_stop);
+                }
+            }
+        }
+        improved
+    }
+
+    /// Appends the locations of the journey to @stop found in @round in the same form as the graph search builds them
+    fn push_round_stop(&self, state: &RaptorState, days: &SearchDays, stop: StopIndex, round: usize, journey: &mut Vec<(Location, i64)>) {
+        match state.labels[round][stop as usize] {
+            RoundLabel::Unreached | RoundLabel::Start => (),
+            RoundLabel::Walk { round, from, departure } => {
+                self.push_round_stop(state, days, from, round, journey);
+                if journey.last().is_none_or(|(_, time)| *time != departure) {
+                    journey.push((Location::Stop(from), departure));
+                }
+            },
+            RoundLabel::Ride { round, boarded, trip, day, from, to } => {
+                let stop_times = &self.trips[boarded as usize].stop_times;
+                let boarding_stop = self.stop_indices[&*stop_times[from as usize].stop_id];
+                let departure = days.starts[day] + (stop_times[from as usize].departure_time - self.trips[boarded as usize].get_expected_wait()) as i64;
+                self.push_round_stop(state, days, boarding_stop, round - 1, journey);
+                if journey.last().is_none_or(|(_, time)| *time != departure) {
+                    journey.push((Location::Stop(boarding_stop), departure));
+                }
+                // the trips the vehicle continues as start at their first stop
+                let (mut ridden, mut start) = (boarded, from as usize);
+                loop {
+                    let stop_times = &self.trips[ridden as usize].stop_times;
+                    let end = if ridden == trip { to as usize } else { stop_times.len() - 1 };
+                    let service = self.trip_services[ridden as usize];
+                    for (position, stop_time) in stop_times.iter().enumerate().take(end + 1).skip(start) {
+                        let location = Location::Trip(ridden, service, self.stop_indices[&*stop_time.stop_id], position as u32);
+                        journey.push((location, days.starts[day] + stop_time.departure_time as i64));
+                    }
+                    if ridden == trip {
+                        break;
+                    }
+                    ridden = self.block_successors[ridden as usize].unwrap();
+                    start = 0;
+                }
+            },
+        }
+        journey.push((Location::Stop(stop), state.times[round][stop as usize]));
+    }
+}
+
+#[test]
+fn test_raptor() {
+    let nw = Network::new(&write_test_feed("prahadlo_test_raptor")).unwrap();
+    let stops = ["Alfa", "Beta", "Gama", "Epsilon"];
+    for minutes in (0..60).step_by(5) {
+        let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 30, 0).unwrap() + Duration::minutes(minutes);
+        for from in &stops {
+            for to in stops.iter().filter(|to| *to != from) {
+                let (from, to) = (String::from(*from), String::from(*to));
+                let by_graph = nw.find_connection(&from, &to, datetime, &SearchProfile::default()).unwrap();
+                let by_raptor = nw.find_connection_by_raptor(&from, &to, datetime, None).unwrap();
+                assert_eq!(by_graph.map(|conn| *conn.times.last().unwrap()), by_raptor.map(|conn| *conn.times.last().unwrap()),
+                    "{} -> {} at {}", from, to, datetime);
+            }
+        }
+    }
+
+    // the change at Beta arrives earlier, without transfers only the direct metro remains
+    let (from, to) = (String::from("Alfa"), String::from("Epsilon"));
+    let datetime = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+    let conn = nw.find_connection_by_raptor(&from, &to, datetime, None).unwrap().unwrap();
+    assert_eq!(*conn.times.last().unwrap(), NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(8, 26, 0).unwrap());
+    assert_eq!(conn.transfers, 1);
+    let conn = nw.find_connection_by_raptor(&from, &to, datetime, Some(0)).unwrap().unwrap();
+    assert_eq!(*conn.times.last().unwrap(), NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(8, 31, 0).unwrap());
+    assert_eq!(conn.transfers, 0);
+    assert_eq!(conn.locations.iter().filter(|location| matches!(location, Location::Trip(_, _, _, _))).count(), 2);
+}
@@ -0,0 +1,174 @@
+use crate::error::{Error, Result};
+use crate::network::{Connection, Network, RouteFilter, SearchProfile};
+
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime};
+use rayon::prelude::*;
+
+/// The algorithm that answers the queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Dijkstra over the time-expanded graph, answers every kind of query
+    Graph,
+    /// Connection Scan Algorithm, only finds the connection with the earliest arrival
+    ConnectionScan,
+    /// RAPTOR, finds the connection with the earliest arrival and can limit the number of transfers
+    Raptor,
+}
+
+impl FromStr for Engine {
+    type Err = Error;
+
+    /// Parses the name used by the prompt and the HTTP API
+    fn from_str(name: &str) -> Result<Engine> {
+        match name {
+            "graph" => Ok(Engine::Graph),
+            "csa" => Ok(Engine::ConnectionScan),
+            "raptor" => Ok(Engine::Raptor),
+            _ => Err(Error::UnsupportedQuery("the engine is one of graph, csa and raptor")),
+        }
+    }
+}
+
+/// A query for connections between two stops. The stops are looked up by name the same way the prompt does it.
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use chrono::NaiveDate;
+/// # use prahadlo::{ConnectionQuery, Network};
+/// let nw = Network::new(Path::new("data/"))?;
+/// let time = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+/// let connections = ConnectionQuery::departing_at("Florenc", "Anděl", time).multi_criteria().run(&nw)?;
+/// # Ok::<(), prahadlo::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionQuery {
+    from: String,
+    to: String,
+    time: NaiveDateTime,
+    arrive_by: bool,
+    last_departure: Option<NaiveDateTime>,
+    multi_criteria: bool,
+    engine: Engine,
+    max_transfers: Option<u32>,
+    profile: SearchProfile,
+    // the stop to pass through and the minimal time spent there
+    via: Option<(String, Duration)>,
+}
+
+impl ConnectionQuery {
+    /// Looks for the connection with the earliest arrival leaving @from at @departure or later
+    pub fn departing_at(from: &str, to: &str, departure: NaiveDateTime) -> ConnectionQuery {
+        ConnectionQuery {
+            from: String::from(from),
+            to: String::from(to),
+            time: departure,
+            arrive_by: false,
+            last_departure: None,
+            multi_criteria: false,
+            engine: Engine::Graph,
+            max_transfers: None,
+            profile: SearchProfile::default(),
+            via: None,
+        }
+    }
+
+    /// Looks for the connection leaving @from as late as possible and arriving at @to at @arrival or earlier
+    pub fn arriving_by(from: &str, to: &str, arrival: NaiveDateTime) -> ConnectionQuery {
+        ConnectionQuery {
+            arrive_by: true,
+            ..ConnectionQuery::departing_at(from, to, arrival)
+        }
+    }
+
+    /// Looks for all the useful connections departing from the departure time up to @last_departure
+    pub fn departing_until(mut self, last_departure: NaiveDateTime) -> ConnectionQuery {
+        self.last_departure = Some(last_departure);
+        self
+    }
+
+    /// Also returns the connections arriving later, but with fewer transfers or less walking
+    pub fn multi_criteria(mut self) -> ConnectionQuery {
+        self.multi_criteria = true;
+        self
+    }
+
+    /// Answers the query with @engine instead of the time-expanded graph
+    pub fn engine(mut self, engine: Engine) -> ConnectionQuery {
+        self.engine = engine;
+        self
+    }
+
+    /// Only looks for connections with at most @max_transfers transfers, which only RAPTOR supports
+    pub fn max_transfers(mut self, max_transfers: u32) -> ConnectionQuery {
+        self.max_transfers = Some(max_transfers);
+        self
+    }
+
+    /// Only takes the trips, stops and walks @profile allows, e.g. those accessible by wheelchair
+    pub fn profile(mut self, profile: SearchProfile) -> ConnectionQuery {
+        self.profile = profile;
+        self
+    }
+
+    /// Only takes the trips of the routes @routes allows, e.g. no buses or only some lines.
+    /// It replaces the route filter of the profile, so it goes after `profile`.
+    pub fn routes(mut self, routes: RouteFilter) -> ConnectionQuery {
+        self.profile.routes = routes;
+        self
+    }
+
+    /// Only looks for connections passing through the stop @via and staying there for at least @min_dwell,
+    /// they have their segments before and after the stop
+    pub fn via(mut self, via: &str, min_dwell: Duration) -> ConnectionQuery {
+        self.via = Some((String::from(via), min_dwell));
+        self
+    }
+
+    /// Runs the query and returns the connections found, none of them if there is no connection
+    pub fn run(&self, nw: &Network) -> Result<Vec<Connection>> {
+        let (from, to) = (&self.from, &self.to);
+        let earliest_arrival = !self.arrive_by && self.last_departure.is_none() && !self.multi_criteria;
+        if self.engine != Engine::Graph && self.profile != SearchProfile::default() {
+            return Err(Error::UnsupportedQuery("only the graph engine supports search profiles and route filters"));
+        }
+        if self.engine != Engine::Graph && self.via.is_some() {
+            return Err(Error::UnsupportedQuery("only the graph engine supports via stops"));
+        }
+        match self.engine {
+            Engine::Graph if self.max_transfers.is_some() => {
+                return Err(Error::UnsupportedQuery("only the raptor engine limits the number of transfers"));
+            },
+            Engine::Graph => (),
+            Engine::ConnectionScan if !earliest_arrival || self.max_transfers.is_some() => {
+                return Err(Error::UnsupportedQuery("the connection scan only finds the earliest arrival"));
+            },
+            Engine::ConnectionScan => return Ok(nw.find_connection_by_scan(from, to, self.time)?.into_iter().collect()),
+            Engine::Raptor if !earliest_arrival => {
+                return Err(Error::UnsupportedQuery("raptor only finds the earliest arrival"));
+            },
+            Engine::Raptor => return Ok(nw.find_connection_by_raptor(from, to, self.time, self.max_transfers)?.into_iter().collect()),
+        }
+        if let Some((via, min_dwell)) = &self.via {
+            if !earliest_arrival {
+                return Err(Error::UnsupportedQuery("a via stop is only supported by the earliest arrival search"));
+            }
+            return Ok(nw.find_connection_via(from, via, to, self.time, *min_dwell, &self.profile)?.into_iter().collect());
+        }
+        match (self.arrive_by, self.last_departure, self.multi_criteria) {
+            (false, None, false) => Ok(nw.find_connection(from, to, self.time, &self.profile)?.into_iter().collect()),
+            (true, None, false) => Ok(nw.find_connection_arriving_by(from, to, self.time, &self.profile)?.into_iter().collect()),
+            (false, Some(last_departure), false) => nw.find_connections_in_window(from, to, self.time, last_departure, &self.profile),
+            (false, None, true) => nw.find_pareto_connections(from, to, self.time, &self.profile),
+            (true, Some(_), _) => Err(Error::UnsupportedQuery("a departure window cannot be combined with an arrival time")),
+            (_, _, true) => Err(Error::UnsupportedQuery("multi-criteria searches only support a single departure time")),
+        }
+    }
+
+    /// Runs the queries in parallel on the rayon thread pool and returns their results in the same order.
+    /// Call it from `ThreadPool::install` to run the queries on a pool of another size.
+    pub fn run_batch(queries: &[ConnectionQuery], nw: &Network) -> Vec<Result<Vec<Connection>>> {
+        queries.par_iter().map(|query| query.run(nw)).collect()
+    }
+}
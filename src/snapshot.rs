@@ -0,0 +1,115 @@
+use crate::error::{Error, Result};
+use crate::gtfs::FeedReader;
+use crate::network::Network;
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// Starts every snapshot so that other files are not mistaken for one
+const SNAPSHOT_MAGIC: &[u8; 8] = b"PRAHADLO";
+/// Has to be raised whenever the stored structures or the rules building the nodes and edges change,
+/// snapshots of other versions are rebuilt
+const SNAPSHOT_VERSION: u32 = 7;
+/// The files the network is built from, a snapshot is only used while none of them changes
+const FEED_FILES: [&str; 9] = [
+    "agency.txt",
+    "stops.txt",
+    "routes.txt",
+    "calendar.txt",
+    "calendar_dates.txt",
+    "trips.txt",
+    "stop_times.txt",
+    "transfers.txt",
+    "frequencies.txt",
+];
+
+/// Describes the feed a snapshot was built from
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+    // a lenient reader leaves out the invalid records, which a strict one has to report
+    lenient: bool,
+    // the CRC-32 of every feed file, None for the optional files missing from the feed
+    file_hashes: Vec<(String, Option<u32>)>,
+}
+
+impl SnapshotHeader {
+    fn for_feed(feed: &FeedReader) -> Result<SnapshotHeader> {
+        Ok(SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            lenient: feed.is_lenient(),
+            file_hashes: feed.file_hashes(&FEED_FILES)?,
+        })
+    }
+}
+
+fn to_io_error(err: bincode::ErrorKind) -> std::io::Error {
+    match err {
+        bincode::ErrorKind::Io(err) => err,
+        other => std::io::Error::other(other),
+    }
+}
+
+impl Network {
+    /// Restores the network saved by save_snapshot from the file at @path. Returns None if there is no such
+    /// snapshot, or if it was written by another version or for another feed than the one read by @feed.
+    /// The network then has to be built from the feed again.
+    pub fn load_snapshot(path: &Path, feed: &FeedReader) -> Result<Option<Network>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; 8];
+        if reader.read_exact(&mut magic).is_err() || &magic != SNAPSHOT_MAGIC {
+            return Ok(None);
+        }
+        match bincode::deserialize_from::<_, SnapshotHeader>(&mut reader) {
+            Ok(header) if header == SnapshotHeader::for_feed(feed)? => (),
+            _ => return Ok(None),
+        }
+        Ok(Network::read_snapshot(&mut reader).ok())
+    }
+
+    /// Saves the network to a snapshot at @path, together with the hashes of the files of @feed it was built from.
+    /// The snapshot is written next to the file first, so that an interrupted write does not leave a broken one.
+    pub fn save_snapshot(&self, path: &Path, feed: &FeedReader) -> Result<()> {
+        let header = SnapshotHeader::for_feed(feed)?;
+        let partial_path = path.with_extension("partial");
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&partial_path)?);
+            writer.write_all(SNAPSHOT_MAGIC)?;
+            bincode::serialize_into(&mut writer, &header).map_err(|err| to_io_error(*err))?;
+            self.write_snapshot(&mut writer).map_err(|err| to_io_error(*err))?;
+            writer.flush()?;
+            fs::rename(&partial_path, path)
+        };
+        write().map_err(Error::Snapshot)
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let feed_path = crate::test_feed::write_test_feed("prahadlo_test_snapshot");
+    let snapshot_path = std::env::temp_dir().join("prahadlo_test.snapshot");
+    let _ = fs::remove_file(&snapshot_path);
+    let mut feed = FeedReader::new(&feed_path);
+    assert!(Network::load_snapshot(&snapshot_path, &feed).unwrap().is_none());
+
+    let nw = Network::from_feed(&mut feed).unwrap();
+    nw.prepare(crate::Engine::Graph);
+    nw.save_snapshot(&snapshot_path, &feed).unwrap();
+    let loaded = Network::load_snapshot(&snapshot_path, &feed).unwrap().unwrap();
+    assert_eq!(format!("{:?}", loaded.get_node(0)), format!("{:?}", nw.get_node(0)));
+    let time = chrono::NaiveDate::from_ymd_opt(2020, 4, 14).unwrap().and_hms_opt(7, 50, 0).unwrap();
+    let (from, to) = (String::from("Alfa"), String::from("Gama"));
+    let expected = nw.find_connection(&from, &to, time, &crate::network::SearchProfile::default()).unwrap().unwrap();
+    let found = loaded.find_connection(&from, &to, time, &crate::network::SearchProfile::default()).unwrap().unwrap();
+    assert_eq!(found.times, expected.times);
+
+    assert!(Network::load_snapshot(&snapshot_path, &FeedReader::lenient(&feed_path)).unwrap().is_none());
+    fs::write(feed_path.join("transfers.txt"), "from_stop_id,to_stop_id,transfer_type,min_transfer_time\n").unwrap();
+    assert!(Network::load_snapshot(&snapshot_path, &feed).unwrap().is_none());
+}
@@ -0,0 +1,93 @@
+//! A small feed written for the tests
+
+use std::collections::HashMap;
+
+/// A change of a file written by write_test_feed_with
+#[derive(Clone, Copy)]
+pub(crate) enum FeedChange<'a> {
+    /// replaces a row of the file by another one, the row has to be there
+    Replace(&'a str, &'a str, &'a str),
+    /// adds a row at the end of the file, a file the feed does not have is created
+    Add(&'a str, &'a str),
+    /// leaves the file out of the feed
+    Remove(&'a str),
+}
+
+/// Writes a small feed into a fresh directory in the temp dir. Trams of line 1 run from Alfa through Beta
+/// to Gama, buses of line 2 from Beta to Epsilon and the metro A from Alfa to Epsilon. Delta is a short walk from Gama.
+pub(crate) fn write_test_feed(name: &str) -> std::path::PathBuf {
+    write_test_feed_with(name, &[])
+}
+
+/// Writes the feed of write_test_feed with the @changes applied, panics if a replaced row is not in the file
+pub(crate) fn write_test_feed_with(name: &str, changes: &[FeedChange]) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    let files = [
+        ("agency.txt", "\
+agency_id,agency_name,agency_url,agency_timezone,agency_lang,agency_phone
+99,Test,https://pid.cz,Europe/Prague,cs,
+"),
+        ("stops.txt", "\
+stop_id,stop_name,stop_lat,stop_lon,zone_id,stop_url,location_type,parent_station,wheelchair_boarding,level_id,platform_code
+U1Z1,Alfa,50.0800,14.4000,P,,0,,1,,
+U2Z1,Beta,50.0800,14.4300,P,,0,,1,,
+U3Z1,Gama,50.0800,14.4600,P,,0,,1,,
+U4Z1,Delta,50.0830,14.4600,P,,0,,1,,
+U5Z1,Epsilon,50.1000,14.4300,P,,0,,1,,
+"),
+        ("routes.txt", "\
+route_id,agency_id,route_short_name,route_long_name,route_type,route_url,route_color,route_text_color,is_night
+L1,99,1,Alfa - Gama,0,,,,0
+L2,99,2,Beta - Epsilon,3,,,,0
+L3,99,A,Alfa - Epsilon,1,,,,0
+"),
+        ("calendar.txt", "\
+service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date
+daily,1,1,1,1,1,1,1,20200101,20201231
+"),
+        ("calendar_dates.txt", "\
+service_id,date,exception_type
+"),
+        ("trips.txt", "\
+route_id,service_id,trip_id,trip_headsign,trip_short_name,direction_id,block_id,shape_id,wheelchair_accessible,bikes_allowed,exceptional,trip_operation_type
+L1,daily,T1,Gama,,0,,,1,1,0,1
+L2,daily,T2,Epsilon,,0,,,1,1,0,1
+L3,daily,T3,Epsilon,,0,,,1,1,0,1
+"),
+        ("stop_times.txt", "\
+trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign,pickup_type,drop_off_type,shape_dist_travelled
+T1,08:00:00,08:00:00,U1Z1,1,,0,0,
+T1,08:10:00,08:10:00,U2Z1,2,,0,0,
+T1,08:20:00,08:20:00,U3Z1,3,,0,0,
+T2,08:15:00,08:15:00,U2Z1,1,,0,0,
+T2,08:25:00,08:25:00,U5Z1,2,,0,0,
+T3,08:05:00,08:05:00,U1Z1,1,,0,0,
+T3,08:30:00,08:30:00,U5Z1,2,,0,0,
+"),
+    ];
+    let mut files: HashMap<&str, String> = files.iter().map(|(file_name, contents)| (*file_name, String::from(*contents))).collect();
+    for change in changes {
+        match *change {
+            FeedChange::Replace(file_name, row, replacement) => {
+                let contents = files.get_mut(file_name).unwrap_or_else(|| panic!("the test feed has no {}", file_name));
+                let found = contents.lines().filter(|line| *line == row).count();
+                assert_eq!(found, 1, "the row {} is not in {}", row, file_name);
+                *contents = contents.replace(&format!("{}\n", row), &format!("{}\n", replacement));
+            },
+            FeedChange::Add(file_name, row) => {
+                let contents = files.entry(file_name).or_insert_with(String::new);
+                contents.push_str(row);
+                contents.push('\n');
+            },
+            FeedChange::Remove(file_name) => {
+                assert!(files.remove(file_name).is_some(), "the test feed has no {}", file_name);
+            },
+        }
+    }
+    for (file_name, contents) in files.iter() {
+        std::fs::write(path.join(file_name), contents).unwrap();
+    }
+    path
+}
@@ -1,27 +1,54 @@
 use std::process::exit;
-use std::rc::Rc;
 
-use crate::network::*;
-use crate::gtfs::Trip;
+use prahadlo::gtfs::Trip;
+use prahadlo::network::*;
+use prahadlo::{ConnectionQuery, Engine};
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime};
+use geo_types::{LineString, MultiPolygon};
+use serde::Serialize;
+use serde_json::{json, Value};
 
-const datetime_format: &str = "%Y-%m-%d %H:%M:%S";
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Travel times in minutes the isochrone command draws the areas for
+const ISOCHRONE_LIMITS: [u32; 3] = [15, 30, 45];
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 enum Command {
     Help,
     Invalid,
-    GetConnection(NaiveDateTime, String, String),
+    GetConnection(NaiveDateTime, String, String, SearchProfile),
+    GetConnectionArrivingBy(NaiveDateTime, String, String, SearchProfile),
+    GetParetoConnections(NaiveDateTime, String, String, SearchProfile),
+    GetConnectionWithMaxTransfers(NaiveDateTime, u32, String, String, SearchProfile),
+    // the via stop with the minimal time spent there goes between the other two
+    GetConnectionVia(NaiveDateTime, String, (String, Duration), String, SearchProfile),
+    GetConnectionsInWindow(NaiveDateTime, NaiveDateTime, String, String),
+    ExportTravelTimes(NaiveDateTime, String, String),
+    ExportIsochrones(NaiveDateTime, String, String),
     PrintNode(usize),
     PrintStop(String),
     PrintTrip(String),
+    SetEngine(Engine),
+}
+
+/// A row of the CSV with travel times written by the export command
+#[derive(Debug, Serialize)]
+struct TravelTimeRecord<'a> {
+    stop_id: &'a str,
+    stop_name: &'a str,
+    lat: f32,
+    lon: f32,
+    arrival_time: String,
+    travel_seconds: i64,
 }
 
 pub struct TextInterface {
     rl: Editor<()>,
+    // the engine answering the connection queries
+    engine: Engine,
 }
 
 pub fn get_time_string(time_in_seconds: u32) -> String {
@@ -31,59 +58,186 @@ pub fn get_time_string(time_in_seconds: u32) -> String {
     format!("{:0>2}:{:0>2}:{:0>2}", hours, minutes, seconds)
 }
 
-fn print_location(location: &Location) {
-    match location {
-        Location::Stop(stop) => println!(" - corresponding to stop {}", stop.stop_name),
-        Location::Trip(trip, _) => println!(" - corresponding to trip {}", trip.trip_id),
+fn print_location(nw: &Network, location: &Location) {
+    match *location {
+        Location::Stop(stop) => println!(" - corresponding to stop {}", nw.get_stop_by_index(stop).stop_name),
+        Location::Trip(trip, _, _, _) => println!(" - corresponding to trip {}", nw.get_trip_by_index(trip).trip_id),
     };
 }
 
-fn print_node(node: &Node) {
+fn print_node(nw: &Network, node: &Node) {
     println!("Node with id {}, time {}:", node.node_id, get_time_string(node.get_time()));
-    print_location(node.get_location());
+    print_location(nw, node.get_location());
     println!(" - Edges to nodes {:?}", node.get_edges());
 }
 
-fn print_connection(nw: &Network, conn: &Connection) {
-    let mut index = 0;
-    loop {
-        // Go through all the waiting stops at the beginning of the connection
-        match conn.nodes[index].location {
-            Location::Trip(_, _) => break,
-            _ => index = index + 1,
-        }
+fn get_datetime_string(time: &NaiveDateTime) -> String {
+    time.format("%H:%M:%S").to_string()
+}
+
+/// Notes that the vehicle stops only when asked, which is common on the regional buses
+fn on_request_note(on_request: bool) -> &'static str {
+    if on_request { " (on request)" } else { "" }
+}
+
+/// Notes how often the trips expanded from frequencies.txt run, the times of those without exact times are expected ones
+fn headway_note(trip: &Trip) -> String {
+    match trip.headway {
+        Some(headway) if headway.exact_times => format!(", every {} minutes", (headway.headway_secs + 30) / 60),
+        Some(headway) => format!(", every {} minutes, expected time", (headway.headway_secs + 30) / 60),
+        None => String::new(),
     }
+}
 
-    let mut past_node = &conn.nodes[index-1];
-    for node in &conn.nodes[index..] {
-        let hours = node.get_time() / 3600;
-        let minutes = (node.get_time() - hours * 3600) / 60;
-        match node.get_location() {
-            Location::Stop(stop1) => {
-                match past_node.get_location() {
-                    Location::Stop(stop2) => {
-                        if stop1.stop_id != stop2.stop_id {
-                            print!("{} -> ", get_time_string(past_node.get_time()));
-                            println!("{} -> {} : pedestrian transfer", stop2.stop_name, stop1.stop_name);
-                        }
-                    },
-                    Location::Trip(trip, _) => {
-                        print!("{} -> ", get_time_string(node.get_time()));
-                        println!("{} : getting off line {}", stop1.stop_name, nw.get_trip_short_name(&trip));
-                    }
+/// Names how the passenger moves between the stops as an adjective and as a noun, cyclists ride instead of walking
+fn get_transfer_names(mobility: Mobility) -> (&'static str, &'static str) {
+    match mobility {
+        Mobility::Bicycle { .. } => ("cycling", "cycling"),
+        _ => ("pedestrian", "walking"),
+    }
+}
+
+fn format_connection(nw: &Network, conn: &Connection, mobility: Mobility) -> Vec<String> {
+    let mut lines = Vec::new();
+    // Go through all the waiting stops at the beginning of the connection,
+    // connections without any trip consist of walking only
+    let index = conn.locations.iter()
+        .position(|location| matches!(location, Location::Trip(..)))
+        .unwrap_or(1)
+        .max(1);
+
+    let mut past_index = index - 1;
+    for (location_index, location) in conn.locations.iter().enumerate().skip(index) {
+        // the segments after the first one start at a via stop
+        if conn.segments.iter().skip(1).any(|segment| segment.start == location_index) {
+            lines.push(format!("{} -> {} : via stop, staying until {}",
+                get_datetime_string(&conn.get_arrival_before(location_index)), nw.get_stop_by_index(location.get_stop()).stop_name,
+                get_datetime_string(&conn.times[location_index])));
+        }
+        match (conn.locations[past_index], *location) {
+            (Location::Stop(stop2), Location::Stop(stop1)) => {
+                if stop1 != stop2 {
+                    lines.push(format!("{} -> {} -> {} : {} transfer",
+                        get_datetime_string(&conn.times[past_index]), nw.get_stop_by_index(stop2).stop_name,
+                        nw.get_stop_by_index(stop1).stop_name, get_transfer_names(mobility).0));
                 }
             },
-            Location::Trip(trip, _) => {
-                match past_node.get_location() {
-                    Location::Stop(stop) => {
-                        print!("{} -> ", get_time_string(node.get_time()));
-                        println!("{} : boarding line {}", stop.stop_name, nw.get_trip_short_name(&trip));
-                    },
-                    Location::Trip(_, _) => {},
+            (Location::Trip(trip, _, _, position), Location::Stop(stop)) => {
+                let on_request = nw.get_stop_time(trip, position).is_getting_off_on_request();
+                lines.push(format!("{} -> {}{} : getting off line {}",
+                    get_datetime_string(&conn.times[location_index]), nw.get_stop_by_index(stop).stop_name,
+                    on_request_note(on_request), nw.get_trip_short_name(nw.get_trip_by_index(trip))));
+            },
+            (Location::Stop(stop), Location::Trip(trip, _, _, position)) => {
+                let on_request = nw.get_stop_time(trip, position).is_boarding_on_request();
+                let trip = nw.get_trip_by_index(trip);
+                lines.push(format!("{} -> {}{} : boarding line {}{}",
+                    get_datetime_string(&conn.times[location_index]), nw.get_stop_by_index(stop).stop_name,
+                    on_request_note(on_request), nw.get_trip_short_name(trip), headway_note(trip)));
+            },
+            (Location::Trip(past_trip, _, _, _), Location::Trip(trip, _, stop, position)) => {
+                if nw.get_block_successor(past_trip) == Some(trip) {
+                    lines.push(format!("{} -> {} : continues as line {}",
+                        get_datetime_string(&conn.times[location_index]), nw.get_stop_by_index(stop).stop_name,
+                        nw.get_trip_short_name(nw.get_trip_by_index(trip))));
+                } else if past_trip != trip {
+                    let on_request = nw.get_stop_time(trip, position).is_boarding_on_request();
+                    lines.push(format!("{} -> {}{} : guaranteed transfer to line {}",
+                        get_datetime_string(&conn.times[location_index]), nw.get_stop_by_index(stop).stop_name,
+                        on_request_note(on_request), nw.get_trip_short_name(nw.get_trip_by_index(trip))));
                 }
             },
         }
-        past_node = node;
+        past_index = location_index;
+    }
+    lines
+}
+
+fn print_connection(nw: &Network, conn: &Connection, mobility: Mobility) {
+    for line in format_connection(nw, conn, mobility) {
+        println!("{}", line);
+    }
+}
+
+/// Prints the connections next to each other, each in its own column headed by its summary
+fn print_connections_side_by_side(nw: &Network, connections: &Vec<Connection>, mobility: Mobility) {
+    let columns: Vec<Vec<String>> = connections.iter()
+        .map(|conn| {
+            let mut column = vec![
+                format!("arrival {}", get_datetime_string(conn.times.last().unwrap())),
+                format!("{} transfers, {:.0} m {}", conn.transfers, conn.walking_distance, get_transfer_names(mobility).1),
+                String::new(),
+            ];
+            column.extend(format_connection(nw, conn, mobility));
+            column
+        })
+        .collect();
+    let widths: Vec<usize> = columns.iter()
+        .map(|column| column.iter().map(|line| line.chars().count()).max().unwrap_or(0))
+        .collect();
+    let height = columns.iter().map(|column| column.len()).max().unwrap_or(0);
+    for row in 0..height {
+        let cells: Vec<String> = columns.iter().zip(&widths)
+            .map(|(column, width)| {
+                let cell = column.get(row).map_or("", |line| line.as_str());
+                format!("{}{}", cell, " ".repeat(width - cell.chars().count()))
+            })
+            .collect();
+        println!("{}", cells.join(" | ").trim_end());
+    }
+}
+
+fn write_travel_times(path: &str, arrivals: &Vec<StopArrival>) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    for arrival in arrivals {
+        wtr.serialize(TravelTimeRecord {
+            stop_id: &arrival.stop.stop_id,
+            stop_name: &arrival.stop.stop_name,
+            lat: arrival.stop.stop_lat,
+            lon: arrival.stop.stop_lon,
+            arrival_time: arrival.arrival.format(DATETIME_FORMAT).to_string(),
+            travel_seconds: arrival.travel_time,
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn ring_to_geojson(ring: &LineString<f64>) -> Value {
+    Value::Array(ring.points_iter().map(|point| json!([point.x(), point.y()])).collect())
+}
+
+/// Writes the isochrones as a GeoJSON FeatureCollection, the largest area first so that the smaller ones are drawn over it
+fn write_isochrones(path: &str, isochrones: &Vec<(u32, MultiPolygon<f64>)>) -> std::io::Result<()> {
+    let features: Vec<Value> = isochrones.iter().rev()
+        .map(|(minutes, area)| {
+            let polygons: Vec<Value> = area.0.iter()
+                .map(|polygon| {
+                    let mut rings = vec![ring_to_geojson(polygon.exterior())];
+                    rings.extend(polygon.interiors().iter().map(ring_to_geojson));
+                    Value::Array(rings)
+                })
+                .collect();
+            json!({
+                "type": "Feature",
+                "properties": { "minutes": minutes },
+                "geometry": { "type": "MultiPolygon", "coordinates": polygons },
+            })
+        })
+        .collect();
+    let collection = json!({ "type": "FeatureCollection", "features": features });
+    std::fs::write(path, collection.to_string())
+}
+
+fn print_lookup_result(nw: &Network, lookup_result: prahadlo::Result<Vec<Connection>>, mobility: Mobility) {
+    match lookup_result {
+        Ok(connections) => {
+            match connections.first() {
+                Some(conn) => print_connection(nw, conn, mobility),
+                None => println!("No connection found, sorry!"),
+            }
+        },
+        Err(err) => println!("ERROR: {}", err),
     }
 }
 
@@ -117,14 +271,144 @@ fn parse_print_trip(args: &[&str]) -> Command {
     }
 }
 
+fn parse_engine(args: &[&str]) -> Command {
+    match args {
+        [name] => name.parse().map_or(Command::Invalid, Command::SetEngine),
+        _ => Command::Invalid,
+    }
+}
+
+/// Names of the GTFS route types in the filter clauses, the types can also be given by their numbers
+const ROUTE_TYPE_NAMES: [(&str, u32); 10] = [
+    ("tram", 0), ("metro", 1), ("train", 2), ("bus", 3), ("ferry", 4), ("cable_tram", 5),
+    ("aerial_lift", 6), ("funicular", 7), ("trolleybus", 11), ("monorail", 12),
+];
+
+/// Parses comma separated clauses such as "only metro tram", "no bus", "no line 22" or "only agency 99"
+fn parse_route_filter(clauses: &str) -> Option<RouteFilter> {
+    let mut filter = RouteFilter::default();
+    for clause in clauses.split(",") {
+        let words: Vec<&str> = clause.split_whitespace().collect();
+        let (include, words) = match words.split_first() {
+            Some((&"only", words)) => (true, words),
+            Some((&"no", words)) => (false, words),
+            _ => return None,
+        };
+        let values = |words: &[&str]| -> Vec<String> { words.iter().map(|word| String::from(*word)).collect() };
+        match words {
+            [] => return None,
+            ["line", lines @ ..] if !lines.is_empty() => {
+                let list = if include { &mut filter.include_lines } else { &mut filter.exclude_lines };
+                list.extend(values(lines));
+            },
+            ["agency", agencies @ ..] if !agencies.is_empty() => {
+                let list = if include { &mut filter.include_agencies } else { &mut filter.exclude_agencies };
+                list.extend(values(agencies));
+            },
+            types => {
+                let list = if include { &mut filter.include_route_types } else { &mut filter.exclude_route_types };
+                for name in types {
+                    let route_type = ROUTE_TYPE_NAMES.iter()
+                        .find(|(type_name, _)| type_name == name)
+                        .map(|(_, route_type)| *route_type)
+                        .or_else(|| name.parse().ok())?;
+                    list.push(route_type);
+                }
+            },
+        }
+    }
+    Some(filter)
+}
+
 fn parse_connection(conn_details: &String) -> Command {
+    // the -a flag makes the time the latest arrival instead of the earliest departure,
+    // the -m flag looks for alternatives with fewer transfers or less walking,
+    // the -t flag is followed by the maximum number of transfers before the time,
+    // the -v flag looks for a connection through a via stop,
+    // the -w flag, which can go before the other ones, only uses what is accessible by wheelchair,
+    // the -b flag in the same place only takes the trips allowing bikes and cycles between the stops
+    let conn_details = conn_details.trim();
+    let profile_flag = |flag: &str| match conn_details.strip_prefix(flag) {
+        Some(rest) if rest.is_empty() || rest.starts_with(" ") => Some(rest.trim_start()),
+        _ => None,
+    };
+    let (profile, conn_details) = match (profile_flag("-w"), profile_flag("-b")) {
+        (Some(rest), _) => (SearchProfile::wheelchair(), rest),
+        (_, Some(rest)) => (SearchProfile::bicycle(DEFAULT_CYCLING_SPEED, MAX_CYCLING_DIST), rest),
+        _ => (SearchProfile::default(), conn_details),
+    };
+    let (flag, conn_details) = match conn_details.strip_prefix("-") {
+        Some(rest) => {
+            let mut chars = rest.chars();
+            match chars.next() {
+                Some(flag) => (Some(flag), chars.as_str()),
+                None => return Command::Invalid,
+            }
+        },
+        None => (None, conn_details),
+    };
     let args: Vec<&str> = conn_details.split("|").map(|x| x.trim()).collect();
-    if args.len() == 3 {
-        let time_res = NaiveDateTime::parse_from_str(args[0], datetime_format);
+    // the -v flag is followed by the minutes spent at the via stop, which goes between the other two
+    let stop_count = if flag == Some('v') { 3 } else { 2 };
+    // the route filter clauses are optional after the stop names
+    let profile = match args.get(stop_count + 1) {
+        Some(clauses) => match parse_route_filter(clauses) {
+            Some(filter) => profile.routes(filter),
+            None => return Command::Invalid,
+        },
+        None => profile,
+    };
+    if args.len() == stop_count + 1 || args.len() == stop_count + 2 {
+        let time_res = NaiveDateTime::parse_from_str(args[0], DATETIME_FORMAT);
+        // the -t and -v flags put a number before the time
+        let counted_time = args[0].split_once(" ").and_then(|(count, time)| {
+            Some((count.parse::<u32>().ok()?, NaiveDateTime::parse_from_str(time.trim(), DATETIME_FORMAT).ok()?))
+        });
         let dep_stop_id = String::from(args[1]);
-        let dest_stop_id = String::from(args[2]);
-        match time_res {
-            Ok(time) => Command::GetConnection(time, dep_stop_id, dest_stop_id),
+        let dest_stop_id = String::from(args[stop_count]);
+        match (time_res, flag) {
+            (Ok(time), None) => Command::GetConnection(time, dep_stop_id, dest_stop_id, profile),
+            (Ok(time), Some('a')) => Command::GetConnectionArrivingBy(time, dep_stop_id, dest_stop_id, profile),
+            (Ok(time), Some('m')) => Command::GetParetoConnections(time, dep_stop_id, dest_stop_id, profile),
+            (Err(_), Some('t')) => match counted_time {
+                Some((max_transfers, time)) => {
+                    Command::GetConnectionWithMaxTransfers(time, max_transfers, dep_stop_id, dest_stop_id, profile)
+                },
+                None => Command::Invalid,
+            },
+            (Err(_), Some('v')) => match counted_time {
+                Some((minutes, time)) => {
+                    let via = (String::from(args[2]), Duration::minutes(minutes as i64));
+                    Command::GetConnectionVia(time, dep_stop_id, via, dest_stop_id, profile)
+                },
+                None => Command::Invalid,
+            },
+            _ => Command::Invalid,
+        }
+    } else {
+        Command::Invalid
+    }
+}
+
+fn parse_profile(profile_details: &String) -> Command {
+    let args: Vec<&str> = profile_details.split("|").map(|x| x.trim()).collect();
+    if args.len() == 4 {
+        let from_res = NaiveDateTime::parse_from_str(args[0], DATETIME_FORMAT);
+        let to_res = NaiveDateTime::parse_from_str(args[1], DATETIME_FORMAT);
+        match (from_res, to_res) {
+            (Ok(from), Ok(to)) => Command::GetConnectionsInWindow(from, to, String::from(args[2]), String::from(args[3])),
+            _ => Command::Invalid,
+        }
+    } else {
+        Command::Invalid
+    }
+}
+
+fn parse_export(export_details: &String) -> Command {
+    let args: Vec<&str> = export_details.split("|").map(|x| x.trim()).collect();
+    if args.len() == 3 && !args[2].is_empty() {
+        match NaiveDateTime::parse_from_str(args[0], DATETIME_FORMAT) {
+            Ok(time) => Command::ExportTravelTimes(time, String::from(args[1]), String::from(args[2])),
             Err(_) => Command::Invalid,
         }
     } else {
@@ -132,6 +416,13 @@ fn parse_connection(conn_details: &String) -> Command {
     }
 }
 
+fn parse_isochrone(isochrone_details: &String) -> Command {
+    match parse_export(isochrone_details) {
+        Command::ExportTravelTimes(time, stop_name, path) => Command::ExportIsochrones(time, stop_name, path),
+        _ => Command::Invalid,
+    }
+}
+
 fn command_from_line(line: &str) -> Command {
     let complete_input: Vec<&str> = line.trim().split(" ").collect();
     let command_type = complete_input[0];
@@ -143,6 +434,10 @@ fn command_from_line(line: &str) -> Command {
         "stop" => parse_print_stop(args),
         "trip" => parse_print_trip(args),
         "conn" => parse_connection(&args.join(" ")),
+        "profile" => parse_profile(&args.join(" ")),
+        "export" => parse_export(&args.join(" ")),
+        "isochrone" => parse_isochrone(&args.join(" ")),
+        "engine" => parse_engine(args),
         "help" => Command::Help,
         _ => Command::Invalid,
     }
@@ -154,6 +449,17 @@ fn print_help() {
     println!(" - stop [stop_id] - prints information about a stop with the id");
     println!(" - trip [trip_id] - prints information about a trip with the id");
     println!(" - conn [time] | [stop_name_1] | [stop_name_2] - finds a connection between the stops. \n [time] is in the format YYYY-MM-DD HH:MM:SS");
+    println!(" - conn -a [time] | [stop_name_1] | [stop_name_2] - finds the latest connection arriving at [time]");
+    println!(" - conn -m [time] | [stop_name_1] | [stop_name_2] - finds alternatives trading arrival time for fewer transfers or less walking");
+    println!(" - conn -t [count] [time] | [stop_name_1] | [stop_name_2] - finds the earliest connection with at most [count] transfers by RAPTOR");
+    println!(" - conn -v [minutes] [time] | [stop_name_1] | [via_stop_name] | [stop_name_2] - finds the earliest connection staying at least [minutes] at the via stop");
+    println!(" - conn -w ... - finds any of the connections above using only accessible trips and stops, e.g. conn -w -a [time] | ...");
+    println!(" - conn -b ... - finds any of the connections above using only trips taking bikes and cycling between the stops");
+    println!(" - conn ... | [stop_name_2] | [clauses] - only takes the routes the clauses allow, e.g. only metro tram, no line 22, no agency 99");
+    println!(" - profile [time_1] | [time_2] | [stop_name_1] | [stop_name_2] - finds all useful connections departing between the times");
+    println!(" - export [time] | [stop_name] | [file_path] - writes the travel times from the stop to all other stops to a CSV file");
+    println!(" - isochrone [time] | [stop_name] | [file_path] - writes the areas reachable in 15, 30 and 45 minutes to a GeoJSON file");
+    println!(" - engine [graph|csa|raptor] - answers the connection queries by the time-expanded graph, the connection scan or RAPTOR");
 }
 
 fn print_invalid() {
@@ -166,7 +472,7 @@ impl TextInterface {
         if rl.load_history(history_file).is_err() {
             println!("No previous history.");
         }
-        TextInterface { rl: rl }
+        TextInterface { rl: rl, engine: Engine::Graph }
     }
 
     fn get_command(&mut self) -> Command {
@@ -174,8 +480,7 @@ impl TextInterface {
         match readline {
             Ok(line) => {
                 self.rl.add_history_entry(line.as_str());
-                let command = command_from_line(&line);
-                command        
+                command_from_line(&line)
             },
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -197,7 +502,7 @@ impl TextInterface {
         match cmd {
             Command::PrintNode(id) => {
                 let node = nw.get_node(id);
-                print_node(node);
+                print_node(nw, node);
             },
             Command::PrintStop(id) => {
                 match nw.get_stop(&id) {
@@ -211,18 +516,66 @@ impl TextInterface {
                     None => println!("ERROR: no trip with such id")
                 }
             },
-            Command::GetConnection(time, s1, s2) => {
-                let lookup_result = nw.find_connection(&s1, &s2, time);
-                match lookup_result {
-                    Ok(maybe_connection) => {
-                        match maybe_connection {
-                            Some(conn) => print_connection(nw, &conn),
-                            None => println!("No connection found, sorry!"),
+            Command::GetConnection(time, s1, s2, profile) => {
+                let mobility = profile.mobility;
+                print_lookup_result(nw, ConnectionQuery::departing_at(&s1, &s2, time).engine(self.engine).profile(profile).run(nw), mobility);
+            },
+            Command::GetConnectionArrivingBy(time, s1, s2, profile) => {
+                let mobility = profile.mobility;
+                print_lookup_result(nw, ConnectionQuery::arriving_by(&s1, &s2, time).engine(self.engine).profile(profile).run(nw), mobility);
+            },
+            Command::GetConnectionWithMaxTransfers(time, max_transfers, s1, s2, profile) => {
+                // only RAPTOR counts the rounds, so the limit overrides the chosen engine
+                let query = ConnectionQuery::departing_at(&s1, &s2, time).engine(Engine::Raptor).max_transfers(max_transfers);
+                let mobility = profile.mobility;
+                print_lookup_result(nw, query.profile(profile).run(nw), mobility);
+            },
+            Command::GetConnectionVia(time, s1, (via, min_dwell), s2, profile) => {
+                let mobility = profile.mobility;
+                let query = ConnectionQuery::departing_at(&s1, &s2, time).engine(self.engine).profile(profile).via(&via, min_dwell);
+                print_lookup_result(nw, query.run(nw), mobility);
+            },
+            Command::GetParetoConnections(time, s1, s2, profile) => {
+                let mobility = profile.mobility;
+                match ConnectionQuery::departing_at(&s1, &s2, time).multi_criteria().engine(self.engine).profile(profile).run(nw) {
+                    Ok(connections) if connections.is_empty() => println!("No connection found, sorry!"),
+                    Ok(connections) => print_connections_side_by_side(nw, &connections, mobility),
+                    Err(err) => println!("ERROR: {}", err),
+                }
+            },
+            Command::GetConnectionsInWindow(from, to, s1, s2) => {
+                match ConnectionQuery::departing_at(&s1, &s2, from).departing_until(to).engine(self.engine).run(nw) {
+                    Ok(connections) => {
+                        if connections.is_empty() {
+                            println!("No connection found, sorry!");
                         }
+                        for (index, conn) in connections.iter().enumerate() {
+                            println!("Connection {}:", index + 1);
+                            print_connection(nw, conn, Mobility::Walking);
+                        }
+                    },
+                    Err(err) => println!("ERROR: {}", err),
+                }
+            },
+            Command::ExportTravelTimes(time, stop_name, path) => {
+                match nw.find_arrivals_at_all_stops(&stop_name, time) {
+                    Ok(arrivals) => match write_travel_times(&path, &arrivals) {
+                        Ok(()) => println!("Travel times to {} stops written to {}", arrivals.len(), path),
+                        Err(err) => println!("ERROR: could not write {}: {}", path, err),
+                    },
+                    Err(err) => println!("ERROR: {}", err),
+                }
+            },
+            Command::ExportIsochrones(time, stop_name, path) => {
+                match nw.find_isochrones(&stop_name, time, &ISOCHRONE_LIMITS) {
+                    Ok(isochrones) => match write_isochrones(&path, &isochrones) {
+                        Ok(()) => println!("Isochrones written to {}", path),
+                        Err(err) => println!("ERROR: could not write {}: {}", path, err),
                     },
-                    Err(err_string) => println!("{}", err_string),
+                    Err(err) => println!("ERROR: {}", err),
                 }
             },
+            Command::SetEngine(engine) => self.engine = engine,
             Command::Help => print_help(),
             Command::Invalid => print_invalid(),
         } 